@@ -0,0 +1,19 @@
+#!/bin/bash
+
+set -euo pipefail
+
+BASE="../target/wasm32-unknown-unknown/release"
+
+echo "building ledger canister"
+
+cargo build --target wasm32-unknown-unknown --release -p ledger --locked
+
+(
+    echo "compressing ledger canister"
+    cd ${BASE}
+    gzip -c ledger.wasm > ledger.wasm.gz
+)
+
+echo "building dex canister"
+cargo build --target wasm32-unknown-unknown --release -p dex --locked
+cp ${BASE}/dex.wasm ./dex.wasm
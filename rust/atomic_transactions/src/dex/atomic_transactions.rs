@@ -0,0 +1,4046 @@
+use ansi_term::Colour;
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_atomic_transactions::{Configuration, Envelope, Phase, PrepareVote, TokenName};
+use ic_atomic_transactions::TransactionStatus as ParticipantStatus;
+use futures::future::join_all;
+use ic_cdk::api::call::{call_raw128, RejectionCode};
+use ic_cdk::{query, update};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::time::Duration;
+
+pub use ic_atomic_transactions::TransactionId;
+
+/// Do not act on a transaction more often than this.
+pub const RATE_LIMIT_TIMEOUT_NS: u64 = 5_000_000_000;
+/// Abort a transaction that has been in the prepare phase for longer than
+/// this: a participant is likely down and we should release the locks on
+/// the other participants.
+pub const ABORT_PREPARE_AFTER_NS: u64 = 10_000_000_000;
+/// Interval of the timer driving all active transactions.
+pub const TIMER_INTERVAL_SECS: u64 = 1;
+
+/// Timer interval while no transaction is active: the timer only has to
+/// notice newly arriving work (which re-arms it anyway), so it can fire
+/// an order of magnitude less often.
+pub const IDLE_TIMER_INTERVAL_SECS: u64 = 10;
+/// Base delay of the per-call exponential backoff: the first retry of a
+/// call waits this long, every further retry doubles the wait.
+pub const CALL_BACKOFF_BASE_NS: u64 = 5_000_000_000;
+/// Upper bound on the per-call backoff, so even a participant that has
+/// failed for hours is probed about once a minute.
+pub const CALL_BACKOFF_CAP_NS: u64 = 60_000_000_000;
+/// How long finalized transactions stay in the table before the timer
+/// garbage-collects them, leaving clients a window to poll the outcome.
+/// History survives in the archive, see `archive_transaction`.
+pub const FINALIZED_RETENTION_NS: u64 = 3_600_000_000_000;
+/// Maximum number of status transitions recorded per transaction.
+pub const MAX_STATE_TRACE_LEN: usize = 32;
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum TransactionStatus {
+    Preparing,
+    Aborting,
+    Aborted,
+    Committing,
+    Committed,
+    /// Terminal state for transactions that need an operator to look at
+    /// them. Never cleaned up automatically.
+    NeedsReview,
+}
+
+impl TransactionStatus {
+    /// True if the transaction is no longer driven by the timer loop.
+    pub fn is_final(&self) -> bool {
+        matches!(
+            self,
+            TransactionStatus::Aborted
+                | TransactionStatus::Committed
+                | TransactionStatus::NeedsReview
+        )
+    }
+}
+
+/// Why a transaction was (or is being) aborted, recorded when the first
+/// negative prepare outcome is observed.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum AbortReason {
+    /// The prepare phase did not finish before the deadline.
+    PrepareTimeout,
+    /// A participant voted `Busy`: another transaction held a still-valid
+    /// lock on one of the resources.
+    LockConflict,
+    /// A prepare call could not be delivered to a participant.
+    CallFailure,
+    /// A participant voted `No`: the transaction can never succeed, e.g.
+    /// because of an unknown token or insufficient balance.
+    Rejected,
+    /// A participant asked for the abort after voting "yes", e.g.
+    /// because an operator froze one of its tokens.
+    ParticipantRequest,
+    /// An optimistic-mode commit found the resource changed since the
+    /// prepare. A fresh attempt re-validates against the new version.
+    CommitConflict,
+    /// The initiator cancelled the transaction via `cancel_transaction`
+    /// while it was still preparing.
+    Cancelled,
+}
+
+impl AbortReason {
+    /// True if a fresh attempt of the same transaction may succeed.
+    pub fn retryable(&self) -> bool {
+        !matches!(
+            self,
+            AbortReason::Rejected | AbortReason::ParticipantRequest | AbortReason::Cancelled
+        )
+    }
+}
+
+/// A call to one participant for one phase of the protocol, together with
+/// counters tracking how often it was tried and how it was answered.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Call {
+    pub target: Principal,
+    pub method: String,
+    pub payload: Vec<u8>,
+    pub num_tries: u64,
+    pub num_success: u64,
+    pub num_fail: u64,
+    /// When this call was last issued, the reference point for its
+    /// exponential backoff.
+    pub last_try_time: u64,
+    /// Cycles attached to every attempt of this call, funding paid work
+    /// the participant does while handling it.
+    pub cycles: u128,
+}
+
+impl Call {
+    fn new(target: Principal, method: &str, payload: Vec<u8>, cycles: u128) -> Self {
+        Self {
+            target,
+            method: method.to_string(),
+            payload,
+            num_tries: 0,
+            num_success: 0,
+            num_fail: 0,
+            last_try_time: 0,
+            cycles,
+        }
+    }
+
+    /// How long after `last_try_time` this call has to wait before the
+    /// next attempt: doubling with every try, capped so a long-failing
+    /// participant is still probed regularly.
+    fn backoff_ns(&self) -> u64 {
+        if self.num_tries == 0 {
+            return 0;
+        }
+        CALL_BACKOFF_BASE_NS
+            .saturating_mul(1 << (self.num_tries - 1).min(63))
+            .min(CALL_BACKOFF_CAP_NS)
+    }
+
+    /// Whether this call may be (re-)issued at `now`, i.e. its backoff
+    /// elapsed. Fresh calls are always ready.
+    fn ready(&self, now: u64) -> bool {
+        now >= self.last_try_time + self.backoff_ns()
+    }
+}
+
+
+/// One participant's share of a transaction: the canister to call, the
+/// names of the 2PC endpoints it exposes and the payload passed along
+/// with each of them.
+#[derive(Clone, Debug)]
+pub struct ParticipantSpec {
+    pub canister: Principal,
+    pub prepare: String,
+    pub abort: String,
+    pub commit: String,
+    pub payload: Vec<u8>,
+}
+
+/// Coordinator-side state of one transaction.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TransactionState {
+    pub transaction_status: TransactionStatus,
+    /// The calls to issue to each participant for each phase. Which
+    /// vector is worked on is determined by the transaction status.
+    pub pending_prepare_calls: Vec<Call>,
+    pub pending_abort_calls: Vec<Call>,
+    pub pending_commit_calls: Vec<Call>,
+    /// Number of participants of this transaction.
+    pub total_number_of_children: u64,
+    /// How many successful participant answers the prepare and the
+    /// commit phase each require before the transaction advances.
+    /// Defaults to `total_number_of_children`, i.e. strict two-phase
+    /// commit. Values below the total turn the protocol into a quorum
+    /// model that BREAKS ATOMICITY - participants beyond the quorum may
+    /// commit late or never - and exist for experimentation only.
+    pub required_successes: u64,
+    /// Time this transaction was registered, used for the prepare timeout.
+    pub transaction_start_time: u64,
+    /// Last time the coordinator acted on this transaction, used for rate
+    /// limiting.
+    pub last_action_time: u64,
+    /// True while a `transaction_loop` invocation is stepping this
+    /// transaction. The awaits in the loop release the state, so a
+    /// concurrent invocation for the same transaction could double-issue
+    /// the same pending calls without this guard.
+    pub in_progress: bool,
+    /// Best-effort balance snapshots taken just before the commit calls
+    /// are issued and again after the commit completed, for auditing.
+    /// One entry per participant, `None` if the snapshot query failed.
+    pub pre_commit_balances: Option<Vec<Option<u64>>>,
+    pub post_commit_balances: Option<Vec<Option<u64>>>,
+    /// Client-supplied deadline: the transaction is aborted if it is
+    /// still preparing past this point in time. The same timestamp is
+    /// propagated to the participants, whose locks auto-release at it.
+    pub valid_until_ns: Option<u64>,
+    /// Why the transaction aborted, set on the first negative prepare
+    /// outcome. `None` while preparing or after a successful commit.
+    pub abort_reason: Option<AbortReason>,
+    /// The first transaction of the retry chain this transaction belongs
+    /// to, `None` if it is not a retry itself.
+    pub root_tid: Option<TransactionId>,
+    /// How many automatic retries may still be spawned if this
+    /// transaction aborts for a transient reason.
+    pub retries_left: u8,
+    /// Ordered (timestamp, from, to) status transitions this transaction
+    /// underwent, capped at `MAX_STATE_TRACE_LEN` entries.
+    pub state_trace: Vec<(u64, TransactionStatus, TransactionStatus)>,
+    /// The principal that initiated the transaction.
+    pub initiator: Principal,
+    /// Correlation ID tying the log entries of this transaction together
+    /// across canisters, embedded in every call envelope.
+    pub trace_id: u64,
+    /// Diagnostic count of "yes" votes that arrived after the decision
+    /// to abort. Kept out of the per-call success counters so straggling
+    /// votes can never make the count read as unanimous.
+    pub late_prepare_yes: u64,
+    /// Taken out of automatic management: the timer loop skips this
+    /// transaction, only explicit `transaction_loop` calls advance it.
+    /// Used by operators to debug or drive a transaction manually
+    /// without disabling the timer globally.
+    pub manual_only: bool,
+    /// Minimum time between two `transaction_loop` steps of this
+    /// transaction. Defaults to `RATE_LIMIT_TIMEOUT_NS`; zero disables
+    /// rate limiting, e.g. for a client driving recovery manually.
+    pub rate_limit_ns: u64,
+    /// How long this transaction may sit in the prepare phase before it
+    /// is aborted. Defaults to `ABORT_PREPARE_AFTER_NS`; shorter for
+    /// latency-sensitive swaps, longer for participants that do heavy
+    /// work in their prepare.
+    pub abort_prepare_after_ns: u64,
+    /// Upper bound on prepare attempts per participant. Once any
+    /// participant's prepare has been tried this often without success
+    /// the transaction aborts, even if the time-based deadline is still
+    /// far away. Zero (the default) disables the cap.
+    pub max_prepare_tries: u64,
+    /// Cycles this canister's balance dropped by while driving the
+    /// transaction's call fan-outs. A rough measure - concurrent
+    /// activity on the canister is attributed to whichever transactions
+    /// happen to be in flight - but good enough to spot runaways.
+    pub cycles_spent: u128,
+    /// Participants that answered a prepare with a refusal. They locked
+    /// nothing, so the abort phase skips them instead of spending a call
+    /// on a no-op.
+    pub declined: BTreeSet<Principal>,
+}
+
+impl TransactionState {
+    /// Create a new transaction calling the given methods on the given
+    /// canisters, with one set of application arguments per canister.
+    /// Each call's payload is wrapped in an `Envelope` carrying the
+    /// transaction ID, the phase and the trace ID, so participants can
+    /// verify a payload belongs to the method it arrived at.
+    /// `cycles` is attached to every call of every phase.
+    pub fn new(
+        tid: TransactionId,
+        trace_id: u64,
+        participants: &[ParticipantSpec],
+        cycles: u128,
+    ) -> Self {
+        let calls_for_phase = |phase: Phase, method_of: &dyn Fn(&ParticipantSpec) -> &str| {
+            participants
+                .iter()
+                .map(|spec| {
+                    Call::new(
+                        spec.canister,
+                        method_of(spec),
+                        Envelope::new(tid, phase, trace_id, spec.payload.clone()).encode(),
+                        cycles,
+                    )
+                })
+                .collect()
+        };
+        TransactionState {
+            transaction_status: TransactionStatus::Preparing,
+            pending_prepare_calls: calls_for_phase(Phase::Prepare, &|spec| &spec.prepare),
+            pending_abort_calls: calls_for_phase(Phase::Abort, &|spec| &spec.abort),
+            pending_commit_calls: calls_for_phase(Phase::Commit, &|spec| &spec.commit),
+            total_number_of_children: participants.len() as u64,
+            required_successes: participants.len() as u64,
+            transaction_start_time: 0,
+            last_action_time: 0,
+            in_progress: false,
+            pre_commit_balances: None,
+            post_commit_balances: None,
+            valid_until_ns: None,
+            abort_reason: None,
+            root_tid: None,
+            retries_left: 0,
+            state_trace: vec![],
+            initiator: Principal::anonymous(),
+            trace_id,
+            late_prepare_yes: 0,
+            manual_only: false,
+            abort_prepare_after_ns: ABORT_PREPARE_AFTER_NS,
+            rate_limit_ns: RATE_LIMIT_TIMEOUT_NS,
+            max_prepare_tries: 0,
+            cycles_spent: 0,
+            declined: BTreeSet::new(),
+        }
+    }
+
+    /// Convenience constructor for the common case where every
+    /// participant exposes the same 2PC endpoints.
+    #[allow(clippy::too_many_arguments)]
+    pub fn uniform(
+        tid: TransactionId,
+        trace_id: u64,
+        canisters: &[Principal],
+        method_prepare: &str,
+        method_abort: &str,
+        method_commit: &str,
+        args: &[Vec<u8>],
+        cycles: u128,
+    ) -> Self {
+        // A silent `zip` truncation would build a transaction that can
+        // never reach `total_number_of_children` successes and strand it
+        // in `Preparing` forever.
+        assert_eq!(
+            canisters.len(),
+            args.len(),
+            "one payload per participant required: got {} participants and {} payloads",
+            canisters.len(),
+            args.len()
+        );
+        let participants: Vec<ParticipantSpec> = canisters
+            .iter()
+            .zip(args.iter())
+            .map(|(canister, args)| ParticipantSpec {
+                canister: *canister,
+                prepare: method_prepare.to_string(),
+                abort: method_abort.to_string(),
+                commit: method_commit.to_string(),
+                payload: args.clone(),
+            })
+            .collect();
+        Self::new(tid, trace_id, &participants, cycles)
+    }
+
+    /// Append one more participant to a transaction that has not issued
+    /// any calls yet, e.g. the coordinator's own local fee leg. Adding a
+    /// participant to a transaction already collecting votes would
+    /// change what "unanimous" means mid-flight, hence the assert.
+    pub fn add_participant(&mut self, tid: TransactionId, spec: &ParticipantSpec, cycles: u128) {
+        assert_eq!(self.transaction_status, TransactionStatus::Preparing);
+        assert!(self.pending_prepare_calls.iter().all(|call| call.num_tries == 0));
+        let phases: [(&mut Vec<Call>, Phase, &str); 3] = [
+            (&mut self.pending_prepare_calls, Phase::Prepare, &spec.prepare),
+            (&mut self.pending_abort_calls, Phase::Abort, &spec.abort),
+            (&mut self.pending_commit_calls, Phase::Commit, &spec.commit),
+        ];
+        for (calls, phase, method) in phases {
+            calls.push(Call::new(
+                spec.canister,
+                method,
+                Envelope::new(tid, phase, self.trace_id, spec.payload.clone()).encode(),
+                cycles,
+            ));
+        }
+        self.total_number_of_children += 1;
+        // The new leg joins the (by default strict) quorum.
+        self.required_successes += 1;
+    }
+
+    /// Mark this transaction as being stepped by a `transaction_loop`
+    /// invocation. Returns `false` if another invocation is already in
+    /// progress, i.e. the caller must not issue any calls.
+    pub fn begin_step(&mut self) -> bool {
+        !std::mem::replace(&mut self.in_progress, true)
+    }
+
+    /// Clear the in-progress mark once all awaits of a step completed.
+    pub fn end_step(&mut self) {
+        self.in_progress = false;
+    }
+
+    /// Record one status transition in the trace, dropping it if the
+    /// trace is already at its length bound.
+    pub fn record_transition(&mut self, now: u64, from: TransactionStatus, to: TransactionStatus) {
+        if self.state_trace.len() < MAX_STATE_TRACE_LEN {
+            self.state_trace.push((now, from, to));
+        }
+    }
+
+    /// Record why the transaction is aborting; only the first reason is
+    /// kept.
+    pub fn record_abort_reason(&mut self, reason: AbortReason) {
+        self.abort_reason.get_or_insert(reason);
+    }
+
+    /// Attribute a drop of the canister's cycle balance to this
+    /// transaction.
+    pub fn record_cycles_spent(&mut self, balance_before: u128, balance_after: u128) {
+        self.cycles_spent = self
+            .cycles_spent
+            .saturating_add(balance_before.saturating_sub(balance_after));
+    }
+
+    /// Record that the given participant refused its prepare and thus
+    /// holds no lock for this transaction.
+    pub fn record_declined(&mut self, target: Principal) {
+        self.declined.insert(target);
+    }
+
+    /// True if the given participant may hold a lock for this
+    /// transaction: its prepare was issued at least once and it never
+    /// answered with a refusal. Only these participants need an abort
+    /// call.
+    fn participant_maybe_prepared(&self, target: Principal) -> bool {
+        !self.declined.contains(&target)
+            && self
+                .pending_prepare_calls
+                .iter()
+                .any(|call| call.target == target && call.num_tries > 0)
+    }
+
+    /// Total payload bytes this transaction holds on to, summed over the
+    /// calls of all three phases.
+    pub fn payload_bytes(&self) -> usize {
+        self.pending_prepare_calls
+            .iter()
+            .chain(self.pending_abort_calls.iter())
+            .chain(self.pending_commit_calls.iter())
+            .map(|call| call.payload.len())
+            .sum()
+    }
+
+    /// The point in time after which the prepare phase must be aborted:
+    /// the client-supplied deadline if there is one, otherwise the
+    /// default prepare timeout.
+    pub fn prepare_deadline(&self) -> u64 {
+        self.valid_until_ns
+            .unwrap_or(self.transaction_start_time + self.abort_prepare_after_ns)
+    }
+
+    /// Whether the prepare phase ran past this transaction's deadline,
+    /// e.g. because a participant holds on to the prepare call forever.
+    pub fn prepare_timed_out(&self, now: u64) -> bool {
+        now > self.prepare_deadline()
+    }
+
+    /// Whether some participant's prepare was attempted
+    /// `max_prepare_tries` times without ever succeeding. A participant
+    /// in that state is likely down for longer than the prepare deadline,
+    /// so there is no point in holding the other participants' locks
+    /// until it passes. Always false while the cap is disabled.
+    pub fn prepare_tries_exhausted(&self) -> bool {
+        self.max_prepare_tries > 0
+            && self
+                .pending_prepare_calls
+                .iter()
+                .any(|call| call.num_success == 0 && call.num_tries >= self.max_prepare_tries)
+    }
+
+    /// Register the answer of one participant to a prepare call.
+    ///
+    /// If all participants have voted "yes", the transaction moves to
+    /// `Committing`; a single "no" vote moves it to `Aborting`. A "yes"
+    /// arriving after the decision to abort is counted separately for
+    /// diagnostics: it must never push the vote count toward unanimity,
+    /// so an aborting transaction cannot drift back toward `Committing`.
+    pub fn prepare_received(&mut self, success: bool, target: Principal) {
+        // Tolerate straggling prepare answers while already aborting,
+        // or - with a sub-total `required_successes` - while already
+        // committing on a reached quorum.
+        assert!(
+            self.transaction_status == TransactionStatus::Preparing
+                || self.transaction_status == TransactionStatus::Aborting
+                || self.transaction_status == TransactionStatus::Committing
+        );
+        let call = self
+            .pending_prepare_calls
+            .iter_mut()
+            .find(|call| call.target == target)
+            .expect("no prepare call for this participant");
+        // A participant's vote is applied at most once. A retry racing a
+        // slow first answer can deliver a second vote for the same
+        // participant; counting it again would let one participant stand
+        // in for another in the unanimity check.
+        if call.num_success > 0 {
+            return;
+        }
+        if success {
+            if self.transaction_status == TransactionStatus::Aborting {
+                self.late_prepare_yes += 1;
+            } else {
+                call.num_success += 1;
+                assert_eq!(
+                    call.num_success, 1,
+                    "prepare vote applied more than once for {}",
+                    target
+                );
+            }
+        } else {
+            call.num_fail += 1;
+        }
+        if self.transaction_status == TransactionStatus::Preparing {
+            if self
+                .pending_prepare_calls
+                .iter()
+                .filter(|call| call.num_success > 0)
+                .count() as u64
+                >= self.required_successes
+            {
+                self.transaction_status = TransactionStatus::Committing;
+            } else if !success {
+                self.transaction_status = TransactionStatus::Aborting;
+            }
+        }
+    }
+
+    /// Register the answer of one participant to an abort call.
+    pub fn abort_received(&mut self, success: bool, target: Principal) {
+        assert_eq!(self.transaction_status, TransactionStatus::Aborting);
+        let call = self
+            .pending_abort_calls
+            .iter_mut()
+            .find(|call| call.target == target)
+            .expect("no abort call for this participant");
+        if success {
+            call.num_success += 1;
+        } else {
+            call.num_fail += 1;
+        }
+        if self
+            .pending_abort_calls
+            .iter()
+            .filter(|call| call.num_success > 0)
+            .count() as u64
+            == self.total_number_of_children
+        {
+            self.transaction_status = TransactionStatus::Aborted;
+        }
+    }
+
+    /// Register the answer of one participant to a commit call.
+    pub fn commit_received(&mut self, success: bool, target: Principal) {
+        assert_eq!(self.transaction_status, TransactionStatus::Committing);
+        let call = self
+            .pending_commit_calls
+            .iter_mut()
+            .find(|call| call.target == target)
+            .expect("no commit call for this participant");
+        if success {
+            call.num_success += 1;
+        } else {
+            call.num_fail += 1;
+        }
+        if self
+            .pending_commit_calls
+            .iter()
+            .filter(|call| call.num_success > 0)
+            .count() as u64
+            >= self.required_successes
+        {
+            self.transaction_status = TransactionStatus::Committed;
+        }
+    }
+}
+
+/// All transactions managed by this coordinator.
+#[derive(CandidType, Deserialize, Default)]
+pub struct TransactionList {
+    pub next_transaction_number: u64,
+    pub transactions: BTreeMap<TransactionId, TransactionState>,
+    /// Incrementally maintained index of the transactions the timer loop
+    /// still needs to drive, so each tick does not rescan the whole
+    /// table. Kept in sync on creation and on reaching a terminal
+    /// status; `rebuild_active_index` recovers it if it ever diverges.
+    pub active: BTreeSet<TransactionId>,
+}
+
+thread_local! {
+    static TRANSACTION_STATE: RefCell<TransactionList> = RefCell::new(TransactionList::default());
+    static DISABLE_TIMER: RefCell<bool> = const { RefCell::new(false) };
+    /// Whether the timer is scheduled to fire again. Starts out `true`:
+    /// nothing needs restarting before `init` arms the timer for the
+    /// first time.
+    static TIMER_ARMED: RefCell<bool> = const { RefCell::new(true) };
+    /// Consecutive timer ticks that saw no active transaction.
+    static IDLE_TICKS: RefCell<u64> = const { RefCell::new(0) };
+    static CONFIGURATION: RefCell<Configuration> = RefCell::new(Configuration::default());
+    /// Outstanding (issued but not yet replied) calls per participant,
+    /// bounded by `max_inflight_per_participant`.
+    static INFLIGHT_CALLS: RefCell<BTreeMap<Principal, u64>> =
+        const { RefCell::new(BTreeMap::new()) };
+}
+
+/// Reserve an in-flight slot toward the given participant. `false` if
+/// the participant is already at the cap; the caller then defers the
+/// call to the next tick instead of queueing more work on a participant
+/// that is not keeping up.
+fn inflight_begin(target: Principal, cap: u64) -> bool {
+    INFLIGHT_CALLS.with(|calls| {
+        let mut calls = calls.borrow_mut();
+        let count = calls.entry(target).or_insert(0);
+        if *count >= cap {
+            return false;
+        }
+        *count += 1;
+        true
+    })
+}
+
+/// Release an in-flight slot once the call replied or failed.
+fn inflight_end(target: Principal) {
+    INFLIGHT_CALLS.with(|calls| {
+        let mut calls = calls.borrow_mut();
+        if let Some(count) = calls.get_mut(&target) {
+            *count = count.saturating_sub(1);
+            if *count == 0 {
+                calls.remove(&target);
+            }
+        }
+    })
+}
+
+/// The current in-flight call count per participant. Participants with
+/// no outstanding call are omitted.
+#[query]
+pub fn participant_stats() -> Vec<(Principal, u64)> {
+    INFLIGHT_CALLS.with(|calls| calls.borrow().iter().map(|(p, c)| (*p, *c)).collect())
+}
+
+/// Set the configuration of the coordinator.
+#[update]
+pub fn set_configuration(configuration: Configuration) {
+    CONFIGURATION.with(|config| *config.borrow_mut() = configuration);
+}
+
+pub fn get_configuration() -> Configuration {
+    CONFIGURATION.with(|config| config.borrow().clone())
+}
+
+/// Reasons the coordinator refuses to create a transaction.
+#[derive(CandidType, Clone, Debug, PartialEq, Eq)]
+pub enum TransactionError {
+    /// The summed leg payloads exceed `max_transaction_payload_bytes`.
+    PayloadTooLarge,
+    /// A signed intent's signature does not verify against the claimed
+    /// public key.
+    InvalidSignature,
+    /// A signed intent's payload is not a valid `SwapIntent` encoding.
+    MalformedIntent,
+    /// A rebalance request failed its consistency checks, e.g. an empty
+    /// or zero-amount leg, a duplicated leg, or a value imbalance where
+    /// the caller requested neutrality.
+    InconsistentRebalance,
+    /// A transaction addressed a principal that is not one of the
+    /// registered ledgers.
+    UnknownParticipant,
+    /// The coordinator has fewer registered ledgers than the request
+    /// needs, e.g. because `init` has not been called yet.
+    NotInitialized,
+    /// A requested token does not exist on its target ledger.
+    UnknownToken,
+    /// A requested (participant, token) pair is already targeted by an
+    /// unfinished transaction; running both concurrently would only make
+    /// the loser abort after wasted work.
+    ResourceBusy,
+    /// Every amount of the requested swap is zero; committing it would
+    /// do nothing, so no transaction is created.
+    NoOpSwap,
+    /// The addressed transaction does not exist on this coordinator.
+    UnknownTransaction,
+    /// The coordinator already has `max_active_transactions` unfinished
+    /// transactions; retry once some of them finalize.
+    TooManyInFlight,
+}
+
+/// Check a freshly built transaction against the configured payload cap.
+pub fn check_payload_cap(
+    state: &TransactionState,
+    configuration: &Configuration,
+) -> Result<(), TransactionError> {
+    if state.payload_bytes() as u64 > configuration.max_transaction_payload_bytes {
+        Err(TransactionError::PayloadTooLarge)
+    } else {
+        Ok(())
+    }
+}
+
+/// Check the in-flight transaction cap before accepting a new
+/// transaction. Only unfinished transactions count: the `active` index
+/// drops them as they finalize.
+pub fn check_active_cap(configuration: &Configuration) -> Result<(), TransactionError> {
+    with_transaction_list(|list| {
+        if (list.active.len() as u64) < configuration.max_active_transactions {
+            Ok(())
+        } else {
+            Err(TransactionError::TooManyInFlight)
+        }
+    })
+}
+
+pub fn with_transaction_list<R>(f: impl FnOnce(&mut TransactionList) -> R) -> R {
+    TRANSACTION_STATE.with(|state| f(&mut state.borrow_mut()))
+}
+
+/// Run `f` on the given transaction, or report `UnknownTransaction`
+/// instead of trapping when no such transaction exists.
+pub fn with_transaction<R>(
+    tid: TransactionId,
+    f: impl FnOnce(&TransactionState) -> R,
+) -> Result<R, TransactionError> {
+    with_transaction_list(|list| {
+        list.transactions
+            .get(&tid)
+            .map(f)
+            .ok_or(TransactionError::UnknownTransaction)
+    })
+}
+
+/// Mutable counterpart of `with_transaction`.
+pub fn with_transaction_mut<R>(
+    tid: TransactionId,
+    f: impl FnOnce(&mut TransactionState) -> R,
+) -> Result<R, TransactionError> {
+    with_transaction_list(|list| {
+        list.transactions
+            .get_mut(&tid)
+            .map(f)
+            .ok_or(TransactionError::UnknownTransaction)
+    })
+}
+
+/// Allocate the next transaction nonce, monotonic within this
+/// coordinator.
+fn next_transaction_nonce() -> u64 {
+    with_transaction_list(|list| {
+        let nonce = list.next_transaction_number;
+        list.next_transaction_number += 1;
+        nonce
+    })
+}
+
+/// Read the nonce the next transaction will be allocated, without
+/// consuming it.
+fn peek_transaction_nonce() -> u64 {
+    with_transaction_list(|list| list.next_transaction_number)
+}
+
+/// Allocate the next transaction ID: this coordinator's principal plus
+/// a monotonic nonce, so IDs stay unique even when participants are
+/// shared across several coordinators.
+pub fn get_next_transaction_number() -> TransactionId {
+    TransactionId::new(ic_cdk::id(), next_transaction_nonce())
+}
+
+/// The transaction ID the next swap will receive. Purely observational:
+/// repeated calls return the same ID until a swap actually allocates it,
+/// so clients can correlate an upcoming swap with its ID up front.
+#[query]
+pub fn peek_next_transaction_number() -> TransactionId {
+    TransactionId::new(ic_cdk::id(), peek_transaction_nonce())
+}
+
+/// Register a new transaction with the coordinator. The timer loop will
+/// start driving it on its next tick; if the timer stopped itself while
+/// idle, it is re-armed here.
+pub fn add_transaction(tid: TransactionId, mut state: TransactionState, now: u64) {
+    state.transaction_start_time = now;
+    with_transaction_list(|list| {
+        if !state.transaction_status.is_final() {
+            list.active.insert(tid);
+        }
+        list.transactions.insert(tid, state)
+    });
+    ensure_timer_running();
+}
+
+/// True if an aborted transaction should be attempted again: it must
+/// have attempts left in its budget and have aborted for a transient
+/// reason.
+fn should_retry(state: &TransactionState) -> bool {
+    state.retries_left > 0
+        && state
+            .abort_reason
+            .as_ref()
+            .is_some_and(|reason| reason.retryable())
+}
+
+/// Build a fresh transaction carrying the same legs as an aborted one,
+/// with one fewer retry in the budget and linked to the same root.
+fn retry_state(
+    new_tid: TransactionId,
+    old_tid: TransactionId,
+    old: &TransactionState,
+) -> TransactionState {
+    let participants: Vec<ParticipantSpec> = old
+        .pending_prepare_calls
+        .iter()
+        .zip(old.pending_abort_calls.iter())
+        .zip(old.pending_commit_calls.iter())
+        .map(|((prepare, abort), commit)| ParticipantSpec {
+            canister: prepare.target,
+            prepare: prepare.method.clone(),
+            abort: abort.method.clone(),
+            commit: commit.method.clone(),
+            payload: Envelope::decode(&prepare.payload).unwrap().args,
+        })
+        .collect();
+    let mut state = TransactionState::new(
+        new_tid,
+        old.trace_id,
+        &participants,
+        old.pending_prepare_calls[0].cycles,
+    );
+    state.valid_until_ns = old.valid_until_ns;
+    state.root_tid = Some(old.root_tid.unwrap_or(old_tid));
+    state.retries_left = old.retries_left - 1;
+    state.initiator = old.initiator;
+    state
+}
+
+fn _retry_chain(list: &TransactionList, tid: TransactionId) -> Vec<TransactionId> {
+    let root = match list.transactions.get(&tid) {
+        Some(state) => state.root_tid.unwrap_or(tid),
+        None => return vec![],
+    };
+    list.transactions
+        .iter()
+        .filter(|(other_tid, state)| **other_tid == root || state.root_tid == Some(root))
+        .map(|(tid, _)| *tid)
+        .collect()
+}
+
+/// All transactions of the retry chain the given transaction belongs to,
+/// in the order they were attempted. Empty for unknown transaction IDs.
+#[query]
+pub fn retry_chain(tid: TransactionId) -> Vec<TransactionId> {
+    with_transaction_list(|list| _retry_chain(list, tid))
+}
+
+/// The transactions that still need to be driven by the timer loop,
+/// read from the incremental index. Manual-only transactions stay in
+/// the index (they are not final) but are not handed to the timer.
+pub fn get_active_transactions() -> Vec<TransactionId> {
+    with_transaction_list(|list| {
+        list.active
+            .iter()
+            .copied()
+            .filter(|tid| {
+                !list
+                    .transactions
+                    .get(tid)
+                    .is_some_and(|state| state.manual_only)
+            })
+            .collect()
+    })
+}
+
+thread_local! {
+    /// Where completion notifications are pushed, once an observer
+    /// registered itself.
+    static OBSERVER: RefCell<Option<(Principal, String)>> = const { RefCell::new(None) };
+}
+
+/// Register (or replace) the observer notified whenever a transaction
+/// completes, so clients do not have to poll `get_transaction_state`.
+/// Only callable by a controller.
+#[update]
+pub fn register_observer(canister: Principal, method: String) {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        ic_cdk::trap("register_observer can only be called by a controller");
+    }
+    OBSERVER.with(|observer| *observer.borrow_mut() = Some((canister, method)));
+}
+
+/// True if entering the given status warrants a completion
+/// notification: only settled outcomes are pushed. `NeedsReview` is an
+/// operator concern, surfaced via `check_invariants` instead.
+fn _should_notify(status: &TransactionStatus) -> bool {
+    matches!(
+        status,
+        TransactionStatus::Committed | TransactionStatus::Aborted
+    )
+}
+
+/// Push the given result to the registered observer, if any. Best
+/// effort: the one-way call cannot block the transaction, and a
+/// refused or trapping observer only costs a log line.
+fn notify_observer(result: &TransactionResult) {
+    let Some((canister, method)) = OBSERVER.with(|observer| observer.borrow().clone()) else {
+        return;
+    };
+    let payload = Encode!(result).unwrap();
+    if let Err(code) = ic_cdk::api::call::notify_raw(canister, &method, &payload, 0) {
+        ic_cdk::println!(
+            "Observer notification to {} failed: {:?}",
+            canister.to_text(),
+            code
+        );
+    }
+}
+
+/// Take the given transaction out of automatic management (or hand it
+/// back): the timer loop skips manual-only transactions, while explicit
+/// `transaction_loop` calls still advance them. Only callable by a
+/// controller.
+#[update]
+pub fn set_manual(tid: TransactionId, manual_only: bool) -> Result<(), TransactionError> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        ic_cdk::trap("set_manual can only be called by a controller");
+    }
+    with_transaction_mut(tid, |state| state.manual_only = manual_only)
+}
+
+/// Force a stuck transaction into the given final state by operator
+/// fiat, e.g. a transaction the commit loop retries forever because a
+/// participant is gone for good. Only final outcomes are accepted, and
+/// only a controller may call this: it trades 2PC's durability
+/// guarantee for an escape hatch, so participants may disagree with the
+/// recorded outcome afterwards.
+#[update]
+pub fn force_finalize(
+    tid: TransactionId,
+    outcome: TransactionStatus,
+) -> Result<(), TransactionError> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        ic_cdk::trap("force_finalize can only be called by a controller");
+    }
+    with_transaction_list(|list| _force_finalize(list, tid, outcome, ic_cdk::api::time()))
+}
+
+fn _force_finalize(
+    list: &mut TransactionList,
+    tid: TransactionId,
+    outcome: TransactionStatus,
+    now: u64,
+) -> Result<(), TransactionError> {
+    assert!(
+        outcome.is_final(),
+        "force_finalize only accepts final outcomes"
+    );
+    let state = list
+        .transactions
+        .get_mut(&tid)
+        .ok_or(TransactionError::UnknownTransaction)?;
+    let old_status = state.transaction_status.clone();
+    state.transaction_status = outcome.clone();
+    state.record_transition(now, old_status.clone(), outcome.clone());
+    list.active.remove(&tid);
+    log_event(
+        &LogEvent {
+            tid: tid.to_string(),
+            phase: "status",
+            participant: None,
+            outcome: format!("force-finalized: {:?} -> {:?}", old_status, outcome),
+            timestamp: now,
+        },
+        || {
+            Colour::Red
+                .paint(format!(
+                    "Transaction {}: FORCE-FINALIZED from {:?} to {:?} by an operator -                      participants were not consulted and may record a different outcome",
+                    tid, old_status, outcome
+                ))
+                .to_string()
+        },
+    );
+    Ok(())
+}
+
+/// Recompute the active-transaction index with a full scan of the
+/// transaction table.
+fn _rebuild_active_index(list: &mut TransactionList) {
+    list.active = list
+        .transactions
+        .iter()
+        .filter(|(_, state)| !state.transaction_status.is_final())
+        .map(|(tid, _)| *tid)
+        .collect();
+}
+
+/// Take the whole transaction table out of the canister for
+/// `pre_upgrade`: without this, an upgrade wipes all in-flight
+/// transactions and leaves the participants locked until their leases
+/// expire.
+pub fn export_transaction_state() -> TransactionList {
+    TRANSACTION_STATE.with(|state| std::mem::take(&mut *state.borrow_mut()))
+}
+
+/// Put a transaction table saved by `export_transaction_state` back in
+/// place after an upgrade.
+pub fn restore_transaction_state(mut list: TransactionList) {
+    // Steps that were awaiting a participant's reply died with the
+    // upgrade; clear their guards so the timer can step the restored
+    // transactions again.
+    for state in list.transactions.values_mut() {
+        state.in_progress = false;
+    }
+    TRANSACTION_STATE.with(|state| *state.borrow_mut() = list);
+}
+
+/// Candid-encode one transaction's full state - status, the calls of
+/// all three phases, the timing fields - so an operator can move a
+/// stuck transaction to a different coordinator canister.
+#[query]
+pub fn export_transaction(tid: TransactionId) -> Result<Vec<u8>, TransactionError> {
+    with_transaction(tid, |state| Encode!(state).unwrap())
+}
+
+/// Decode a transaction exported with `export_transaction` and clear
+/// its per-step bookkeeping, so the importing coordinator starts
+/// driving it from a clean step.
+fn _import_state(blob: &[u8]) -> TransactionState {
+    let mut state = Decode!(blob, TransactionState).expect("not an exported transaction");
+    // The exporting coordinator may have been mid-step; its reentrancy
+    // guard and rate-limit clock mean nothing over here.
+    state.end_step();
+    state.last_action_time = 0;
+    state
+}
+
+/// Register a transaction exported from another coordinator and let the
+/// timer resume driving it. It gets a fresh local transaction ID to
+/// avoid colliding with this coordinator's own numbering; the call
+/// envelopes keep the original ID, which is the one the participants
+/// hold their in-flight prepares under. Only callable by a controller.
+#[update]
+pub fn import_transaction(blob: Vec<u8>) -> TransactionId {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        ic_cdk::trap("import_transaction can only be called by a controller");
+    }
+    let state = _import_state(&blob);
+    let tid = get_next_transaction_number();
+    add_transaction(tid, state, ic_cdk::api::time());
+    tid
+}
+
+/// Recovery path in case the incremental active-transaction index ever
+/// diverges from the actual statuses (e.g. after a manual state edit):
+/// rebuild it from a full scan, without reinstalling the canister. Only
+/// callable by a controller.
+#[update]
+pub fn rebuild_active_index() {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        ic_cdk::trap("rebuild_active_index can only be called by a controller");
+    }
+    with_transaction_list(_rebuild_active_index);
+}
+
+/// Per-participant call counters of the currently relevant phase, so a
+/// client can see which participant is slow or failing.
+#[derive(CandidType, Clone, Debug)]
+pub struct CallReport {
+    pub target: Principal,
+    pub num_tries: u64,
+    pub num_success: u64,
+    pub num_fail: u64,
+}
+
+impl From<&Call> for CallReport {
+    fn from(call: &Call) -> Self {
+        CallReport {
+            target: call.target,
+            num_tries: call.num_tries,
+            num_success: call.num_success,
+            num_fail: call.num_fail,
+        }
+    }
+}
+
+/// The result of a transaction as reported to clients.
+#[derive(CandidType, Clone, Debug)]
+pub struct TransactionResult {
+    pub transaction_number: TransactionId,
+    pub state: TransactionStatus,
+    /// Who initiated the transaction, for auditing. The anonymous
+    /// principal if it was created without an authenticated caller.
+    pub initiator: Principal,
+    /// One report per participant, covering the calls of the phase the
+    /// transaction is currently in (or ended in).
+    pub call_reports: Vec<CallReport>,
+    /// Cycles burned by this canister while driving the transaction.
+    pub cycles_spent: u128,
+    /// How many participants have acknowledged their commit. Relevant
+    /// while `Committing`: a split like "3 of 4 committed" means the
+    /// acknowledged participants already applied the change and released
+    /// their locks, and only the remaining commits are being retried.
+    pub committed_count: u64,
+    /// Total number of participants, the denominator of
+    /// `committed_count`.
+    pub total: u64,
+    /// Why the transaction aborted (or is aborting): turns a bare
+    /// `Aborted` into an actionable diagnosis. `None` outside the abort
+    /// path.
+    pub abort_reason: Option<AbortReason>,
+}
+
+fn _get_transaction_result(tid: TransactionId, state: &TransactionState) -> TransactionResult {
+    let phase_calls = match state.transaction_status {
+        TransactionStatus::Preparing => &state.pending_prepare_calls,
+        TransactionStatus::Aborting | TransactionStatus::Aborted => &state.pending_abort_calls,
+        // `NeedsReview` is reached from the commit phase, so the commit
+        // counters are what the operator wants to look at.
+        TransactionStatus::Committing
+        | TransactionStatus::Committed
+        | TransactionStatus::NeedsReview => &state.pending_commit_calls,
+    };
+    TransactionResult {
+        transaction_number: tid,
+        state: state.transaction_status.clone(),
+        initiator: state.initiator,
+        call_reports: phase_calls.iter().map(CallReport::from).collect(),
+        cycles_spent: state.cycles_spent,
+        committed_count: state
+            .pending_commit_calls
+            .iter()
+            .filter(|call| call.num_success > 0)
+            .count() as u64,
+        total: state.total_number_of_children,
+        abort_reason: state.abort_reason.clone(),
+    }
+}
+
+/// Query the current state of the given transaction.
+#[query]
+pub fn get_transaction_state(tid: TransactionId) -> Result<TransactionResult, TransactionError> {
+    with_transaction(tid, |state| _get_transaction_result(tid, state))
+}
+
+/// Page through all transactions in transaction-number order, so an
+/// operator can enumerate them without knowing the IDs. An `offset`
+/// past the end and a `limit` of zero both yield an empty vector.
+#[query]
+pub fn list_transactions(offset: usize, limit: usize) -> Vec<TransactionResult> {
+    with_transaction_list(|list| {
+        list.transactions
+            .iter()
+            .skip(offset)
+            .take(limit)
+            .map(|(tid, state)| _get_transaction_result(*tid, state))
+            .collect()
+    })
+}
+
+/// Number of transactions in the table, for sizing `list_transactions`
+/// pages.
+#[query]
+pub fn count_transactions() -> usize {
+    with_transaction_list(|list| list.transactions.len())
+}
+
+/// Cycles burned across all transactions still in the table, for
+/// spotting runaway retry loops.
+#[query]
+pub fn total_cycles_spent() -> u128 {
+    with_transaction_list(|list| {
+        list.transactions
+            .values()
+            .map(|state| state.cycles_spent)
+            .sum()
+    })
+}
+
+fn _transactions_of(list: &TransactionList, initiator: Principal) -> Vec<TransactionResult> {
+    list.transactions
+        .iter()
+        .filter(|(_, state)| state.initiator == initiator)
+        .map(|(tid, state)| _get_transaction_result(*tid, state))
+        .collect()
+}
+
+/// The transactions initiated by the caller, in transaction-number
+/// order. Anonymous callers see exactly the transactions that were
+/// created anonymously, so authenticated swaps never show up there.
+#[query]
+pub fn list_my_transactions() -> Vec<TransactionResult> {
+    with_transaction_list(|list| _transactions_of(list, ic_cdk::caller()))
+}
+
+fn _gc_finalized_transactions(list: &mut TransactionList, older_than_ns: u64) -> usize {
+    let before = list.transactions.len();
+    list.transactions.retain(|_, state| {
+        !(state.transaction_status.is_final() && state.last_action_time < older_than_ns)
+    });
+    before - list.transactions.len()
+}
+
+/// Drop transactions in a final state that were last acted on more than
+/// `older_than_ns` ago, returning how many were purged. Keeps memory
+/// growth of a long-running coordinator bounded; the timer calls this
+/// with the `FINALIZED_RETENTION_NS` window, this update lets an
+/// operator purge more aggressively. The age-based parameter matches
+/// `purge_archive`.
+#[update]
+pub fn gc_finalized_transactions(older_than_ns: u64) -> usize {
+    let cutoff = ic_cdk::api::time().saturating_sub(older_than_ns);
+    with_transaction_list(|list| {
+        let purged = _gc_finalized_transactions(list, cutoff);
+        expire_idempotency_keys(list);
+        purged
+    })
+}
+
+/// Test-only: force the given transaction into the given status, keeping
+/// the active index in sync. Lets tests exercise states that are hard to
+/// reach naturally, such as `NeedsReview`. Strictly gated behind the
+/// `test-helpers` feature so production builds do not export it.
+#[cfg(any(test, feature = "test-helpers"))]
+#[update]
+pub fn set_status(tid: TransactionId, status: TransactionStatus) {
+    with_transaction_list(|list| {
+        if let Some(state) = list.transactions.get_mut(&tid) {
+            state.transaction_status = status.clone();
+            if status.is_final() {
+                list.active.remove(&tid);
+            } else {
+                list.active.insert(tid);
+            }
+        }
+    });
+}
+
+/// Consolidated per-transaction metadata, covering both live and
+/// archived transactions.
+#[derive(CandidType, Clone, Debug)]
+pub struct TransactionInfo {
+    pub transaction_number: TransactionId,
+    pub status: TransactionStatus,
+    /// Time the transaction was registered. `None` for archived
+    /// transactions, which only retain their completion time.
+    pub created_at: Option<u64>,
+    /// Time the transaction reached its terminal state, `None` while it
+    /// is still live.
+    pub completed_at: Option<u64>,
+    pub valid_until_ns: Option<u64>,
+    /// The principal that initiated the transaction, `None` for archived
+    /// transactions.
+    pub initiator: Option<Principal>,
+}
+
+fn _transaction_info(tid: TransactionId) -> Option<TransactionInfo> {
+    let live = with_transaction_list(|list| {
+        list.transactions.get(&tid).map(|state| TransactionInfo {
+            transaction_number: tid,
+            status: state.transaction_status.clone(),
+            created_at: Some(state.transaction_start_time),
+            completed_at: None,
+            valid_until_ns: state.valid_until_ns,
+            initiator: Some(state.initiator),
+        })
+    });
+    live.or_else(|| {
+        with_archive(|archive| {
+            archive
+                .iter()
+                .find(|entry| entry.result.transaction_number == tid)
+                .map(|entry| TransactionInfo {
+                    transaction_number: tid,
+                    status: entry.result.state.clone(),
+                    created_at: None,
+                    completed_at: Some(entry.completed_at),
+                    valid_until_ns: None,
+                    initiator: None,
+                })
+        })
+    })
+}
+
+fn _request_abort(state: &mut TransactionState, caller: Principal, now: u64) -> bool {
+    if state.transaction_status != TransactionStatus::Preparing {
+        return false;
+    }
+    // Only registered participants of this transaction may abort it.
+    if !state
+        .pending_prepare_calls
+        .iter()
+        .any(|call| call.target == caller)
+    {
+        return false;
+    }
+    state.record_abort_reason(AbortReason::ParticipantRequest);
+    state.record_transition(now, TransactionStatus::Preparing, TransactionStatus::Aborting);
+    state.transaction_status = TransactionStatus::Aborting;
+    true
+}
+
+/// Cooperative abort channel for participants: a participant that
+/// detects a local problem after voting "yes" (e.g. an operator freeze)
+/// can ask for the abort instead of letting the commit fail later.
+///
+/// Only registered participants of a still-`Preparing` transaction may
+/// request its abort; everything else is refused.
+#[update]
+pub fn request_abort(tid: TransactionId) -> bool {
+    let caller = ic_cdk::caller();
+    let now = ic_cdk::api::time();
+    with_transaction_list(|list| match list.transactions.get_mut(&tid) {
+        Some(state) => _request_abort(state, caller, now),
+        None => false,
+    })
+}
+
+fn _cancel_transaction(state: &mut TransactionState, now: u64) -> bool {
+    // A transaction that started committing (or already ended) cannot be
+    // taken back; only an undecided prepare phase may be cancelled.
+    if state.transaction_status != TransactionStatus::Preparing {
+        return false;
+    }
+    // `Cancelled` is not retryable, so a voluntary cancel is never
+    // auto-retried.
+    state.record_abort_reason(AbortReason::Cancelled);
+    state.record_transition(now, TransactionStatus::Preparing, TransactionStatus::Aborting);
+    state.transaction_status = TransactionStatus::Aborting;
+    true
+}
+
+/// Voluntarily cancel a transaction that is still `Preparing`: it moves
+/// to `Aborting` and the regular abort machinery releases any locks the
+/// prepares already acquired. A transaction that is `Committing` or in a
+/// final state is left untouched, because committing cannot be undone.
+/// Returns the (possibly unchanged) state of the transaction.
+#[update]
+pub fn cancel_transaction(tid: TransactionId) -> Result<TransactionResult, TransactionError> {
+    let now = ic_cdk::api::time();
+    with_transaction_list(|list| {
+        if let Some(state) = list.transactions.get_mut(&tid) {
+            _cancel_transaction(state, now);
+        }
+    });
+    get_transaction_state(tid)
+}
+
+/// One participant's leg in a reconciliation report.
+#[derive(CandidType, Clone, Debug)]
+pub struct ReconciliationEntry {
+    pub target: Principal,
+    pub token: TokenName,
+    /// True if the coordinator has recorded this participant's "yes"
+    /// vote.
+    pub coordinator_prepared: bool,
+    /// The lock state the participant reports for the token.
+    pub participant_status: Option<ParticipantStatus>,
+    pub agrees: bool,
+}
+
+/// Result of cross-checking one transaction against its participants.
+#[derive(CandidType, Clone, Debug)]
+pub struct ReconciliationReport {
+    pub tid: TransactionId,
+    pub entries: Vec<ReconciliationEntry>,
+    /// True if the reconciliation aborted the transaction because a
+    /// participant no longer backs a vote the coordinator recorded.
+    pub aborted: bool,
+}
+
+fn _reconcile(
+    state: &mut TransactionState,
+    tid: TransactionId,
+    statuses: &[(Principal, String, Option<ParticipantStatus>)],
+    now: u64,
+) -> ReconciliationReport {
+    let mut entries = vec![];
+    for (target, token, participant_status) in statuses {
+        let coordinator_prepared = state
+            .pending_prepare_calls
+            .iter()
+            .any(|call| call.target == *target && call.num_success > 0);
+        let participant_prepared = matches!(
+            participant_status,
+            Some(ParticipantStatus::Prepared(other_tid)) if *other_tid == tid
+        );
+        entries.push(ReconciliationEntry {
+            target: *target,
+            token: token.clone(),
+            coordinator_prepared,
+            participant_status: participant_status.clone(),
+            agrees: coordinator_prepared == participant_prepared,
+        });
+    }
+    // A vote the coordinator holds but the participant no longer backs,
+    // e.g. because the lock's lease expired, can never commit: abort
+    // while still preparing instead of failing at commit time.
+    let split_brain = entries
+        .iter()
+        .any(|entry| entry.coordinator_prepared && !entry.agrees);
+    let mut aborted = false;
+    if split_brain && state.transaction_status == TransactionStatus::Preparing {
+        state.record_abort_reason(AbortReason::PrepareTimeout);
+        state.record_transition(now, TransactionStatus::Preparing, TransactionStatus::Aborting);
+        state.transaction_status = TransactionStatus::Aborting;
+        aborted = true;
+    }
+    ReconciliationReport {
+        tid,
+        entries,
+        aborted,
+    }
+}
+
+/// Safety net: cross-check the coordinator's recorded prepare votes for
+/// the given transaction against the lock state each participant
+/// reports, and abort a still-`Preparing` transaction if a participant
+/// dropped a lock the coordinator counts on. Detects and heals
+/// split-brain between the two views, e.g. after a lease expiry the
+/// coordinator has not noticed. Returns `None` for unknown transaction
+/// IDs.
+#[update]
+pub async fn reconcile(tid: TransactionId) -> Option<ReconciliationReport> {
+    let legs = with_transaction_list(|list| list.transactions.get(&tid).map(transaction_legs))?;
+    let mut statuses = vec![];
+    for (target, token) in legs {
+        let status = match ic_cdk::api::call::call::<_, (Option<ParticipantStatus>,)>(
+            target,
+            "token_status",
+            (token.clone(),),
+        )
+        .await
+        {
+            Ok((status,)) => status,
+            Err(_) => None,
+        };
+        statuses.push((target, token, status));
+    }
+    let now = ic_cdk::api::time();
+    with_transaction_list(|list| {
+        list.transactions
+            .get_mut(&tid)
+            .map(|state| _reconcile(state, tid, &statuses, now))
+    })
+}
+
+/// The status history of a transaction: when it was created and when it
+/// entered each subsequent status, derived from the state trace so the
+/// same length bound applies.
+fn _transaction_history(state: &TransactionState) -> Vec<(u64, TransactionStatus)> {
+    std::iter::once((state.transaction_start_time, TransactionStatus::Preparing))
+        .chain(
+            state
+                .state_trace
+                .iter()
+                .map(|(now, _, to)| (*now, to.clone())),
+        )
+        .collect()
+}
+
+/// Query when the given transaction entered each of its statuses,
+/// starting with `Preparing` at creation time. Empty for unknown
+/// transaction IDs.
+#[query]
+pub fn get_transaction_history(tid: TransactionId) -> Vec<(u64, TransactionStatus)> {
+    with_transaction_list(|list| {
+        list.transactions
+            .get(&tid)
+            .map(_transaction_history)
+            .unwrap_or_default()
+    })
+}
+
+/// The ordered status transitions the given transaction underwent, as
+/// (timestamp, from, to) triples: a queryable version of the "state
+/// changed from X to Y" log line, for understanding why a transaction
+/// ended where it did. Empty for unknown transaction IDs.
+#[query]
+pub fn state_trace(tid: TransactionId) -> Vec<(u64, TransactionStatus, TransactionStatus)> {
+    with_transaction_list(|list| {
+        list.transactions
+            .get(&tid)
+            .map(|state| state.state_trace.clone())
+            .unwrap_or_default()
+    })
+}
+
+/// The canonical "everything about this transaction except per-call
+/// details" endpoint: all per-transaction metadata in one query, for
+/// live as well as archived transactions. Returns `None` for unknown
+/// transaction IDs.
+#[query]
+pub fn transaction_info(tid: TransactionId) -> Option<TransactionInfo> {
+    _transaction_info(tid)
+}
+
+/// One violated invariant found by `check_invariants`.
+#[derive(CandidType, Clone, Debug, PartialEq, Eq)]
+pub struct InvariantViolation {
+    pub transaction_number: TransactionId,
+    pub description: String,
+}
+
+fn _check_invariants(list: &TransactionList, now: u64) -> Vec<InvariantViolation> {
+    let mut violations = vec![];
+    let mut violation = |tid: TransactionId, description: &str| {
+        violations.push(InvariantViolation {
+            transaction_number: tid,
+            description: description.to_string(),
+        })
+    };
+    for (tid, state) in &list.transactions {
+        let all_succeeded =
+            |calls: &[Call]| calls.iter().all(|call| call.num_success > 0);
+        // With a sub-total `required_successes`, reaching the quorum is
+        // what the protocol promises, so that is what is checked.
+        let quorum_reached = |calls: &[Call]| {
+            calls.iter().filter(|call| call.num_success > 0).count() as u64
+                >= state.required_successes
+        };
+        if matches!(
+            state.transaction_status,
+            TransactionStatus::Committing | TransactionStatus::Committed
+        ) && !quorum_reached(&state.pending_prepare_calls)
+        {
+            violation(*tid, "committing without the required yes votes");
+        }
+        if state.transaction_status == TransactionStatus::Committed
+            && !quorum_reached(&state.pending_commit_calls)
+        {
+            violation(*tid, "committed without the required commit successes");
+        }
+        if state.transaction_status == TransactionStatus::Aborted
+            && !all_succeeded(&state.pending_abort_calls)
+        {
+            violation(*tid, "aborted without all abort calls succeeding");
+        }
+        // A transaction only decides to abort if at least one "yes" is
+        // missing; late yes-votes are counted separately, so a unanimous
+        // count here means the counters were miscomputed.
+        if matches!(
+            state.transaction_status,
+            TransactionStatus::Aborting | TransactionStatus::Aborted
+        ) && all_succeeded(&state.pending_prepare_calls)
+        {
+            violation(*tid, "aborting despite a unanimous yes vote count");
+        }
+        for call in state
+            .pending_prepare_calls
+            .iter()
+            .chain(state.pending_abort_calls.iter())
+            .chain(state.pending_commit_calls.iter())
+        {
+            if call.num_success + call.num_fail > call.num_tries {
+                violation(*tid, "call answered more often than it was tried");
+                break;
+            }
+        }
+        if state.last_action_time > now {
+            violation(*tid, "last action time lies in the future");
+        }
+    }
+    violations
+}
+
+/// Scan the whole transaction table and report every violated protocol
+/// invariant, e.g. a `Committing` transaction without a unanimous "yes"
+/// vote. Cheap enough to be polled by monitoring, and a strong assertion
+/// for integration tests: a healthy coordinator always returns an empty
+/// vector.
+#[query]
+pub fn check_invariants() -> Vec<InvariantViolation> {
+    with_transaction_list(|list| _check_invariants(list, ic_cdk::api::time()))
+}
+
+/// One transaction in a `StateSnapshot`: its status plus the summed
+/// success/failure counters over the calls of all three phases.
+#[derive(CandidType, Clone, Debug, PartialEq, Eq)]
+pub struct SnapshotEntry {
+    pub transaction_number: TransactionId,
+    pub status: TransactionStatus,
+    pub num_success: u64,
+    pub num_fail: u64,
+}
+
+/// A compact, deterministic summary of the coordinator state: one entry
+/// per transaction, ordered by transaction number. Capturing one before
+/// and one after an action and diffing them shows exactly what the
+/// action changed; this is more targeted than a full state dump.
+#[derive(CandidType, Clone, Debug, PartialEq, Eq)]
+pub struct StateSnapshot {
+    pub transactions: Vec<SnapshotEntry>,
+}
+
+fn _snapshot(list: &TransactionList) -> StateSnapshot {
+    StateSnapshot {
+        // BTreeMap iteration is ordered by transaction number, keeping
+        // the snapshot deterministic.
+        transactions: list
+            .transactions
+            .iter()
+            .map(|(tid, state)| {
+                let calls = state
+                    .pending_prepare_calls
+                    .iter()
+                    .chain(state.pending_abort_calls.iter())
+                    .chain(state.pending_commit_calls.iter());
+                SnapshotEntry {
+                    transaction_number: *tid,
+                    status: state.transaction_status.clone(),
+                    num_success: calls.clone().map(|call| call.num_success).sum(),
+                    num_fail: calls.map(|call| call.num_fail).sum(),
+                }
+            })
+            .collect(),
+    }
+}
+
+/// Capture a `StateSnapshot` of all live transactions.
+#[query]
+pub fn snapshot() -> StateSnapshot {
+    with_transaction_list(|list| _snapshot(list))
+}
+
+/// The entries that differ between two snapshots, as (before, after)
+/// pairs; `None` on either side means the transaction only exists in the
+/// other snapshot. Stable across runs since snapshots are ordered.
+pub fn snapshot_diff(
+    before: &StateSnapshot,
+    after: &StateSnapshot,
+) -> Vec<(Option<SnapshotEntry>, Option<SnapshotEntry>)> {
+    let mut diff = vec![];
+    let find = |snapshot: &StateSnapshot, tid: TransactionId| {
+        snapshot
+            .transactions
+            .iter()
+            .find(|entry| entry.transaction_number == tid)
+            .cloned()
+    };
+    for entry in &before.transactions {
+        let other = find(after, entry.transaction_number);
+        if other.as_ref() != Some(entry) {
+            diff.push((Some(entry.clone()), other));
+        }
+    }
+    for entry in &after.transactions {
+        if find(before, entry.transaction_number).is_none() {
+            diff.push((None, Some(entry.clone())));
+        }
+    }
+    diff
+}
+
+/// Size statistics of the coordinator state, for capacity planning.
+#[derive(CandidType, Clone, Debug, PartialEq, Eq)]
+pub struct StateStats {
+    pub live_transactions: u64,
+    pub active_transactions: u64,
+    pub archived_transactions: u64,
+    /// Rough estimate of the heap bytes held by transaction state,
+    /// dominated by the call payloads.
+    pub estimated_bytes: u64,
+}
+
+fn _state_stats(list: &TransactionList, archived_transactions: u64) -> StateStats {
+    let mut active_transactions = 0;
+    let mut estimated_bytes = 0;
+    for state in list.transactions.values() {
+        if !state.transaction_status.is_final() {
+            active_transactions += 1;
+        }
+        estimated_bytes += std::mem::size_of::<TransactionState>() as u64;
+        for call in state
+            .pending_prepare_calls
+            .iter()
+            .chain(state.pending_abort_calls.iter())
+            .chain(state.pending_commit_calls.iter())
+        {
+            estimated_bytes += (call.payload.len() + call.method.len()) as u64;
+        }
+    }
+    estimated_bytes +=
+        archived_transactions * std::mem::size_of::<ArchivedTransaction>() as u64;
+    StateStats {
+        live_transactions: list.transactions.len() as u64,
+        active_transactions,
+        archived_transactions,
+        estimated_bytes,
+    }
+}
+
+/// The current size and estimated memory usage of the coordinator state,
+/// computed in a single pass over the structures.
+#[query]
+pub fn state_stats() -> StateStats {
+    let archived_transactions = with_archive(|archive| archive.len() as u64);
+    with_transaction_list(|list| _state_stats(list, archived_transactions))
+}
+
+/// A finalized transaction's result, retained for history queries.
+#[derive(CandidType, Clone, Debug)]
+pub struct ArchivedTransaction {
+    pub result: TransactionResult,
+    /// Time the transaction reached its terminal state.
+    pub completed_at: u64,
+}
+
+/// Maximum number of archived transactions retained.
+pub const MAX_ARCHIVE_SIZE: usize = 1000;
+
+thread_local! {
+    static ARCHIVE: RefCell<VecDeque<ArchivedTransaction>> = const { RefCell::new(VecDeque::new()) };
+}
+
+pub fn with_archive<R>(f: impl FnOnce(&mut VecDeque<ArchivedTransaction>) -> R) -> R {
+    ARCHIVE.with(|archive| f(&mut archive.borrow_mut()))
+}
+
+thread_local! {
+    /// Per-initiator index of swaps that aborted because of an expired
+    /// deadline or prepare timeout. Lets clients that initiated a swap
+    /// and walked away discover later that their funds never moved.
+    static EXPIRED_SWAPS: RefCell<BTreeMap<Principal, Vec<TransactionId>>> =
+        const { RefCell::new(BTreeMap::new()) };
+}
+
+thread_local! {
+    /// Client-supplied idempotency keys mapped to the transaction their
+    /// first submission created. A resubmission under the same key gets
+    /// that transaction's result back instead of a second transaction
+    /// moving the same funds again.
+    static IDEMPOTENCY_KEYS: RefCell<BTreeMap<String, TransactionId>> =
+        const { RefCell::new(BTreeMap::new()) };
+}
+
+/// The transaction an earlier submission registered under the given
+/// idempotency key, if any.
+pub fn idempotent_transaction(key: &str) -> Option<TransactionId> {
+    IDEMPOTENCY_KEYS.with(|keys| keys.borrow().get(key).copied())
+}
+
+/// Remember which transaction the given idempotency key created.
+pub fn record_idempotency_key(key: String, tid: TransactionId) {
+    IDEMPOTENCY_KEYS.with(|keys| {
+        keys.borrow_mut().insert(key, tid);
+    });
+}
+
+/// Drop idempotency keys whose transaction is no longer in the table.
+/// Runs after every finalized-transaction GC pass, so keys live exactly
+/// as long as the transaction whose result they deduplicate to.
+pub fn expire_idempotency_keys(list: &TransactionList) {
+    IDEMPOTENCY_KEYS.with(|keys| {
+        keys.borrow_mut()
+            .retain(|_, tid| list.transactions.contains_key(tid));
+    });
+}
+
+/// Number of recently completed transactions whose durations feed the
+/// completion-time estimate.
+pub const DURATION_WINDOW_LEN: usize = 20;
+/// Minimum number of samples before an estimate is reported.
+pub const MIN_DURATION_SAMPLES: usize = 5;
+
+thread_local! {
+    /// Rolling window of the durations of recently completed
+    /// transactions, from registration to terminal state.
+    static RECENT_DURATIONS: RefCell<VecDeque<u64>> = const { RefCell::new(VecDeque::new()) };
+}
+
+/// Record the duration of a completed transaction in the rolling window.
+fn record_duration(duration_ns: u64) {
+    RECENT_DURATIONS.with(|durations| {
+        let mut durations = durations.borrow_mut();
+        if durations.len() == DURATION_WINDOW_LEN {
+            durations.pop_front();
+        }
+        durations.push_back(duration_ns);
+    });
+}
+
+fn _estimated_completion_ns(durations: &VecDeque<u64>) -> Option<u64> {
+    if durations.len() < MIN_DURATION_SAMPLES {
+        return None;
+    }
+    Some(durations.iter().sum::<u64>() / durations.len() as u64)
+}
+
+/// How long a newly submitted swap is expected to take, as the moving
+/// average over the durations of the most recently completed
+/// transactions. Gives clients a realistic polling interval. `None`
+/// until enough transactions completed to make the estimate meaningful.
+#[query]
+pub fn estimated_completion_ns() -> Option<u64> {
+    RECENT_DURATIONS.with(|durations| _estimated_completion_ns(&durations.borrow()))
+}
+
+/// If the transaction aborted because its deadline or prepare timeout
+/// expired, record it in the initiator's expired-swaps index.
+fn maybe_record_expired_swap(tid: TransactionId, state: &TransactionState) {
+    if state.transaction_status == TransactionStatus::Aborted
+        && state.abort_reason == Some(AbortReason::PrepareTimeout)
+    {
+        EXPIRED_SWAPS.with(|index| {
+            index
+                .borrow_mut()
+                .entry(state.initiator)
+                .or_default()
+                .push(tid)
+        });
+    }
+}
+
+/// The swaps initiated by the given principal that aborted because of an
+/// expired deadline or prepare timeout.
+#[query]
+pub fn expired_swaps(who: Principal) -> Vec<TransactionId> {
+    EXPIRED_SWAPS.with(|index| index.borrow().get(&who).cloned().unwrap_or_default())
+}
+
+/// Record the terminal result of a transaction in the archive ring
+/// buffer, dropping the oldest entry when full.
+pub fn archive_transaction(result: TransactionResult, now: u64) {
+    with_archive(|archive| {
+        archive.push_back(ArchivedTransaction {
+            result,
+            completed_at: now,
+        });
+        while archive.len() > MAX_ARCHIVE_SIZE {
+            archive.pop_front();
+        }
+    });
+}
+
+fn _purge_archive(archive: &mut VecDeque<ArchivedTransaction>, cutoff: u64) -> u64 {
+    let len_before = archive.len();
+    archive.retain(|entry| {
+        entry.completed_at >= cutoff || entry.result.state == TransactionStatus::NeedsReview
+    });
+    (len_before - archive.len()) as u64
+}
+
+/// Remove archived transactions whose completion time is older than the
+/// given age, returning the number of entries removed. Transactions in
+/// `NeedsReview` are never purged. Only callable by a controller.
+#[update]
+pub fn purge_archive(older_than_ns: u64) -> u64 {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        ic_cdk::trap("purge_archive can only be called by a controller");
+    }
+    let cutoff = ic_cdk::api::time().saturating_sub(older_than_ns);
+    with_archive(|archive| _purge_archive(archive, cutoff))
+}
+
+/// Before/after record of one participant's balance for a committed
+/// transaction, for auditing.
+#[derive(CandidType, Clone, Debug, PartialEq, Eq)]
+pub struct BalanceDelta {
+    pub target: Principal,
+    pub token: TokenName,
+    pub balance_before: Option<u64>,
+    pub balance_after: Option<u64>,
+}
+
+impl BalanceDelta {
+    /// The observed balance movement, if both snapshots succeeded.
+    pub fn delta(&self) -> Option<i64> {
+        Some(self.balance_after? as i64 - self.balance_before? as i64)
+    }
+}
+
+/// The (participant, token) pairs this transaction operates on, recovered
+/// from the call payloads.
+fn transaction_legs(state: &TransactionState) -> Vec<(Principal, TokenName)> {
+    state
+        .pending_commit_calls
+        .iter()
+        .filter_map(|call| {
+            let envelope = Envelope::decode(&call.payload).ok()?;
+            Decode!(&envelope.args, TokenName)
+                .ok()
+                .map(|token| (call.target, token))
+        })
+        .collect()
+}
+
+/// True if any unfinished transaction already targets the given
+/// (participant, token) pair. The active index is exactly the set of
+/// transactions holding reservations, so no separate bookkeeping is
+/// needed - a reservation is released the moment its transaction
+/// finalizes and leaves the index.
+pub fn resource_reserved(list: &TransactionList, canister: Principal, token: &str) -> bool {
+    list.active.iter().any(|tid| {
+        list.transactions.get(tid).is_some_and(|state| {
+            transaction_legs(state)
+                .iter()
+                .any(|(target, leg_token)| *target == canister && leg_token == token)
+        })
+    })
+}
+
+/// Best-effort balance snapshot of the given (participant, token) pairs.
+/// A failed query is recorded as `None`.
+async fn snapshot_balances(legs: &[(Principal, TokenName)]) -> Vec<Option<u64>> {
+    let mut balances = vec![];
+    for (target, token) in legs {
+        let balance = match ic_cdk::api::call::call::<_, (Option<u64>,)>(
+            *target,
+            "get_balance",
+            (token,),
+        )
+        .await
+        {
+            Ok((balance,)) => balance,
+            Err(_) => None,
+        };
+        balances.push(balance);
+    }
+    balances
+}
+
+fn _commit_delta(state: &TransactionState) -> Option<Vec<BalanceDelta>> {
+    let before = state.pre_commit_balances.as_ref()?;
+    let after = state.post_commit_balances.as_ref()?;
+    Some(
+        transaction_legs(state)
+            .into_iter()
+            .zip(before.iter().zip(after.iter()))
+            .map(|((target, token), (balance_before, balance_after))| BalanceDelta {
+                target,
+                token,
+                balance_before: *balance_before,
+                balance_after: *balance_after,
+            })
+            .collect(),
+    )
+}
+
+/// The before/after balance record of a committed transaction, or `None`
+/// if the transaction is unknown or its snapshots are not complete yet.
+#[query]
+pub fn commit_delta(tid: TransactionId) -> Option<Vec<BalanceDelta>> {
+    with_transaction_list(|list| list.transactions.get(&tid).and_then(_commit_delta))
+}
+
+/// The signed amount applied by each leg of a transaction, recovered
+/// from the call payloads. Batched legs are skipped, like in
+/// `transaction_legs`.
+fn leg_amounts(state: &TransactionState) -> Vec<i64> {
+    state
+        .pending_commit_calls
+        .iter()
+        .filter_map(|call| {
+            let envelope = Envelope::decode(&call.payload).ok()?;
+            Decode!(&envelope.args, String, i64)
+                .ok()
+                .map(|(_, amount)| amount)
+        })
+        .collect()
+}
+
+fn _effective_rate(state: &TransactionState) -> Option<(i64, i64)> {
+    if state.transaction_status != TransactionStatus::Committed {
+        return None;
+    }
+    match leg_amounts(state)[..] {
+        [amount1, amount2] => Some((amount1, amount2)),
+        _ => None,
+    }
+}
+
+/// The exchange implied by a committed two-leg swap: the signed amounts
+/// that were actually applied to the two tokens. For the fixed demo swap
+/// this is `(-1337, 42)`; once amounts are discovered at prepare time it
+/// reflects the discovered values. `None` for transactions that are
+/// unknown, not committed, or do not have exactly two plain legs.
+#[query]
+pub fn effective_rate(tid: TransactionId) -> Option<(i64, i64)> {
+    with_transaction_list(|list| list.transactions.get(&tid).and_then(_effective_rate))
+}
+
+/// Disable or re-enable the timer, e.g. to drive transactions manually in
+/// tests.
+#[update]
+pub fn disable_timer(disable: bool) {
+    DISABLE_TIMER.with(|disable_timer| *disable_timer.borrow_mut() = disable);
+}
+
+/// Read back whether the timer is disabled, so a test setup can confirm
+/// the mode took effect before driving transactions manually.
+#[query]
+pub fn get_timer_disabled() -> bool {
+    DISABLE_TIMER.with(|disable_timer| *disable_timer.borrow())
+}
+
+/// The delay until the timer should fire next, from the earliest point
+/// at which any active transaction becomes actionable again: a long
+/// idle interval when nothing is active, otherwise the remaining wait
+/// of the least rate-limited transaction, capped at the regular
+/// interval so a ready transaction is picked up promptly.
+fn next_timer_delay_ns(next_due_ns: Option<u64>, now: u64) -> u64 {
+    match next_due_ns {
+        None => IDLE_TIMER_INTERVAL_SECS * 1_000_000_000,
+        Some(due) => due
+            .saturating_sub(now)
+            .min(TIMER_INTERVAL_SECS * 1_000_000_000),
+    }
+}
+
+/// When the earliest active transaction is due for its next step, or
+/// `None` if no transaction needs the timer at all.
+fn _next_transaction_due_ns(list: &TransactionList, configuration: &Configuration) -> Option<u64> {
+    list.active
+        .iter()
+        .filter_map(|tid| list.transactions.get(tid))
+        .filter(|state| !state.manual_only)
+        .map(|state| state.last_action_time + required_wait_ns(state, configuration))
+        .min()
+}
+
+/// Start the timer driving all active transactions, adapting the delay
+/// to the current transaction state instead of polling blindly.
+pub fn start_timer() {
+    TIMER_ARMED.with(|armed| *armed.borrow_mut() = true);
+    let configuration = get_configuration();
+    let next_due = with_transaction_list(|list| _next_transaction_due_ns(list, &configuration));
+    let delay_ns = next_timer_delay_ns(next_due, ic_cdk::api::time());
+    ic_cdk_timers::set_timer(Duration::from_nanos(delay_ns), || {
+        ic_cdk::spawn(timer_loop())
+    });
+}
+
+/// Re-arm the timer if it stopped itself while idle. Called whenever a
+/// new transaction arrives, so an idle-stopped coordinator reliably
+/// resumes driving transactions.
+pub fn ensure_timer_running() {
+    if timer_needs_restart(
+        TIMER_ARMED.with(|armed| *armed.borrow()),
+        DISABLE_TIMER.with(|disable_timer| *disable_timer.borrow()),
+    ) {
+        IDLE_TICKS.with(|ticks| *ticks.borrow_mut() = 0);
+        start_timer();
+    }
+}
+
+/// True if the timer idle-stopped and has to be re-armed. A manually
+/// disabled timer stays off.
+fn timer_needs_restart(armed: bool, disabled: bool) -> bool {
+    !armed && !disabled
+}
+
+/// Record one timer tick that saw the given number of active
+/// transactions and decide whether the timer should re-arm. After
+/// `idle_stop_after_ticks` consecutive empty ticks the timer stops, so
+/// an idle coordinator consumes essentially no cycles; `0` never stops.
+fn should_rearm(num_active: usize, idle_stop_after_ticks: u64) -> bool {
+    IDLE_TICKS.with(|ticks| {
+        let mut ticks = ticks.borrow_mut();
+        if num_active > 0 {
+            *ticks = 0;
+            return true;
+        }
+        *ticks += 1;
+        idle_stop_after_ticks == 0 || *ticks < idle_stop_after_ticks
+    })
+}
+
+/// Drive all active transactions one step forward.
+async fn timer_loop() {
+    if DISABLE_TIMER.with(|disable_timer| *disable_timer.borrow()) {
+        TIMER_ARMED.with(|armed| *armed.borrow_mut() = false);
+        return;
+    }
+
+    let active_transactions = get_active_transactions();
+    if should_rearm(
+        active_transactions.len(),
+        get_configuration().idle_stop_after_ticks,
+    ) {
+        // Set the timer first, so that the loop keeps running even if
+        // processing a transaction traps.
+        start_timer();
+    } else {
+        ic_cdk::println!("Timer loop - idle, stopping until a new transaction arrives");
+        TIMER_ARMED.with(|armed| *armed.borrow_mut() = false);
+        return;
+    }
+
+    // Routine cleanup: drop finalized transactions that fell out of
+    // their retention window.
+    let purged = with_transaction_list(|list| {
+        let purged = _gc_finalized_transactions(
+            list,
+            ic_cdk::api::time().saturating_sub(FINALIZED_RETENTION_NS),
+        );
+        expire_idempotency_keys(list);
+        purged
+    });
+    if purged > 0 {
+        ic_cdk::println!(
+            "Timer loop - garbage-collected {} finalized transactions",
+            purged
+        );
+    }
+
+    if active_transactions.is_empty() {
+        ic_cdk::println!("Timer loop - no transactions");
+        return;
+    }
+    for tid in active_transactions {
+        // Step each transaction in its own task: a participant that
+        // never answers then hangs only its own transaction's await,
+        // instead of starving everything behind it in this loop. The
+        // rate limiting and the reentrancy guard inside
+        // `transaction_loop` are per transaction, so the spawned steps
+        // cannot trample each other.
+        ic_cdk::spawn(async move {
+            let _ = transaction_loop(tid).await;
+        });
+    }
+}
+
+/// The minimum time that must pass after the last action before the
+/// coordinator acts on this transaction again. The first retry of a
+/// prepare uses the configurable grace period; everything else follows
+/// the regular rate limit.
+fn required_wait_ns(state: &TransactionState, configuration: &Configuration) -> u64 {
+    let first_prepare_retry = state.transaction_status == TransactionStatus::Preparing
+        && state
+            .pending_prepare_calls
+            .iter()
+            .map(|call| call.num_tries)
+            .max()
+            == Some(1);
+    if first_prepare_retry {
+        configuration.first_retry_grace_ns
+    } else {
+        state.rate_limit_ns
+    }
+}
+
+/// Settle the aborts of participants that never reached a prepared
+/// state: they hold no lock, so their abort succeeds without a call.
+fn settle_unprepared_aborts(state: &mut TransactionState) {
+    let skippable: Vec<Principal> = state
+        .pending_abort_calls
+        .iter()
+        .filter(|call| call.num_success == 0 && !state.participant_maybe_prepared(call.target))
+        .map(|call| call.target)
+        .collect();
+    for target in skippable {
+        state.abort_received(true, target);
+    }
+}
+
+/// One structured log record, serialized as a single JSON line so log
+/// pipelines can parse events without scraping the colored output.
+#[derive(serde::Serialize)]
+pub struct LogEvent {
+    /// Display form of the transaction ID.
+    pub tid: String,
+    /// The 2PC phase the event belongs to, or "status" for lifecycle
+    /// transitions.
+    pub phase: &'static str,
+    /// The participant involved, if the event concerns a single one.
+    pub participant: Option<String>,
+    /// What happened.
+    pub outcome: String,
+    /// IC time of the event, in nanoseconds.
+    pub timestamp: u64,
+}
+
+/// The JSON line of a structured record.
+fn render_event(event: &LogEvent) -> String {
+    serde_json::to_string(event).unwrap_or_default()
+}
+
+/// Emit a structured record and, with `colored_logs` enabled, the given
+/// colored human-oriented line next to it.
+pub fn log_event(event: &LogEvent, human: impl FnOnce() -> String) {
+    ic_cdk::println!("{}", render_event(event));
+    if get_configuration().colored_logs {
+        ic_cdk::println!("{}", human());
+    }
+}
+
+/// True if a rejected call may succeed when retried: the system refused
+/// it transiently (e.g. output queue full) or the participant was
+/// stopping. A deliberate reject or a missing canister is permanent.
+fn is_transient_reject(code: RejectionCode, message: &str) -> bool {
+    code == RejectionCode::SysTransient
+        || (code == RejectionCode::CanisterError && message.contains("stopping"))
+}
+
+/// Decode a participant's raw prepare answer, surfacing the error to the
+/// caller. A misbehaving participant answering with the wrong type must
+/// cost it its vote, not trap the whole transaction loop.
+fn _decode_prepare_vote(payload: &[u8]) -> Result<PrepareVote, candid::Error> {
+    Decode!(payload, PrepareVote)
+}
+
+/// Execute one step of the given transaction: depending on the current
+/// status, issue the prepare, commit or abort calls to all participants
+/// that have not successfully answered yet and fold their answers back
+/// into the transaction state.
+///
+/// This is also exposed as an update so that a client can drive a
+/// transaction forward without waiting for the timer.
+#[update]
+pub async fn transaction_loop(tid: TransactionId) -> Result<TransactionResult, TransactionError> {
+    let now = ic_cdk::api::time();
+    let (status, prepare_timed_out, prepare_tries_exhausted, last_action_time, wait_ns) =
+        with_transaction(tid, |state| {
+            (
+                state.transaction_status.clone(),
+                state.prepare_timed_out(now),
+                state.prepare_tries_exhausted(),
+                state.last_action_time,
+                required_wait_ns(state, &get_configuration()),
+            )
+        })?;
+
+    // Rate limit: do not hammer the participants.
+    if now < last_action_time + wait_ns {
+        return get_transaction_state(tid);
+    }
+    with_transaction_mut(tid, |state| state.last_action_time = now)?;
+
+    // Reentrancy guard: a second invocation for the same transaction,
+    // e.g. a direct call racing the timer, would read the same pending
+    // calls across the awaits below and double-issue them. Let it return
+    // early with the current state instead.
+    if !with_transaction_mut(tid, TransactionState::begin_step)? {
+        return get_transaction_state(tid);
+    }
+    let balance_before = ic_cdk::api::canister_balance128();
+
+    match status {
+        TransactionStatus::Preparing => {
+            if prepare_timed_out || prepare_tries_exhausted {
+                log_event(
+                    &LogEvent {
+                        tid: tid.to_string(),
+                        phase: "prepare",
+                        participant: None,
+                        outcome: if prepare_timed_out {
+                            "timeout".to_string()
+                        } else {
+                            "retries_exhausted".to_string()
+                        },
+                        timestamp: now,
+                    },
+                    || {
+                        Colour::Red
+                            .paint(format!(
+                                "Transaction {}: prepare phase {} - aborting",
+                                tid,
+                                if prepare_timed_out {
+                                    "timed out"
+                                } else {
+                                    "ran out of retries"
+                                }
+                            ))
+                            .to_string()
+                    },
+                );
+                with_transaction_mut(tid, |state| {
+                    state.record_abort_reason(if prepare_timed_out {
+                        AbortReason::PrepareTimeout
+                    } else {
+                        AbortReason::CallFailure
+                    });
+                    state.transaction_status = TransactionStatus::Aborting
+                })?;
+            } else {
+                // Snapshot the calls: we must not hold a borrow of the
+                // transaction state across the await points below.
+                let calls: Vec<Call> = with_transaction(tid, |state| {
+                    state
+                        .pending_prepare_calls
+                        .iter()
+                        .filter(|call| call.num_success == 0 && call.ready(now))
+                        .cloned()
+                        .collect()
+                })?;
+                // Issue all prepares of this generation concurrently: an
+                // N-participant transaction costs one round trip, not N.
+                // Participants at the in-flight cap are deferred to the
+                // next tick rather than queued up further.
+                let issued: Vec<Call> = calls
+                    .into_iter()
+                    .filter(|call| {
+                        inflight_begin(
+                            call.target,
+                            get_configuration().max_inflight_per_participant,
+                        )
+                    })
+                    .collect();
+                with_transaction_mut(tid, |state| {
+                    for call in &issued {
+                        let pending = state
+                            .pending_prepare_calls
+                            .iter_mut()
+                            .find(|c| c.target == call.target)
+                            .unwrap();
+                        pending.num_tries += 1;
+                        pending.last_try_time = now;
+                    }
+                })?;
+                let answers = join_all(
+                    issued
+                        .iter()
+                        .map(|call| call_raw128(call.target, &call.method, call.payload.clone(), call.cycles)),
+                )
+                .await;
+                with_transaction_mut(tid, |state| {
+                    for (call, answer) in issued.iter().zip(answers) {
+                        inflight_end(call.target);
+                        match answer {
+                            Ok(payload) => {
+                                // A payload that does not decode as a vote
+                                // (e.g. a `nat` from a mismatched method)
+                                // costs this participant its vote, not the
+                                // whole loop a trap.
+                                let vote = match _decode_prepare_vote(&payload) {
+                                    Ok(vote) => vote,
+                                    Err(error) => {
+                                        log_event(
+                                            &LogEvent {
+                                                tid: tid.to_string(),
+                                                phase: "prepare",
+                                                participant: Some(call.target.to_text()),
+                                                outcome: format!(
+                                                    "undecodable vote: {} (raw {:02x?})",
+                                                    error, payload
+                                                ),
+                                                timestamp: now,
+                                            },
+                                            || {
+                                                format!(
+                                                    "Prepare answer from {} does not decode as a vote: {} (raw {:02x?})",
+                                                    call.target.to_text(),
+                                                    error,
+                                                    payload
+                                                )
+                                            },
+                                        );
+                                        state.record_abort_reason(AbortReason::Rejected);
+                                        state.prepare_received(false, call.target);
+                                        continue;
+                                    }
+                                };
+                                match vote {
+                                    PrepareVote::Yes => {}
+                                    PrepareVote::No(_) | PrepareVote::TokenFrozen => {
+                                        state.record_declined(call.target);
+                                        state.record_abort_reason(AbortReason::Rejected)
+                                    }
+                                    PrepareVote::Busy => {
+                                        state.record_declined(call.target);
+                                        state.record_abort_reason(AbortReason::LockConflict)
+                                    }
+                                }
+                                state.prepare_received(vote == PrepareVote::Yes, call.target);
+                            }
+                            Err((code, message)) => {
+                                log_event(
+                                    &LogEvent {
+                                        tid: tid.to_string(),
+                                        phase: "prepare",
+                                        participant: Some(call.target.to_text()),
+                                        outcome: format!("call failed: {:?} {}", code, message),
+                                        timestamp: now,
+                                    },
+                                    || {
+                                        format!(
+                                            "Prepare call to {} failed: {:?} {}",
+                                            call.target.to_text(),
+                                            code,
+                                            message
+                                        )
+                                    },
+                                );
+                                if is_transient_reject(code, &message) {
+                                    // The participant may well answer a
+                                    // retry; leave the call pending with
+                                    // backoff instead of treating the
+                                    // reject as a "no" vote.
+                                    continue;
+                                }
+                                state.record_abort_reason(AbortReason::CallFailure);
+                                state.prepare_received(false, call.target);
+                            }
+                        }
+                    }
+                })?;
+            }
+        }
+        TransactionStatus::Aborting => {
+            // Participants that never reached a prepared state hold no
+            // lock: settle their aborts locally instead of spending an
+            // inter-canister call on a no-op.
+            with_transaction_mut(tid, settle_unprepared_aborts)?;
+            let calls: Vec<Call> = with_transaction(tid, |state| {
+                state
+                    .pending_abort_calls
+                    .iter()
+                    .filter(|call| call.num_success == 0 && call.ready(now))
+                    .cloned()
+                    .collect()
+            })?;
+            let issued: Vec<Call> = calls
+                .into_iter()
+                .filter(|call| {
+                    inflight_begin(
+                        call.target,
+                        get_configuration().max_inflight_per_participant,
+                    )
+                })
+                .collect();
+            with_transaction_mut(tid, |state| {
+                for call in &issued {
+                    let pending = state
+                        .pending_abort_calls
+                        .iter_mut()
+                        .find(|c| c.target == call.target)
+                        .unwrap();
+                    pending.num_tries += 1;
+                    pending.last_try_time = now;
+                }
+            })?;
+            let answers = join_all(
+                issued
+                    .iter()
+                    .map(|call| call_raw128(call.target, &call.method, call.payload.clone(), call.cycles)),
+            )
+            .await;
+            with_transaction_mut(tid, |state| {
+                for (call, answer) in issued.iter().zip(answers) {
+                    inflight_end(call.target);
+                    match answer {
+                        Ok(_) => state.abort_received(true, call.target),
+                        Err(err) => {
+                            log_event(
+                                &LogEvent {
+                                    tid: tid.to_string(),
+                                    phase: "abort",
+                                    participant: Some(call.target.to_text()),
+                                    outcome: format!("call failed: {:?}", err),
+                                    timestamp: now,
+                                },
+                                || {
+                                    format!(
+                                        "Abort call to {} failed: {:?}",
+                                        call.target.to_text(),
+                                        err
+                                    )
+                                },
+                            );
+                            state.abort_received(false, call.target);
+                        }
+                    }
+                }
+            })?;
+        }
+        TransactionStatus::Committing => {
+            // Take a best-effort balance snapshot before issuing the first
+            // commit call, so auditors get a before/after record of the
+            // transaction. Snapshot failures never block the commit.
+            let needs_snapshot =
+                with_transaction(tid, |state| state.pre_commit_balances.is_none())?;
+            if needs_snapshot {
+                let legs = with_transaction(tid, transaction_legs)?;
+                let balances = snapshot_balances(&legs).await;
+                with_transaction_mut(tid, |state| state.pre_commit_balances = Some(balances))?;
+            }
+            let calls: Vec<Call> = with_transaction(tid, |state| {
+                state
+                    .pending_commit_calls
+                    .iter()
+                    .filter(|call| call.num_success == 0 && call.ready(now))
+                    .cloned()
+                    .collect()
+            })?;
+            let issued: Vec<Call> = calls
+                .into_iter()
+                .filter(|call| {
+                    inflight_begin(
+                        call.target,
+                        get_configuration().max_inflight_per_participant,
+                    )
+                })
+                .collect();
+            with_transaction_mut(tid, |state| {
+                for call in &issued {
+                    let pending = state
+                        .pending_commit_calls
+                        .iter_mut()
+                        .find(|c| c.target == call.target)
+                        .unwrap();
+                    pending.num_tries += 1;
+                    pending.last_try_time = now;
+                }
+            })?;
+            let answers = join_all(
+                issued
+                    .iter()
+                    .map(|call| call_raw128(call.target, &call.method, call.payload.clone(), call.cycles)),
+            )
+            .await;
+            with_transaction_mut(tid, |state| {
+                // Fold the successes and failures first, then the
+                // refusals: a refusal may flip the status, after which
+                // `commit_received` must not be called any more.
+                let mut refused = vec![];
+                for (call, answer) in issued.iter().zip(answers) {
+                    inflight_end(call.target);
+                    match answer {
+                        Ok(payload) => {
+                            // Participants answer `false` if the commit
+                            // can never apply, e.g. an optimistic-mode
+                            // conflict.
+                            let applied = Decode!(&payload, bool).unwrap_or(true);
+                            if applied {
+                                state.commit_received(true, call.target);
+                            } else {
+                                refused.push(call.target);
+                            }
+                        }
+                        Err(err) => {
+                            // Commits must not be given up on: retry
+                            // forever.
+                            log_event(
+                                &LogEvent {
+                                    tid: tid.to_string(),
+                                    phase: "commit",
+                                    participant: Some(call.target.to_text()),
+                                    outcome: format!("call failed: {:?}", err),
+                                    timestamp: now,
+                                },
+                                || {
+                                    format!(
+                                        "Commit call to {} failed: {:?}",
+                                        call.target.to_text(),
+                                        err
+                                    )
+                                },
+                            );
+                            state.commit_received(false, call.target);
+                        }
+                    }
+                }
+                if let Some(target) = refused.first() {
+                    log_event(
+                        &LogEvent {
+                            tid: tid.to_string(),
+                            phase: "commit",
+                            participant: Some(target.to_text()),
+                            outcome: "refused".to_string(),
+                            timestamp: now,
+                        },
+                        || format!("Commit refused by {} - aborting", target.to_text()),
+                    );
+                    for target in &refused {
+                        state.commit_received(false, *target);
+                    }
+                    state.record_abort_reason(AbortReason::CommitConflict);
+                    // The abort path is only safe while nothing has been
+                    // committed anywhere; otherwise an operator has to
+                    // review the half-applied transaction.
+                    if state
+                        .pending_commit_calls
+                        .iter()
+                        .all(|call| call.num_success == 0)
+                    {
+                        state.transaction_status = TransactionStatus::Aborting;
+                    } else {
+                        state.transaction_status = TransactionStatus::NeedsReview;
+                    }
+                }
+            })?;
+            // Once the last commit went through, capture the matching
+            // post-commit snapshot.
+            let needs_snapshot = with_transaction(tid, |state| {
+                state.transaction_status == TransactionStatus::Committed
+                    && state.post_commit_balances.is_none()
+            })?;
+            if needs_snapshot {
+                let legs = with_transaction(tid, transaction_legs)?;
+                let balances = snapshot_balances(&legs).await;
+                with_transaction_mut(tid, |state| state.post_commit_balances = Some(balances))?;
+            }
+        }
+        TransactionStatus::Aborted
+        | TransactionStatus::Committed
+        | TransactionStatus::NeedsReview => {}
+    }
+    let balance_after = ic_cdk::api::canister_balance128();
+    with_transaction_mut(tid, |state| {
+        state.record_cycles_spent(balance_before, balance_after);
+        state.end_step()
+    })?;
+
+    let new_status = with_transaction(tid, |state| state.transaction_status.clone())?;
+    if new_status != status {
+        log_event(
+            &LogEvent {
+                tid: tid.to_string(),
+                phase: "status",
+                participant: None,
+                outcome: format!("{:?} -> {:?}", status, new_status),
+                timestamp: now,
+            },
+            || {
+                Colour::Yellow
+                    .paint(format!(
+                        "Transaction {}: state changed from {:?} to {:?}",
+                        tid, status, new_status
+                    ))
+                    .to_string()
+            },
+        );
+        with_transaction_mut(tid, |state| {
+            state.record_transition(now, status.clone(), new_status.clone())
+        })?;
+        if new_status.is_final() {
+            with_transaction_list(|list| list.active.remove(&tid));
+            archive_transaction(get_transaction_state(tid)?, now);
+            with_transaction(tid, |state| {
+                maybe_record_expired_swap(tid, state);
+                record_duration(now.saturating_sub(state.transaction_start_time));
+            })?;
+        }
+        if _should_notify(&new_status) {
+            notify_observer(&with_transaction(tid, |state| {
+                _get_transaction_result(tid, state)
+            })?);
+        }
+        if new_status == TransactionStatus::Aborted
+            && with_transaction(tid, should_retry)?
+        {
+            // Spawn a fresh attempt with the same legs. Its freshly set
+            // last_action_time makes the rate limiter delay the first
+            // prepare, which serves as the backoff between attempts.
+            let new_tid = get_next_transaction_number();
+            let mut retry = with_transaction(tid, |state| retry_state(new_tid, tid, state))?;
+            retry.last_action_time = now;
+            log_event(
+                &LogEvent {
+                    tid: tid.to_string(),
+                    phase: "status",
+                    participant: None,
+                    outcome: format!("retrying as {}", new_tid),
+                    timestamp: now,
+                },
+                || {
+                    Colour::Yellow
+                        .paint(format!(
+                            "Transaction {}: aborted for a transient reason - retrying as transaction {}",
+                            tid, new_tid
+                        ))
+                        .to_string()
+                },
+            );
+            add_transaction(new_tid, retry, now);
+        }
+    }
+    get_transaction_state(tid)
+}
+
+/// Step the given transaction immediately, bypassing its rate limit:
+/// the last-action clock is reset before running one regular step, so
+/// the step is guaranteed to perform work instead of returning early.
+/// For a client that knows a participant just recovered and does not
+/// want to wait out `rate_limit_ns`. Only a controller or the
+/// transaction's initiator may force a step, since each one fans calls
+/// out to all pending participants.
+#[update]
+pub async fn force_retry(tid: TransactionId) -> Result<TransactionResult, TransactionError> {
+    let caller = ic_cdk::caller();
+    let initiator = with_transaction(tid, |state| state.initiator)?;
+    if !ic_cdk::api::is_controller(&caller) && caller != initiator {
+        ic_cdk::trap("force_retry can only be called by a controller or the initiator");
+    }
+    with_transaction_mut(tid, |state| state.last_action_time = 0)?;
+    transaction_loop(tid).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::Encode;
+
+    fn tid(nonce: u64) -> TransactionId {
+        TransactionId::new(Principal::anonymous(), nonce)
+    }
+
+    fn swap_transaction() -> TransactionState {
+        let ledger1 = Principal::from_slice(&[1]);
+        let ledger2 = Principal::from_slice(&[2]);
+        TransactionState::uniform(
+            tid(0),
+            0,
+            &[ledger1, ledger2],
+            "prepare_transaction",
+            "abort_transaction",
+            "commit_transaction",
+            &[
+                Encode!(&"ICP".to_string(), &-1337_i64).unwrap(),
+                Encode!(&"EUR".to_string(), &42_i64).unwrap(),
+            ],
+            0,
+        )
+    }
+
+    #[test]
+    fn test_transient_reject_leaves_prepare_pending() {
+        assert!(is_transient_reject(RejectionCode::SysTransient, "canister busy"));
+        assert!(is_transient_reject(
+            RejectionCode::CanisterError,
+            "canister is stopping"
+        ));
+        assert!(!is_transient_reject(RejectionCode::CanisterReject, "no"));
+        assert!(!is_transient_reject(RejectionCode::DestinationInvalid, ""));
+
+        // A transient reject records no answer at all: one "yes" and one
+        // withheld answer leave the transaction `Preparing` with the
+        // second call still pending, to be retried with backoff - only a
+        // decoded "no" vote aborts.
+        let mut state = swap_transaction();
+        for call in &mut state.pending_prepare_calls {
+            call.num_tries = 1;
+        }
+        state.prepare_received(true, Principal::from_slice(&[1]));
+        assert_eq!(state.transaction_status, TransactionStatus::Preparing);
+        let pending = &state.pending_prepare_calls[1];
+        assert_eq!(pending.num_success, 0);
+        assert_eq!(pending.num_fail, 0);
+    }
+
+    #[test]
+    fn test_aborts_skip_participants_that_never_prepared() {
+        let ledgers: Vec<Principal> = (1..=3u8).map(|i| Principal::from_slice(&[i])).collect();
+        let mut state = TransactionState::uniform(
+            tid(0),
+            0,
+            &ledgers,
+            "prepare_transaction",
+            "abort_transaction",
+            "commit_transaction",
+            &[
+                Encode!(&"ICP".to_string(), &-1337_i64).unwrap(),
+                Encode!(&"EUR".to_string(), &1000_i64).unwrap(),
+                Encode!(&"USD".to_string(), &337_i64).unwrap(),
+            ],
+            0,
+        );
+        for call in &mut state.pending_prepare_calls {
+            call.num_tries = 1;
+        }
+        // Two participants vote yes, the third refuses its prepare.
+        state.prepare_received(true, ledgers[0]);
+        state.prepare_received(true, ledgers[1]);
+        state.record_declined(ledgers[2]);
+        state.prepare_received(false, ledgers[2]);
+        assert_eq!(state.transaction_status, TransactionStatus::Aborting);
+
+        // The refusing participant holds no lock: its abort settles
+        // locally, and only the yes-voters remain to be called.
+        settle_unprepared_aborts(&mut state);
+        let to_call: Vec<Principal> = state
+            .pending_abort_calls
+            .iter()
+            .filter(|call| call.num_success == 0)
+            .map(|call| call.target)
+            .collect();
+        assert_eq!(to_call, vec![ledgers[0], ledgers[1]]);
+
+        // Once the two real abort calls answer, the transaction is done.
+        state.abort_received(true, ledgers[0]);
+        state.abort_received(true, ledgers[1]);
+        assert_eq!(state.transaction_status, TransactionStatus::Aborted);
+    }
+
+    #[test]
+    fn test_cycles_spent_is_tracked_per_transaction() {
+        let ledger1 = Principal::from_slice(&[1]);
+        let ledger2 = Principal::from_slice(&[2]);
+        let mut state = swap_transaction();
+        state.prepare_received(true, ledger1);
+        state.prepare_received(true, ledger2);
+        state.commit_received(true, ledger1);
+        state.commit_received(true, ledger2);
+        assert_eq!(state.transaction_status, TransactionStatus::Committed);
+
+        // Two loop iterations, each burning part of the balance.
+        state.record_cycles_spent(1_000_000, 900_000);
+        state.record_cycles_spent(900_000, 850_000);
+        assert_eq!(state.cycles_spent, 150_000);
+        let result = _get_transaction_result(tid(0), &state);
+        assert!(result.cycles_spent > 0);
+
+        // A balance that grew mid-step (e.g. a concurrent deposit) must
+        // not subtract from the tally.
+        state.record_cycles_spent(850_000, 2_000_000);
+        assert_eq!(state.cycles_spent, 150_000);
+
+        with_transaction_list(|list| list.transactions.insert(tid(0), state));
+        assert_eq!(total_cycles_spent(), 150_000);
+    }
+
+    #[test]
+    fn test_observer_notified_exactly_once_per_completed_swap() {
+        // Mock observer: record every status a notification would be
+        // pushed for while a swap walks through its lifecycle.
+        let trace = [
+            TransactionStatus::Preparing,
+            TransactionStatus::Committing,
+            TransactionStatus::Committed,
+        ];
+        let observed: Vec<&TransactionStatus> =
+            trace.iter().filter(|status| _should_notify(status)).collect();
+        assert_eq!(observed, vec![&TransactionStatus::Committed]);
+
+        // An aborted swap is pushed too; in-flight and review states
+        // are not.
+        assert!(_should_notify(&TransactionStatus::Aborted));
+        assert!(!_should_notify(&TransactionStatus::Aborting));
+        assert!(!_should_notify(&TransactionStatus::NeedsReview));
+    }
+
+    #[test]
+    fn test_log_events_render_as_parseable_json() {
+        let event = LogEvent {
+            tid: tid(7).to_string(),
+            phase: "prepare",
+            participant: Some(Principal::from_slice(&[1]).to_text()),
+            outcome: "timeout".to_string(),
+            timestamp: 1_234,
+        };
+        let parsed: serde_json::Value = serde_json::from_str(&render_event(&event)).unwrap();
+        assert_eq!(parsed["tid"], tid(7).to_string());
+        assert_eq!(parsed["phase"], "prepare");
+        assert_eq!(parsed["participant"], Principal::from_slice(&[1]).to_text());
+        assert_eq!(parsed["outcome"], "timeout");
+        assert_eq!(parsed["timestamp"], 1_234);
+    }
+
+    #[test]
+    fn test_force_finalize_unsticks_a_committing_transaction() {
+        let ledger1 = Principal::from_slice(&[1]);
+        let ledger2 = Principal::from_slice(&[2]);
+        let mut state = swap_transaction();
+        state.prepare_received(true, ledger1);
+        state.prepare_received(true, ledger2);
+        assert_eq!(state.transaction_status, TransactionStatus::Committing);
+        add_transaction(tid(0), state, 0);
+        assert_eq!(get_active_transactions(), vec![tid(0)]);
+
+        with_transaction_list(|list| {
+            _force_finalize(list, tid(0), TransactionStatus::Committed, 5).unwrap()
+        });
+        assert!(get_active_transactions().is_empty());
+        with_transaction(tid(0), |state| {
+            assert_eq!(state.transaction_status, TransactionStatus::Committed);
+        })
+        .unwrap();
+
+        // Unknown transactions are a clean error, not a trap.
+        with_transaction_list(|list| {
+            assert_eq!(
+                _force_finalize(list, tid(9), TransactionStatus::Aborted, 5),
+                Err(TransactionError::UnknownTransaction)
+            );
+        });
+    }
+
+    #[test]
+    fn test_stuck_transaction_does_not_block_others() {
+        // The first transaction's prepare hangs forever: its step stays
+        // marked in-progress across the hung await.
+        let mut stuck = swap_transaction();
+        assert!(stuck.begin_step());
+
+        // A healthy transaction steps in its own task, so the stuck
+        // await does not stop it from running to completion.
+        let ledger1 = Principal::from_slice(&[1]);
+        let ledger2 = Principal::from_slice(&[2]);
+        let mut normal = swap_transaction();
+        assert!(normal.begin_step());
+        normal.prepare_received(true, ledger1);
+        normal.prepare_received(true, ledger2);
+        normal.end_step();
+        normal.commit_received(true, ledger1);
+        normal.commit_received(true, ledger2);
+        assert_eq!(normal.transaction_status, TransactionStatus::Committed);
+
+        // Only the stuck transaction's own guard is still held.
+        assert!(!stuck.begin_step());
+    }
+
+    #[test]
+    fn test_active_transaction_cap_rejects_new_swaps() {
+        let configuration = Configuration {
+            max_active_transactions: 1,
+            ..Configuration::default()
+        };
+        assert_eq!(check_active_cap(&configuration), Ok(()));
+
+        // A single unfinished swap fills the cap.
+        add_transaction(tid(0), swap_transaction(), 0);
+        assert_eq!(
+            check_active_cap(&configuration),
+            Err(TransactionError::TooManyInFlight)
+        );
+
+        // Finalizing it frees the slot; the finished transaction stays
+        // in the table but no longer counts against the cap.
+        with_transaction_list(|list| {
+            _force_finalize(list, tid(0), TransactionStatus::Aborted, 5).unwrap()
+        });
+        assert_eq!(check_active_cap(&configuration), Ok(()));
+    }
+
+    #[test]
+    #[should_panic(expected = "one payload per participant")]
+    fn test_mismatched_payload_count_is_rejected() {
+        TransactionState::uniform(
+            tid(0),
+            0,
+            &[Principal::from_slice(&[1]), Principal::from_slice(&[2])],
+            "prepare_transaction",
+            "abort_transaction",
+            "commit_transaction",
+            // Only one payload for two participants.
+            &[Encode!(&"ICP".to_string(), &-1337_i64).unwrap()],
+            0,
+        );
+    }
+
+    #[test]
+    fn test_timer_disable_flag_reads_back() {
+        assert!(!get_timer_disabled());
+        disable_timer(true);
+        assert!(get_timer_disabled());
+        disable_timer(false);
+        assert!(!get_timer_disabled());
+    }
+
+    #[test]
+    fn test_peek_does_not_consume_transaction_numbers() {
+        let first_peek = peek_transaction_nonce();
+        assert_eq!(first_peek, peek_transaction_nonce());
+
+        // Only a real allocation, as done when a swap is created,
+        // advances the counter.
+        let allocated = next_transaction_nonce();
+        assert_eq!(allocated, first_peek);
+        assert_eq!(peek_transaction_nonce(), first_peek + 1);
+    }
+
+    #[test]
+    fn test_exported_transaction_resumes_on_another_coordinator() {
+        let ledger1 = Principal::from_slice(&[1]);
+        let ledger2 = Principal::from_slice(&[2]);
+        // A transaction stuck in `Committing`: both prepares succeeded,
+        // one commit is still outstanding when the export happens.
+        let mut state = swap_transaction();
+        state.prepare_received(true, ledger1);
+        state.prepare_received(true, ledger2);
+        state.commit_received(true, ledger1);
+        state.begin_step();
+
+        let blob = Encode!(&state).unwrap();
+        let mut imported = _import_state(&blob);
+
+        // The new coordinator sees the same progress, minus the step
+        // bookkeeping that died with the old one.
+        assert_eq!(imported.transaction_status, TransactionStatus::Committing);
+        assert_eq!(imported.pending_commit_calls[0].num_success, 1);
+        assert!(imported.begin_step());
+
+        // Resuming the commit phase completes the transaction.
+        imported.end_step();
+        imported.commit_received(true, ledger2);
+        assert_eq!(imported.transaction_status, TransactionStatus::Committed);
+    }
+
+    #[test]
+    fn test_partial_commit_progress_is_reported() {
+        let ledger1 = Principal::from_slice(&[1]);
+        let ledger2 = Principal::from_slice(&[2]);
+        let mut state = swap_transaction();
+        state.prepare_received(true, ledger1);
+        state.prepare_received(true, ledger2);
+
+        // One participant commits, the other is down. The transaction
+        // keeps retrying, but the result must show the split: ledger1
+        // has already applied the change and released its lock.
+        state.commit_received(true, ledger1);
+        state.commit_received(false, ledger2);
+        let result = _get_transaction_result(tid(0), &state);
+        assert_eq!(state.transaction_status, TransactionStatus::Committing);
+        assert_eq!(result.committed_count, result.total - 1);
+        assert_eq!(result.total, 2);
+
+        // Once the laggard answers, the counts agree again.
+        state.commit_received(true, ledger2);
+        let result = _get_transaction_result(tid(0), &state);
+        assert_eq!(result.committed_count, 2);
+    }
+
+    #[test]
+    fn test_duplicate_prepare_vote_is_not_double_counted() {
+        let ledger1 = Principal::from_slice(&[1]);
+        let mut state = swap_transaction();
+
+        // The same participant answers "yes" twice, e.g. a retry racing
+        // its own slow first answer. One vote must not count as two.
+        state.prepare_received(true, ledger1);
+        state.prepare_received(true, ledger1);
+        assert_eq!(state.pending_prepare_calls[0].num_success, 1);
+        assert_eq!(state.transaction_status, TransactionStatus::Preparing);
+
+        // Only the second participant's own vote completes the quorum.
+        state.prepare_received(true, Principal::from_slice(&[2]));
+        assert_eq!(state.transaction_status, TransactionStatus::Committing);
+        assert_eq!(state.late_prepare_yes, 0);
+    }
+
+    #[test]
+    fn test_prepare_retry_cap_forces_abort() {
+        // One participant permanently fails its prepare: every attempt
+        // counts a try but never a success.
+        let mut state = swap_transaction();
+        state.max_prepare_tries = 3;
+        state.abort_prepare_after_ns = ABORT_PREPARE_AFTER_NS;
+
+        state.prepare_received(true, Principal::from_slice(&[1]));
+        for attempt in 1..=3 {
+            assert!(!state.prepare_tries_exhausted());
+            state.pending_prepare_calls[1].num_tries = attempt;
+        }
+        // The cap trips well before the time-based deadline would.
+        assert!(state.prepare_tries_exhausted());
+        assert!(!state.prepare_timed_out(1_000_000_000));
+
+        // A capped-out participant that eventually answered does not
+        // count, and neither does anything with the cap disabled.
+        state.pending_prepare_calls[1].num_success = 1;
+        assert!(!state.prepare_tries_exhausted());
+        state.pending_prepare_calls[1].num_success = 0;
+        state.max_prepare_tries = 0;
+        assert!(!state.prepare_tries_exhausted());
+    }
+
+    #[test]
+    fn test_heterogeneous_participant_methods() {
+        let ledger = Principal::from_slice(&[1]);
+        let escrow = Principal::from_slice(&[2]);
+        let participants = vec![
+            ParticipantSpec {
+                canister: ledger,
+                prepare: "prepare_transaction".to_string(),
+                abort: "abort_transaction".to_string(),
+                commit: "commit_transaction".to_string(),
+                payload: Encode!(&"ICP".to_string(), &-1337_i64).unwrap(),
+            },
+            ParticipantSpec {
+                canister: escrow,
+                prepare: "prepare_escrow".to_string(),
+                abort: "abort_escrow".to_string(),
+                commit: "commit_escrow".to_string(),
+                payload: Encode!(&"EUR".to_string(), &42_i64).unwrap(),
+            },
+        ];
+        let state = TransactionState::new(tid(0), 0, &participants, 0);
+
+        // Each participant is called on its own endpoints with its own
+        // payload, in every phase.
+        for (calls, method_of) in [
+            (
+                &state.pending_prepare_calls,
+                (|spec: &ParticipantSpec| spec.prepare.clone()) as fn(&ParticipantSpec) -> String,
+            ),
+            (&state.pending_abort_calls, |spec| spec.abort.clone()),
+            (&state.pending_commit_calls, |spec| spec.commit.clone()),
+        ] {
+            for (call, spec) in calls.iter().zip(participants.iter()) {
+                assert_eq!(call.target, spec.canister);
+                assert_eq!(call.method, method_of(spec));
+                assert_eq!(Envelope::decode(&call.payload).unwrap().args, spec.payload);
+            }
+        }
+    }
+
+    #[test]
+    fn test_zero_rate_limit_allows_back_to_back_steps() {
+        let configuration = Configuration::default();
+        let mut state = swap_transaction();
+        state.last_action_time = 100;
+        // `transaction_loop` skips the step while `now` is less than
+        // `last_action_time + required_wait_ns`: with a zero window, a
+        // second step at the very same timestamp still performs work.
+        state.rate_limit_ns = 0;
+        let now = 100;
+        assert!(now >= state.last_action_time + required_wait_ns(&state, &configuration));
+        // The default window throttles such a step.
+        state.rate_limit_ns = RATE_LIMIT_TIMEOUT_NS;
+        assert!(now < state.last_action_time + required_wait_ns(&state, &configuration));
+    }
+
+    #[test]
+    fn test_force_retry_resets_the_rate_limit_clock() {
+        let configuration = Configuration::default();
+        let mut state = swap_transaction();
+        // A regular step just ran: the next one is throttled for the
+        // full rate-limit window. (Like IC time, `now` is large against
+        // the window, so a reset clock always reads as elapsed.)
+        let now = 100 * RATE_LIMIT_TIMEOUT_NS;
+        state.last_action_time = now;
+        assert!(now < state.last_action_time + required_wait_ns(&state, &configuration));
+        // `force_retry` resets the clock, so the very same timestamp
+        // passes the check and the step performs work...
+        state.last_action_time = 0;
+        assert!(now >= state.last_action_time + required_wait_ns(&state, &configuration));
+        // ...which stamps the clock again, throttling regular steps.
+        state.last_action_time = now;
+        assert!(now < state.last_action_time + required_wait_ns(&state, &configuration));
+        // A second force_retry right after the first works just the
+        // same: each reset buys exactly one immediate step.
+        state.last_action_time = 0;
+        assert!(now >= state.last_action_time + required_wait_ns(&state, &configuration));
+    }
+
+    #[test]
+    fn test_delayed_prepare_within_timeout_still_commits() {
+        let ledger1 = Principal::from_slice(&[1]);
+        let ledger2 = Principal::from_slice(&[2]);
+        let mut state = swap_transaction();
+        state.abort_prepare_after_ns = 10_000_000_000;
+        // Participants configured with a 3-second prepare delay answer
+        // late, but well within the abort timeout.
+        let answered_at = 3_000_000_000;
+        assert!(!state.prepare_timed_out(answered_at));
+        state.prepare_received(true, ledger1);
+        state.prepare_received(true, ledger2);
+        assert_eq!(state.transaction_status, TransactionStatus::Committing);
+        // Past the timeout, the same swap would have been aborted
+        // before the delayed votes arrived.
+        assert!(state.prepare_timed_out(11_000_000_000));
+    }
+
+    #[test]
+    fn test_first_prepare_retry_uses_grace_period() {
+        let configuration = Configuration {
+            first_retry_grace_ns: 1_000,
+            ..Configuration::default()
+        };
+        let mut state = swap_transaction();
+        // Before the first attempt, the regular rate limit applies.
+        assert_eq!(required_wait_ns(&state, &configuration), RATE_LIMIT_TIMEOUT_NS);
+        // After one round of prepares, the first retry uses the grace.
+        for call in &mut state.pending_prepare_calls {
+            call.num_tries = 1;
+        }
+        assert_eq!(required_wait_ns(&state, &configuration), 1_000);
+        // Later retries follow the regular rate limit again.
+        for call in &mut state.pending_prepare_calls {
+            call.num_tries = 2;
+        }
+        assert_eq!(required_wait_ns(&state, &configuration), RATE_LIMIT_TIMEOUT_NS);
+        // The grace only applies to the prepare phase.
+        for call in &mut state.pending_prepare_calls {
+            call.num_tries = 1;
+        }
+        state.transaction_status = TransactionStatus::Aborting;
+        assert_eq!(required_wait_ns(&state, &configuration), RATE_LIMIT_TIMEOUT_NS);
+    }
+
+    #[test]
+    fn test_rebuild_active_index_matches_full_scan() {
+        let mut list = TransactionList::default();
+        list.transactions.insert(tid(0), swap_transaction());
+        let mut finished = swap_transaction();
+        finished.transaction_status = TransactionStatus::Committed;
+        list.transactions.insert(tid(1), finished);
+        // Corrupt the index: the live transaction is missing, a finished
+        // and an unknown one are present.
+        list.active = BTreeSet::from([tid(1), tid(7)]);
+
+        _rebuild_active_index(&mut list);
+        assert_eq!(list.active, BTreeSet::from([tid(0)]));
+    }
+
+    #[test]
+    fn test_check_invariants_flags_inconsistent_transactions() {
+        let mut list = TransactionList::default();
+        // A healthy, freshly created transaction raises no violations.
+        list.transactions.insert(tid(0), swap_transaction());
+        assert!(_check_invariants(&list, 1_000).is_empty());
+
+        // Committing without the votes to back it up.
+        let mut state = swap_transaction();
+        state.transaction_status = TransactionStatus::Committing;
+        list.transactions.insert(tid(1), state);
+
+        // More answers than tries, as in a double-counted vote.
+        let mut state = swap_transaction();
+        state.pending_prepare_calls[0].num_success = 1;
+        list.transactions.insert(tid(2), state);
+
+        // An action timestamp from the future.
+        let mut state = swap_transaction();
+        state.last_action_time = 2_000;
+        list.transactions.insert(tid(3), state);
+
+        let violations = _check_invariants(&list, 1_000);
+        assert_eq!(
+            violations.iter().map(|v| v.transaction_number).collect::<Vec<_>>(),
+            vec![tid(1), tid(2), tid(3)]
+        );
+    }
+
+    #[test]
+    fn test_expired_swaps_indexed_per_initiator() {
+        let initiator = Principal::from_slice(&[7]);
+        let mut state = swap_transaction();
+        state.initiator = initiator;
+        state.record_abort_reason(AbortReason::PrepareTimeout);
+        state.transaction_status = TransactionStatus::Aborted;
+        maybe_record_expired_swap(tid(0), &state);
+
+        // Aborts for other reasons are not expiries.
+        let mut state = swap_transaction();
+        state.initiator = initiator;
+        state.record_abort_reason(AbortReason::Rejected);
+        state.transaction_status = TransactionStatus::Aborted;
+        maybe_record_expired_swap(tid(1), &state);
+
+        assert_eq!(expired_swaps(initiator), vec![tid(0)]);
+        assert!(expired_swaps(Principal::from_slice(&[8])).is_empty());
+    }
+
+    #[test]
+    fn test_snapshot_diff_shows_exactly_what_changed() {
+        add_transaction(tid(0), swap_transaction(), 100);
+        add_transaction(tid(1), swap_transaction(), 100);
+        let before = snapshot();
+        assert!(snapshot_diff(&before, &before).is_empty());
+
+        // One participant of transaction 1 votes "yes".
+        with_transaction_mut(tid(1), |state| {
+            state.prepare_received(true, Principal::from_slice(&[1]))
+        }).unwrap();
+        let after = snapshot();
+        let diff = snapshot_diff(&before, &after);
+        assert_eq!(diff.len(), 1);
+        let (old, new) = &diff[0];
+        assert_eq!(old.as_ref().unwrap().num_success, 0);
+        assert_eq!(new.as_ref().unwrap().num_success, 1);
+        assert_eq!(new.as_ref().unwrap().transaction_number, tid(1));
+
+        // A freshly created transaction shows up with no "before" side.
+        add_transaction(tid(2), swap_transaction(), 200);
+        let diff = snapshot_diff(&after, &snapshot());
+        assert_eq!(diff.len(), 1);
+        assert_eq!(diff[0].0, None);
+        assert_eq!(diff[0].1.as_ref().unwrap().transaction_number, tid(2));
+    }
+
+    #[test]
+    fn test_payload_cap_rejects_oversized_transactions() {
+        // The default cap is generous: regular swaps pass.
+        let configuration = Configuration::default();
+        assert_eq!(check_payload_cap(&swap_transaction(), &configuration), Ok(()));
+
+        let oversized = TransactionState::uniform(
+            tid(0),
+            0,
+            &[Principal::from_slice(&[1])],
+            "prepare_transaction",
+            "abort_transaction",
+            "commit_transaction",
+            &[vec![0; 1024]],
+            0,
+        );
+        let configuration = Configuration {
+            max_transaction_payload_bytes: 1024,
+            ..Configuration::default()
+        };
+        // The payload is held once per phase, so it counts three times.
+        assert_eq!(
+            check_payload_cap(&oversized, &configuration),
+            Err(TransactionError::PayloadTooLarge)
+        );
+    }
+
+    #[test]
+    fn test_participant_can_request_abort_while_preparing() {
+        let mut state = swap_transaction();
+        let participant = Principal::from_slice(&[1]);
+        assert!(_request_abort(&mut state, participant, 100));
+        assert_eq!(state.transaction_status, TransactionStatus::Aborting);
+        assert_eq!(state.abort_reason, Some(AbortReason::ParticipantRequest));
+        // The transition shows up in the trace.
+        assert_eq!(
+            state.state_trace,
+            vec![(100, TransactionStatus::Preparing, TransactionStatus::Aborting)]
+        );
+        // A second request is refused, the transaction is already aborting.
+        assert!(!_request_abort(&mut state, participant, 200));
+    }
+
+    #[test]
+    fn test_request_abort_refused_for_non_participants() {
+        let mut state = swap_transaction();
+        let outsider = Principal::from_slice(&[9]);
+        assert!(!_request_abort(&mut state, outsider, 100));
+        assert_eq!(state.transaction_status, TransactionStatus::Preparing);
+    }
+
+    #[test]
+    fn test_state_trace_records_bounded_transitions() {
+        add_transaction(tid(0), swap_transaction(), 100);
+        with_transaction_mut(tid(0), |state| {
+            state.record_transition(150, TransactionStatus::Preparing, TransactionStatus::Aborting);
+            state.record_transition(200, TransactionStatus::Aborting, TransactionStatus::Aborted);
+        }).unwrap();
+        assert_eq!(
+            state_trace(tid(0)),
+            vec![
+                (150, TransactionStatus::Preparing, TransactionStatus::Aborting),
+                (200, TransactionStatus::Aborting, TransactionStatus::Aborted),
+            ]
+        );
+        assert!(state_trace(tid(1)).is_empty());
+
+        // The trace length is bounded.
+        with_transaction_mut(tid(0), |state| {
+            for _ in 0..2 * MAX_STATE_TRACE_LEN {
+                state.record_transition(
+                    300,
+                    TransactionStatus::Preparing,
+                    TransactionStatus::Aborting,
+                );
+            }
+        }).unwrap();
+        assert_eq!(state_trace(tid(0)).len(), MAX_STATE_TRACE_LEN);
+    }
+
+    #[test]
+    fn test_history_of_a_full_commit() {
+        let mut state = swap_transaction();
+        state.transaction_start_time = 100;
+        let targets: Vec<Principal> = state
+            .pending_prepare_calls
+            .iter()
+            .map(|call| call.target)
+            .collect();
+        // Both participants vote "yes" at t=150...
+        let before = state.transaction_status.clone();
+        for target in &targets {
+            state.prepare_received(true, *target);
+        }
+        state.record_transition(150, before, state.transaction_status.clone());
+        // ...and both commits succeed at t=200.
+        let before = state.transaction_status.clone();
+        for target in &targets {
+            state.commit_received(true, *target);
+        }
+        state.record_transition(200, before, state.transaction_status.clone());
+
+        assert_eq!(
+            _transaction_history(&state),
+            vec![
+                (100, TransactionStatus::Preparing),
+                (150, TransactionStatus::Committing),
+                (200, TransactionStatus::Committed),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cancel_while_preparing_moves_to_aborting() {
+        let mut state = swap_transaction();
+        assert!(_cancel_transaction(&mut state, 100));
+        assert_eq!(state.transaction_status, TransactionStatus::Aborting);
+        // Cancellation is voluntary, so no retry is ever attempted.
+        assert_eq!(state.abort_reason, Some(AbortReason::Cancelled));
+        assert!(!AbortReason::Cancelled.retryable());
+        assert_eq!(
+            state.state_trace,
+            vec![(100, TransactionStatus::Preparing, TransactionStatus::Aborting)]
+        );
+    }
+
+    #[test]
+    fn test_abort_reason_surfaced_in_transaction_result() {
+        // A participant voting "no" is recorded as `Rejected`.
+        let mut state = swap_transaction();
+        state.record_declined(state.pending_prepare_calls[0].target);
+        state.record_abort_reason(AbortReason::Rejected);
+        state.prepare_received(false, state.pending_prepare_calls[0].target);
+        assert_eq!(state.transaction_status, TransactionStatus::Aborting);
+        assert_eq!(
+            _get_transaction_result(tid(0), &state).abort_reason,
+            Some(AbortReason::Rejected)
+        );
+
+        // A prepare that never comes back before the deadline is a
+        // `PrepareTimeout`.
+        let mut state = swap_transaction();
+        state.record_abort_reason(AbortReason::PrepareTimeout);
+        state.record_transition(100, TransactionStatus::Preparing, TransactionStatus::Aborting);
+        state.transaction_status = TransactionStatus::Aborting;
+        assert_eq!(
+            _get_transaction_result(tid(1), &state).abort_reason,
+            Some(AbortReason::PrepareTimeout)
+        );
+
+        // A voluntary cancel is `Cancelled`.
+        let mut state = swap_transaction();
+        assert!(_cancel_transaction(&mut state, 100));
+        assert_eq!(
+            _get_transaction_result(tid(2), &state).abort_reason,
+            Some(AbortReason::Cancelled)
+        );
+
+        // Transactions that never abort report no reason.
+        let state = swap_transaction();
+        assert_eq!(_get_transaction_result(tid(3), &state).abort_reason, None);
+    }
+
+    #[test]
+    fn test_undecodable_prepare_answer_is_a_failed_vote() {
+        // A participant answering with a `nat` instead of a vote - e.g.
+        // a wrong method name or an incompatible upgrade - must surface
+        // as a decode error, not a trap.
+        assert!(_decode_prepare_vote(&Encode!(&42u64).unwrap()).is_err());
+        assert!(_decode_prepare_vote(b"not candid").is_err());
+        assert_eq!(
+            _decode_prepare_vote(&Encode!(&PrepareVote::Busy).unwrap()).unwrap(),
+            PrepareVote::Busy
+        );
+
+        // The loop folds the failure in as a "no" vote, so the
+        // transaction aborts cleanly instead of hanging.
+        let mut state = swap_transaction();
+        let target = state.pending_prepare_calls[0].target;
+        state.record_abort_reason(AbortReason::Rejected);
+        state.prepare_received(false, target);
+        assert_eq!(state.transaction_status, TransactionStatus::Aborting);
+        assert_eq!(state.abort_reason, Some(AbortReason::Rejected));
+    }
+
+    #[test]
+    fn test_cancel_while_committing_is_refused() {
+        let mut state = swap_transaction();
+        let targets: Vec<Principal> = state
+            .pending_prepare_calls
+            .iter()
+            .map(|call| call.target)
+            .collect();
+        for target in &targets {
+            state.prepare_received(true, *target);
+        }
+        assert_eq!(state.transaction_status, TransactionStatus::Committing);
+        // Committing cannot be undone, the cancel is a no-op.
+        assert!(!_cancel_transaction(&mut state, 100));
+        assert_eq!(state.transaction_status, TransactionStatus::Committing);
+        assert!(state.state_trace.is_empty());
+    }
+
+    #[test]
+    fn test_timer_delay_adapts_to_active_transactions() {
+        let configuration = Configuration::default();
+        // Without active transactions the timer backs off to the long
+        // idle interval.
+        let list = TransactionList::default();
+        assert_eq!(_next_transaction_due_ns(&list, &configuration), None);
+        assert_eq!(
+            next_timer_delay_ns(None, 0),
+            IDLE_TIMER_INTERVAL_SECS * 1_000_000_000
+        );
+
+        // A started swap pulls the next fire forward: it is due when its
+        // rate limit elapses and the delay never exceeds the regular
+        // interval.
+        let mut list = TransactionList::default();
+        let mut state = swap_transaction();
+        state.last_action_time = 100;
+        list.transactions.insert(tid(0), state);
+        list.active.insert(tid(0));
+        let due = _next_transaction_due_ns(&list, &configuration).unwrap();
+        assert_eq!(due, 100 + RATE_LIMIT_TIMEOUT_NS);
+        assert!(next_timer_delay_ns(Some(due), 100) <= TIMER_INTERVAL_SECS * 1_000_000_000);
+        // A transaction that is already actionable fires immediately.
+        assert_eq!(next_timer_delay_ns(Some(due), due + 1), 0);
+    }
+
+    #[test]
+    fn test_retryable_abort_spawns_fresh_attempt() {
+        let mut state = swap_transaction();
+        state.retries_left = 1;
+        state.record_abort_reason(AbortReason::LockConflict);
+        state.transaction_status = TransactionStatus::Aborted;
+        assert!(should_retry(&state));
+
+        let retry = retry_state(tid(5), tid(0), &state);
+        assert_eq!(retry.transaction_status, TransactionStatus::Preparing);
+        assert_eq!(retry.root_tid, Some(tid(0)));
+        assert_eq!(retry.retries_left, 0);
+        // The envelopes carry the new transaction ID but the same legs.
+        let envelope = Envelope::decode(&retry.pending_prepare_calls[0].payload).unwrap();
+        assert_eq!(envelope.tid, tid(5));
+        let (token, amount) = Decode!(&envelope.args, String, i64).unwrap();
+        assert_eq!(token, "ICP");
+        assert_eq!(amount, -1337);
+        // An exhausted budget stops the chain.
+        assert!(!should_retry(&retry));
+    }
+
+    #[test]
+    fn test_non_retryable_abort_is_not_retried() {
+        let mut state = swap_transaction();
+        state.retries_left = 3;
+        state.record_abort_reason(AbortReason::Rejected);
+        state.transaction_status = TransactionStatus::Aborted;
+        assert!(!should_retry(&state));
+        // Only the first reason counts: a later transient failure does
+        // not make the abort retryable.
+        state.record_abort_reason(AbortReason::LockConflict);
+        assert!(!should_retry(&state));
+    }
+
+    #[test]
+    fn test_unknown_transaction_is_a_clean_error() {
+        assert_eq!(
+            get_transaction_state(tid(404)).unwrap_err(),
+            TransactionError::UnknownTransaction
+        );
+        // `transaction_loop` funnels every state access through these
+        // helpers, so a nonexistent tid surfaces the same error there
+        // instead of trapping.
+        assert_eq!(
+            with_transaction(tid(404), |state| state.transaction_status.clone()).unwrap_err(),
+            TransactionError::UnknownTransaction
+        );
+        assert!(with_transaction_mut(tid(404), TransactionState::begin_step).is_err());
+    }
+
+    #[test]
+    fn test_retry_chain_links_attempts() {
+        let mut root = swap_transaction();
+        root.retries_left = 2;
+        add_transaction(tid(0), root, 100);
+        let retry = retry_state(tid(1), tid(0), &with_transaction(tid(0), |state| state.clone()).unwrap());
+        add_transaction(tid(1), retry, 200);
+        let second_retry = retry_state(tid(2), tid(1), &with_transaction(tid(1), |state| state.clone()).unwrap());
+        // Retries of retries stay linked to the original root.
+        assert_eq!(second_retry.root_tid, Some(tid(0)));
+        add_transaction(tid(2), second_retry, 300);
+
+        assert_eq!(retry_chain(tid(0)), vec![tid(0), tid(1), tid(2)]);
+        assert_eq!(retry_chain(tid(2)), vec![tid(0), tid(1), tid(2)]);
+        assert_eq!(retry_chain(tid(7)), Vec::<TransactionId>::new());
+    }
+
+    #[test]
+    fn test_transaction_info_live_archived_and_unknown() {
+        add_transaction(tid(0), swap_transaction(), 100);
+        let info = _transaction_info(tid(0)).unwrap();
+        assert_eq!(info.status, TransactionStatus::Preparing);
+        assert_eq!(info.created_at, Some(100));
+        assert_eq!(info.completed_at, None);
+
+        archive_transaction(
+            TransactionResult {
+                transaction_number: tid(1),
+                state: TransactionStatus::Committed,
+                initiator: Principal::anonymous(),
+                call_reports: vec![],
+                cycles_spent: 0,
+                committed_count: 0,
+                total: 0,
+                abort_reason: None,
+            },
+            200,
+        );
+        let info = _transaction_info(tid(1)).unwrap();
+        assert_eq!(info.status, TransactionStatus::Committed);
+        assert_eq!(info.completed_at, Some(200));
+
+        assert!(_transaction_info(tid(2)).is_none());
+    }
+
+    #[test]
+    fn test_commit_delta_records_movement() {
+        let mut state = swap_transaction();
+        // No snapshots yet: no delta.
+        assert_eq!(_commit_delta(&state), None);
+
+        state.pre_commit_balances = Some(vec![Some(1_000_000), Some(1_000_000)]);
+        state.post_commit_balances = Some(vec![Some(998_663), Some(1_000_042)]);
+
+        let deltas = _commit_delta(&state).unwrap();
+        assert_eq!(deltas.len(), 2);
+        assert_eq!(deltas[0].token, "ICP");
+        assert_eq!(deltas[0].delta(), Some(-1337));
+        assert_eq!(deltas[1].token, "EUR");
+        assert_eq!(deltas[1].delta(), Some(42));
+    }
+
+    #[test]
+    fn test_commit_delta_with_failed_snapshot() {
+        let mut state = swap_transaction();
+        state.pre_commit_balances = Some(vec![Some(1_000_000), None]);
+        state.post_commit_balances = Some(vec![Some(998_663), Some(1_000_042)]);
+
+        let deltas = _commit_delta(&state).unwrap();
+        assert_eq!(deltas[0].delta(), Some(-1337));
+        // The failed snapshot makes the delta unknown.
+        assert_eq!(deltas[1].delta(), None);
+    }
+
+    fn archived(tid: TransactionId, state: TransactionStatus, completed_at: u64) -> ArchivedTransaction {
+        ArchivedTransaction {
+            result: TransactionResult {
+                transaction_number: tid,
+                state,
+                initiator: Principal::anonymous(),
+                call_reports: vec![],
+                cycles_spent: 0,
+                committed_count: 0,
+                total: 0,
+                abort_reason: None,
+            },
+            completed_at,
+        }
+    }
+
+    #[test]
+    fn test_purge_archive_only_removes_old_entries() {
+        let mut archive = VecDeque::from(vec![
+            archived(tid(0), TransactionStatus::Committed, 100),
+            archived(tid(1), TransactionStatus::Aborted, 200),
+            archived(tid(2), TransactionStatus::Committed, 300),
+        ]);
+        assert_eq!(_purge_archive(&mut archive, 250), 2);
+        assert_eq!(archive.len(), 1);
+        assert_eq!(archive[0].result.transaction_number, tid(2));
+    }
+
+    #[test]
+    fn test_purge_archive_keeps_needs_review() {
+        let mut archive = VecDeque::from(vec![
+            archived(tid(0), TransactionStatus::NeedsReview, 100),
+            archived(tid(1), TransactionStatus::Committed, 100),
+        ]);
+        assert_eq!(_purge_archive(&mut archive, 250), 1);
+        assert_eq!(archive.len(), 1);
+        assert_eq!(archive[0].result.state, TransactionStatus::NeedsReview);
+    }
+
+    #[test]
+    fn test_estimated_completion_needs_enough_samples() {
+        for duration in [100, 200, 300, 400] {
+            record_duration(duration);
+        }
+        assert_eq!(estimated_completion_ns(), None);
+        record_duration(500);
+        assert_eq!(estimated_completion_ns(), Some(300));
+        // The window is bounded: old samples age out.
+        for _ in 0..DURATION_WINDOW_LEN {
+            record_duration(1_000);
+        }
+        assert_eq!(estimated_completion_ns(), Some(1_000));
+    }
+
+    #[test]
+    fn test_late_yes_vote_cannot_leave_abort_path() {
+        let ledger1 = Principal::from_slice(&[1]);
+        let ledger2 = Principal::from_slice(&[2]);
+        let mut state = swap_transaction();
+        for call in &mut state.pending_prepare_calls {
+            call.num_tries = 2;
+        }
+        state.prepare_received(true, ledger1);
+        state.prepare_received(false, ledger2);
+        assert_eq!(state.transaction_status, TransactionStatus::Aborting);
+        // Ledger 2's retried prepare answers "yes" after the abort
+        // decision: recorded for diagnostics, but the vote count must
+        // not read as unanimous.
+        state.prepare_received(true, ledger2);
+        assert_eq!(state.transaction_status, TransactionStatus::Aborting);
+        assert_eq!(state.late_prepare_yes, 1);
+        let mut list = TransactionList::default();
+        list.transactions.insert(tid(0), state);
+        assert_eq!(_check_invariants(&list, 0), vec![]);
+        // A miscomputed counter is what the new invariant flags.
+        list.transactions
+            .get_mut(&tid(0))
+            .unwrap()
+            .pending_prepare_calls[1]
+            .num_success = 1;
+        assert_eq!(
+            _check_invariants(&list, 0)[0].description,
+            "aborting despite a unanimous yes vote count"
+        );
+    }
+
+    #[test]
+    fn test_concurrent_step_is_refused() {
+        let mut state = swap_transaction();
+        // The first invocation takes the guard; a concurrent second one
+        // must not issue any calls, so no vote is counted twice.
+        assert!(state.begin_step());
+        assert!(!state.begin_step());
+        // Once the first invocation's awaits completed, stepping is
+        // allowed again.
+        state.end_step();
+        assert!(state.begin_step());
+    }
+
+    #[test]
+    fn test_reconcile_aborts_on_lease_expired_lock() {
+        let ledger1 = Principal::from_slice(&[1]);
+        let ledger2 = Principal::from_slice(&[2]);
+        let mut state = swap_transaction();
+        // The coordinator recorded ledger 1's "yes" vote...
+        state.prepare_received(true, ledger1);
+        // ...but the participant's lease expired and released the lock.
+        let statuses = vec![
+            (ledger1, "ICP".to_string(), Some(ParticipantStatus::Aborted)),
+            (ledger2, "EUR".to_string(), None),
+        ];
+        let report = _reconcile(&mut state, tid(0), &statuses, 1_000);
+        assert!(!report.entries[0].agrees);
+        // Ledger 2 never voted, both sides agree there.
+        assert!(report.entries[1].agrees);
+        assert!(report.aborted);
+        assert_eq!(state.transaction_status, TransactionStatus::Aborting);
+        assert_eq!(state.abort_reason, Some(AbortReason::PrepareTimeout));
+    }
+
+    #[test]
+    fn test_set_status_drives_review_workflow() {
+        add_transaction(tid(0), swap_transaction(), 100);
+        // Force the hard-to-reach review state and confirm the timer
+        // stops driving the transaction.
+        set_status(tid(0), TransactionStatus::NeedsReview);
+        assert!(get_active_transactions().is_empty());
+        assert_eq!(
+            get_transaction_state(tid(0)).unwrap().state,
+            TransactionStatus::NeedsReview
+        );
+        // An operator putting it back into a live state re-activates it.
+        set_status(tid(0), TransactionStatus::Preparing);
+        assert_eq!(get_active_transactions(), vec![tid(0)]);
+    }
+
+    #[test]
+    fn test_prepare_fan_out_completes_in_one_generation() {
+        let ledgers: Vec<Principal> = (1..=4u8).map(|i| Principal::from_slice(&[i])).collect();
+        let args: Vec<Vec<u8>> = (0..4)
+            .map(|i| Encode!(&format!("TOK{}", i), &1_i64).unwrap())
+            .collect();
+        let mut state = TransactionState::uniform(
+            tid(0),
+            0,
+            &ledgers,
+            "prepare_transaction",
+            "abort_transaction",
+            "commit_transaction",
+            &args,
+            0,
+        );
+        // One generation snapshots every unanswered call and issues them
+        // all concurrently, so a 4-ledger swap still costs a single
+        // round trip for the prepare phase.
+        let generation: Vec<Principal> = state
+            .pending_prepare_calls
+            .iter()
+            .filter(|call| call.num_success == 0)
+            .map(|call| call.target)
+            .collect();
+        assert_eq!(generation.len(), 4);
+        for call in &mut state.pending_prepare_calls {
+            call.num_tries += 1;
+        }
+        for target in generation {
+            state.prepare_received(true, target);
+        }
+        // All votes arrived within that single generation: no call was
+        // tried twice and the transaction is ready to commit.
+        assert!(state
+            .pending_prepare_calls
+            .iter()
+            .all(|call| call.num_tries == 1));
+        assert_eq!(state.transaction_status, TransactionStatus::Committing);
+    }
+
+    #[test]
+    fn test_upgrade_round_trip_preserves_transactions() {
+        assert_eq!(next_transaction_nonce(), 0);
+        add_transaction(tid(0), swap_transaction(), 100);
+        with_transaction_mut(tid(0), |state| assert!(state.begin_step())).unwrap();
+        // Simulate an upgrade mid-prepare: the saved table survives a
+        // candid round trip through stable memory.
+        let saved = export_transaction_state();
+        assert!(get_active_transactions().is_empty());
+        let bytes = candid::Encode!(&saved).unwrap();
+        let restored = Decode!(&bytes, TransactionList).unwrap();
+        restore_transaction_state(restored);
+        // The transaction is live again, with its calls intact...
+        assert_eq!(get_active_transactions(), vec![tid(0)]);
+        assert_eq!(get_transaction_state(tid(0)).unwrap().state, TransactionStatus::Preparing);
+        with_transaction(tid(0), |state| {
+            assert_eq!(state.pending_prepare_calls.len(), 2);
+            assert_eq!(state.pending_prepare_calls[0].method, "prepare_transaction");
+        }).unwrap();
+        // ...and the step guard of the invocation that died with the
+        // upgrade does not block it forever.
+        assert!(with_transaction_mut(tid(0), TransactionState::begin_step).unwrap());
+        // Fresh transactions do not reuse recovered numbers.
+        assert_eq!(next_transaction_nonce(), 1);
+    }
+
+    #[test]
+    fn test_inflight_cap_defers_calls_to_slow_participant() {
+        let ledger = Principal::from_slice(&[1]);
+        // A slow participant accumulates outstanding calls; at the cap,
+        // further calls are deferred instead of issued.
+        assert!(inflight_begin(ledger, 2));
+        assert!(inflight_begin(ledger, 2));
+        assert!(!inflight_begin(ledger, 2));
+        assert_eq!(participant_stats(), vec![(ledger, 2)]);
+        // A slot frees up only once a reply arrives.
+        inflight_end(ledger);
+        assert!(inflight_begin(ledger, 2));
+        inflight_end(ledger);
+        inflight_end(ledger);
+        assert!(participant_stats().is_empty());
+    }
+
+    #[test]
+    fn test_manual_only_transaction_is_skipped_by_timer() {
+        add_transaction(tid(0), swap_transaction(), 100);
+        assert_eq!(get_active_transactions(), vec![tid(0)]);
+        with_transaction_mut(tid(0), |state| state.manual_only = true).unwrap();
+        // The timer's work list no longer contains the transaction, even
+        // though it is not final.
+        assert!(get_active_transactions().is_empty());
+        // A direct step still advances it: deliver the prepare votes as
+        // an explicit `transaction_loop` call would.
+        with_transaction_mut(tid(0), |state| {
+            for call in &mut state.pending_prepare_calls {
+                call.num_tries = 1;
+            }
+            state.prepare_received(true, Principal::from_slice(&[1]));
+            state.prepare_received(true, Principal::from_slice(&[2]));
+        }).unwrap();
+        assert_eq!(
+            get_transaction_state(tid(0)).unwrap().state,
+            TransactionStatus::Committing
+        );
+        // Handing it back re-activates it for the timer.
+        with_transaction_mut(tid(0), |state| state.manual_only = false).unwrap();
+        assert_eq!(get_active_transactions(), vec![tid(0)]);
+    }
+
+    #[test]
+    fn test_effective_rate_of_committed_swap() {
+        let mut state = swap_transaction();
+        // A transaction that has not committed yet has no rate.
+        assert_eq!(_effective_rate(&state), None);
+        state.transaction_status = TransactionStatus::Committed;
+        assert_eq!(_effective_rate(&state), Some((-1337, 42)));
+    }
+
+    #[test]
+    fn test_timer_stops_after_consecutive_idle_ticks() {
+        // Two idle ticks are tolerated, the third stops the timer.
+        assert!(should_rearm(0, 3));
+        assert!(should_rearm(0, 3));
+        assert!(!should_rearm(0, 3));
+        // An active transaction resets the idle counter.
+        assert!(should_rearm(1, 3));
+        assert!(should_rearm(0, 3));
+        // A threshold of zero never stops the timer.
+        IDLE_TICKS.with(|ticks| *ticks.borrow_mut() = 0);
+        for _ in 0..100 {
+            assert!(should_rearm(0, 0));
+        }
+    }
+
+    #[test]
+    fn test_new_transaction_restarts_idle_stopped_timer() {
+        // Only an idle-stopped timer is re-armed by `add_transaction`; a
+        // manually disabled timer stays off.
+        assert!(timer_needs_restart(false, false));
+        assert!(!timer_needs_restart(true, false));
+        assert!(!timer_needs_restart(false, true));
+    }
+
+    #[test]
+    fn test_prepare_deadline_prefers_client_value() {
+        let mut state = swap_transaction();
+        state.transaction_start_time = 1_000;
+        assert_eq!(state.prepare_deadline(), 1_000 + ABORT_PREPARE_AFTER_NS);
+        state.valid_until_ns = Some(5_000);
+        assert_eq!(state.prepare_deadline(), 5_000);
+    }
+
+    #[test]
+    fn test_failing_call_backs_off_exponentially() {
+        let ledger = Principal::from_slice(&[1]);
+        let mut call = Call::new(ledger, "prepare_transaction", vec![], 0);
+        // A fresh call goes out immediately.
+        assert!(call.ready(0));
+        // After the first failed try, the call waits the base delay.
+        call.num_tries = 1;
+        call.num_fail = 1;
+        call.last_try_time = 0;
+        assert!(!call.ready(CALL_BACKOFF_BASE_NS - 1));
+        assert!(call.ready(CALL_BACKOFF_BASE_NS));
+        // The second failure doubles the gap to the next attempt.
+        call.num_tries = 2;
+        call.num_fail = 2;
+        call.last_try_time = CALL_BACKOFF_BASE_NS;
+        assert!(!call.ready(3 * CALL_BACKOFF_BASE_NS - 1));
+        assert!(call.ready(3 * CALL_BACKOFF_BASE_NS));
+        // A persistently failing participant is still probed at the cap
+        // instead of the wait doubling forever.
+        call.num_tries = 40;
+        call.last_try_time = 0;
+        assert!(!call.ready(CALL_BACKOFF_CAP_NS - 1));
+        assert!(call.ready(CALL_BACKOFF_CAP_NS));
+    }
+
+    #[test]
+    fn test_call_reports_surface_failing_participant() {
+        let mut state = swap_transaction();
+        let failing = state.pending_prepare_calls[0].target;
+        // A transport-level failure leaves the transaction preparing but
+        // is counted against the participant's call.
+        state.pending_prepare_calls[0].num_tries += 1;
+        state.pending_prepare_calls[0].num_fail += 1;
+        let result = _get_transaction_result(tid(0), &state);
+        assert_eq!(result.state, TransactionStatus::Preparing);
+        let report = result
+            .call_reports
+            .iter()
+            .find(|report| report.target == failing)
+            .unwrap();
+        assert_eq!(report.num_tries, 1);
+        assert_eq!(report.num_fail, 1);
+        // The healthy participant reports clean counters.
+        assert!(result
+            .call_reports
+            .iter()
+            .any(|report| report.target != failing && report.num_fail == 0));
+    }
+
+    #[test]
+    fn test_transactions_filtered_by_initiator() {
+        let alice = Principal::from_slice(&[9]);
+        let mut authenticated = swap_transaction();
+        authenticated.initiator = alice;
+        add_transaction(tid(0), authenticated, 100);
+        // A swap created without an authenticated caller keeps the
+        // anonymous principal as its initiator.
+        add_transaction(tid(1), swap_transaction(), 100);
+        with_transaction_list(|list| {
+            let mine = _transactions_of(list, alice);
+            assert_eq!(mine.len(), 1);
+            assert_eq!(mine[0].transaction_number, tid(0));
+            assert_eq!(mine[0].initiator, alice);
+            let anonymous = _transactions_of(list, Principal::anonymous());
+            assert_eq!(anonymous.len(), 1);
+            assert_eq!(anonymous[0].transaction_number, tid(1));
+        });
+    }
+
+    #[test]
+    fn test_gc_purges_only_old_finalized_transactions() {
+        add_transaction(tid(0), swap_transaction(), 100);
+        with_transaction_mut(tid(0), |state| state.last_action_time = 100).unwrap();
+        // A live transaction is never collected, no matter how old.
+        assert_eq!(
+            with_transaction_list(|list| _gc_finalized_transactions(list, 1_000)),
+            0
+        );
+        set_status(tid(0), TransactionStatus::Committed);
+        // Within the retention window the outcome stays pollable...
+        assert_eq!(
+            with_transaction_list(|list| _gc_finalized_transactions(list, 50)),
+            0
+        );
+        assert_eq!(get_transaction_state(tid(0)).unwrap().state, TransactionStatus::Committed);
+        // ...once time moves past it, the entry disappears.
+        assert_eq!(
+            with_transaction_list(|list| _gc_finalized_transactions(list, 1_000)),
+            1
+        );
+        assert_eq!(count_transactions(), 0);
+    }
+
+    #[test]
+    fn test_list_transactions_pagination() {
+        for nonce in 0..3 {
+            add_transaction(tid(nonce), swap_transaction(), 100);
+        }
+        assert_eq!(count_transactions(), 3);
+        let page: Vec<TransactionId> = list_transactions(0, 2)
+            .iter()
+            .map(|result| result.transaction_number)
+            .collect();
+        assert_eq!(page, vec![tid(0), tid(1)]);
+        let page: Vec<TransactionId> = list_transactions(2, 2)
+            .iter()
+            .map(|result| result.transaction_number)
+            .collect();
+        assert_eq!(page, vec![tid(2)]);
+        // An offset past the end and a zero limit are both empty, not
+        // errors.
+        assert!(list_transactions(10, 2).is_empty());
+        assert!(list_transactions(0, 0).is_empty());
+    }
+
+    #[test]
+    fn test_per_transaction_prepare_timeout() {
+        let mut state = swap_transaction();
+        state.transaction_start_time = 1_000;
+        // A latency-sensitive swap opts into a one-second timeout.
+        state.abort_prepare_after_ns = 1_000_000_000;
+        assert_eq!(state.prepare_deadline(), 1_000 + 1_000_000_000);
+        // A participant that holds on to the prepare (`infinite_prepare`)
+        // never answers. Under the default timeout the swap would still
+        // be pending at the two-second mark; the per-transaction one has
+        // expired, which is when the timer moves the swap to `Aborting`.
+        let two_seconds_in = 1_000 + 2_000_000_000;
+        let mut default_timeout = swap_transaction();
+        default_timeout.transaction_start_time = 1_000;
+        assert!(!default_timeout.prepare_timed_out(two_seconds_in));
+        assert!(state.prepare_timed_out(two_seconds_in));
+    }
+
+    #[test]
+    fn test_state_stats_counts_transactions_and_bytes() {
+        let mut list = TransactionList::default();
+        let mut committed = swap_transaction();
+        committed.transaction_status = TransactionStatus::Committed;
+        list.transactions.insert(tid(0), committed);
+        list.transactions.insert(tid(1), swap_transaction());
+
+        let stats = _state_stats(&list, 3);
+        assert_eq!(stats.live_transactions, 2);
+        assert_eq!(stats.active_transactions, 1);
+        assert_eq!(stats.archived_transactions, 3);
+        // At least the payloads of 2 transactions x 3 phases x 2 calls.
+        assert!(stats.estimated_bytes > 0);
+    }
+}
@@ -0,0 +1,1527 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ed25519_dalek::{Signature, VerifyingKey};
+use ic_atomic_transactions::{BalanceDelta, Envelope, Phase, PrepareCallMode, PrepareVote, TokenName};
+use ic_cdk::api::management_canister::main::CanisterInstallMode;
+use ic_cdk::{post_upgrade, pre_upgrade, update};
+
+pub mod atomic_transactions;
+pub mod local_participant;
+pub mod metrics;
+pub mod utils;
+
+use atomic_transactions::{
+    add_transaction, check_active_cap, check_payload_cap, get_configuration,
+    get_next_transaction_number, get_transaction_state, idempotent_transaction,
+    record_idempotency_key, resource_reserved, with_transaction_list, TransactionError,
+    TransactionId, TransactionResult, TransactionState,
+};
+
+/// Create and initialize the participant ledgers and start the timer that
+/// drives all transactions. `specs` selects how many ledgers to create
+/// and which tokens each one holds; omitted, the demo's default
+/// ICP/USD/EUR layout is provisioned.
+#[update]
+async fn init(specs: Option<Vec<utils::LedgerSpec>>) {
+    if !utils::get_canister_ids().is_empty() {
+        let health = healthcheck_ledgers().await;
+        if health.iter().all(|(_, alive)| *alive) {
+            ic_cdk::println!("Ledgers already initialized");
+            return;
+        }
+        // A saved ledger no longer answers, e.g. it was deleted out of
+        // band. Reusing the stale list would turn every swap into call
+        // failures, so start over with a fresh set; any survivors are
+        // orphaned, which is acceptable for a demo.
+        for (canister, alive) in health {
+            if !alive {
+                ic_cdk::println!("Ledger {} failed its healthcheck - recreating ledgers", canister);
+            }
+        }
+        utils::set_canister_ids(vec![]);
+    }
+    if let Err(error) =
+        utils::create_ledgers_from_wasm(&specs.unwrap_or_else(utils::default_ledger_specs)).await
+    {
+        // The failed run already rolled its canisters back; no ledgers
+        // were registered, so a retried `init` starts from a clean slate.
+        ic_cdk::trap(&format!("Failed to create the ledgers: {}", error));
+    }
+    // The participant set just changed; a token listing cached before
+    // (e.g. from a failed earlier init) would miss the new ledgers.
+    invalidate_token_cache();
+    atomic_transactions::start_timer();
+}
+
+/// Save the whole transaction table and the ledger canister IDs to
+/// stable memory. Without this, an upgrade wipes all in-flight
+/// transactions and leaves the participant ledgers locked in `Prepared`
+/// state until their leases expire.
+#[pre_upgrade]
+fn pre_upgrade() {
+    ic_cdk::storage::stable_save((
+        atomic_transactions::export_transaction_state(),
+        utils::get_canister_ids(),
+    ))
+    .expect("Failed to save transaction state to stable memory");
+}
+
+/// Restore the transaction table saved by `pre_upgrade` and re-arm the
+/// timer so the recovered transactions keep progressing.
+#[post_upgrade]
+fn post_upgrade() {
+    let (transactions, canister_ids) = ic_cdk::storage::stable_restore()
+        .expect("Failed to restore transaction state from stable memory");
+    atomic_transactions::restore_transaction_state(transactions);
+    utils::set_canister_ids(canister_ids);
+    atomic_transactions::start_timer();
+}
+
+/// Start a demo swap: 1337 ICP on the first ledger against 42 EUR on the
+/// second ledger.
+#[update]
+async fn swap_token1_to_token2() -> Result<TransactionResult, TransactionError> {
+    swap_tokens(
+        "ICP".to_string(),
+        "EUR".to_string(),
+        BalanceDelta::debit(1337),
+        BalanceDelta::credit(42),
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+    )
+    .await
+}
+
+/// Start a token swap: atomically apply `amount1` to `token1` on the
+/// first ledger and `amount2` to `token2` on the second ledger.
+///
+/// The swap is executed asynchronously by the timer loop; poll
+/// `get_transaction_state` or call `transaction_loop` to track progress.
+///
+/// `valid_until_ns` is an optional client-supplied deadline: past it, the
+/// coordinator aborts the transaction and the participants release their
+/// locks, both against the same timestamp.
+///
+/// `auto_retry` is the number of times an abort for a transient reason
+/// (lock conflict, timeout) is automatically retried with a fresh
+/// transaction; aborts that can never succeed are not retried. The
+/// attempts are linked, see `retry_chain`.
+///
+/// `prepare_timeout_ns` overrides how long the swap may sit in the
+/// prepare phase before it is aborted; the default suits most ledgers,
+/// latency-sensitive swaps want a shorter one.
+///
+/// `cycles` is attached to every prepare/abort/commit call of the swap,
+/// funding participants that do paid work while handling them. Defaults
+/// to zero.
+///
+/// `idempotency_key` deduplicates retried submissions: a key already
+/// seen returns the result of the transaction it created instead of
+/// registering a second one, so a client that lost the answer to its
+/// first submission can safely resend. Keys expire together with their
+/// garbage-collected transaction.
+#[allow(clippy::too_many_arguments)]
+#[update]
+async fn swap_tokens(
+    token1: TokenName,
+    token2: TokenName,
+    amount1: BalanceDelta,
+    amount2: BalanceDelta,
+    valid_until_ns: Option<u64>,
+    auto_retry: Option<u8>,
+    prepare_timeout_ns: Option<u64>,
+    cycles: Option<u128>,
+    rate_limit_ns: Option<u64>,
+    idempotency_key: Option<String>,
+) -> Result<TransactionResult, TransactionError> {
+    check_swap_tokens(&token1, &token2).await?;
+    create_swap(
+        token1,
+        token2,
+        amount1,
+        amount2,
+        valid_until_ns,
+        auto_retry,
+        prepare_timeout_ns,
+        cycles,
+        rate_limit_ns,
+        idempotency_key,
+        ic_cdk::caller(),
+        get_next_transaction_number(),
+        ic_cdk::api::time(),
+    )
+}
+
+/// Dry-run a swap: issue the participants' prepares, collect the votes
+/// and immediately abort again to release any locks taken. Nothing is
+/// ever committed; the returned votes (in leg order) tell the client
+/// whether the real swap would currently succeed.
+#[update]
+async fn simulate_swap(
+    token1: TokenName,
+    token2: TokenName,
+    amount1: BalanceDelta,
+    amount2: BalanceDelta,
+) -> Vec<bool> {
+    let canisters = utils::get_canister_ids();
+    if _require_ledgers(&canisters, 2).is_err() {
+        return vec![];
+    }
+    // A real transaction ID, so the participants' abort log keeps a
+    // later replay of these prepares idempotent.
+    let tid = get_next_transaction_number();
+    let trace_id = ic_cdk::api::time();
+    let legs = [
+        (canisters[0], token1, amount1),
+        (canisters[1], token2, amount2),
+    ];
+
+    let mut votes = vec![];
+    for (canister, token, amount) in &legs {
+        let payload = Envelope::new(
+            tid,
+            Phase::Prepare,
+            trace_id,
+            Encode!(token, amount, &None::<u64>).unwrap(),
+        )
+        .encode();
+        let answer = ic_cdk::api::call::call_raw(*canister, "prepare_transaction", &payload, 0).await;
+        votes.push(match answer {
+            Ok(bytes) => _decode_vote(&bytes),
+            Err(_) => false,
+        });
+    }
+    // Release whatever the prepares locked; a refused leg's abort is a
+    // cheap no-op on the participant.
+    for (canister, token, amount) in &legs {
+        let payload = Envelope::new(
+            tid,
+            Phase::Abort,
+            trace_id,
+            Encode!(token, amount).unwrap(),
+        )
+        .encode();
+        let _ = ic_cdk::api::call::call_raw(*canister, "abort_transaction", &payload, 0).await;
+    }
+    votes
+}
+
+/// Redeploy the bundled ledger WASM into the existing ledger canisters
+/// in `Upgrade` mode, keeping their principals and - once the ledger
+/// persists its state to stable memory - their balances. Use `init` on a
+/// fresh DEX instead to create new ledgers.
+#[update]
+async fn upgrade_ledgers() {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        ic_cdk::trap("upgrade_ledgers can only be called by a controller");
+    }
+    utils::redeploy_ledgers(CanisterInstallMode::Upgrade(None)).await;
+}
+
+/// Ping every known ledger with a cheap query and report which of them
+/// still answer. A ledger deleted or uninstalled out of band would
+/// otherwise only surface as opaque call failures in the transaction
+/// loop.
+#[update]
+async fn healthcheck_ledgers() -> Vec<(Principal, bool)> {
+    let mut health = vec![];
+    for canister in utils::get_canister_ids() {
+        let answer = ic_cdk::api::call::call_raw(
+            canister,
+            "get_configuration",
+            &Encode!().unwrap(),
+            0,
+        )
+        .await;
+        health.push((canister, _ledger_alive(&answer)));
+    }
+    health
+}
+
+/// A ledger counts as alive if the healthcheck call produced an answer
+/// at all; any reject means it is stopped, uninstalled or deleted.
+fn _ledger_alive(answer: &Result<Vec<u8>, (ic_cdk::api::call::RejectionCode, String)>) -> bool {
+    answer.is_ok()
+}
+
+/// Fold a participant's raw prepare answer into a vote: only a decoded
+/// `Yes` counts, a refusal or an undecodable answer is a "no".
+fn _decode_vote(bytes: &[u8]) -> bool {
+    Decode!(bytes, PrepareVote)
+        .map(|vote| vote == PrepareVote::Yes)
+        .unwrap_or(false)
+}
+
+/// Pre-flight for swap submissions: confirm both tokens exist on their
+/// target ledgers, so a typo fails here instead of as a doomed
+/// transaction. Best effort: an unreachable ledger is left to the
+/// transaction's own retry machinery, only a definite "no such token"
+/// answer rejects.
+async fn check_swap_tokens(token1: &str, token2: &str) -> Result<(), TransactionError> {
+    let canisters = utils::get_canister_ids();
+    _require_ledgers(&canisters, 2)?;
+    for (canister, token) in [(canisters[0], token1), (canisters[1], token2)] {
+        let answer = ic_cdk::api::call::call::<_, (Option<u64>,)>(
+            canister,
+            "get_balance",
+            (token.to_string(),),
+        )
+        .await;
+        if let Ok((None,)) = answer {
+            return Err(TransactionError::UnknownToken);
+        }
+    }
+    Ok(())
+}
+
+/// One swap of a batch submission, mirroring `swap_tokens`' arguments.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct SwapRequest {
+    pub token1: TokenName,
+    pub token2: TokenName,
+    pub amount1: i64,
+    pub amount2: i64,
+    pub valid_until_ns: Option<u64>,
+    pub auto_retry: Option<u8>,
+}
+
+/// Submit many swaps in one ingress message, e.g. an arbitrage bundle.
+/// Each request gets its own transaction and result, in request order;
+/// one invalid request does not block the others. Like `swap_tokens`,
+/// this only registers the transactions - the timer loop drives them.
+#[update]
+async fn swap_batch(
+    swaps: Vec<SwapRequest>,
+) -> Vec<Result<TransactionResult, TransactionError>> {
+    let initiator = ic_cdk::caller();
+    let mut results = Vec::with_capacity(swaps.len());
+    for swap in swaps {
+        let result = match check_swap_tokens(&swap.token1, &swap.token2).await {
+            Ok(()) => _create_swap(
+                swap,
+                initiator,
+                get_next_transaction_number(),
+                ic_cdk::api::time(),
+            ),
+            Err(err) => Err(err),
+        };
+        results.push(result);
+    }
+    results
+}
+
+/// Register the transaction of a single batched swap.
+fn _create_swap(
+    swap: SwapRequest,
+    initiator: Principal,
+    tid: TransactionId,
+    now: u64,
+) -> Result<TransactionResult, TransactionError> {
+    create_swap(
+        swap.token1,
+        swap.token2,
+        BalanceDelta::from_signed(swap.amount1),
+        BalanceDelta::from_signed(swap.amount2),
+        swap.valid_until_ns,
+        swap.auto_retry,
+        None,
+        None,
+        None,
+        None,
+        initiator,
+        tid,
+        now,
+    )
+}
+
+/// Register a swap on behalf of the given initiator. Shared backend of
+/// `swap_tokens` (initiator is the caller) and `submit_signed_swap`
+/// (initiator is recovered from the verified signature).
+#[allow(clippy::too_many_arguments)]
+fn create_swap(
+    token1: TokenName,
+    token2: TokenName,
+    amount1: BalanceDelta,
+    amount2: BalanceDelta,
+    valid_until_ns: Option<u64>,
+    auto_retry: Option<u8>,
+    prepare_timeout_ns: Option<u64>,
+    cycles: Option<u128>,
+    rate_limit_ns: Option<u64>,
+    idempotency_key: Option<String>,
+    initiator: Principal,
+    tid: TransactionId,
+    now: u64,
+) -> Result<TransactionResult, TransactionError> {
+    if let Some(key) = &idempotency_key {
+        if let Some(existing) = idempotent_transaction(key) {
+            // A resubmission: hand back the transaction the first
+            // submission under this key created, whatever state it is
+            // in by now, instead of registering a duplicate.
+            return get_transaction_state(existing);
+        }
+    }
+    let canisters = utils::get_canister_ids();
+    _require_ledgers(&canisters, 2)?;
+    check_active_cap(&get_configuration())?;
+    if amount1.as_i64() == 0 && amount2.as_i64() == 0 {
+        // A swap moving nothing would still lock both tokens for a full
+        // 2PC round; refuse it outright.
+        return Err(TransactionError::NoOpSwap);
+    }
+
+    let legs = vec![
+        (canisters[0], token1, amount1.as_i64()),
+        (canisters[1], token2, amount2.as_i64()),
+    ];
+    let fee = local_participant::swap_fee();
+    // Coordinator-side reservation: a second swap targeting a reserved
+    // resource would lose the participant's lock race anyway, so refuse
+    // it before any work is done. The local fee account is a resource
+    // like any other and takes part in the reservation.
+    let mut reserved: Vec<(Principal, &TokenName)> = legs
+        .iter()
+        .map(|(canister, token, _)| (*canister, token))
+        .collect();
+    if let Some(fee) = &fee {
+        reserved.push((fee.coordinator, &fee.account));
+    }
+    with_transaction_list(|list| {
+        if reserved
+            .iter()
+            .any(|(canister, token)| resource_reserved(list, *canister, token))
+        {
+            Err(TransactionError::ResourceBusy)
+        } else {
+            Ok(())
+        }
+    })?;
+    // The trace ID correlates this transaction's log entries across the
+    // coordinator and the participants.
+    let trace_id = now;
+    let mut transaction_state = transaction_for_legs(
+        tid,
+        trace_id,
+        &legs,
+        valid_until_ns,
+        get_configuration().prepare_call_mode,
+        cycles.unwrap_or(0),
+    );
+    if let Some(fee) = &fee {
+        // The fee leg is one more participant of the same 2PC round: it
+        // prepares, commits and aborts together with the ledger legs,
+        // so the fee is charged exactly when the swap commits.
+        transaction_state.add_participant(
+            tid,
+            &local_participant::fee_participant(fee, valid_until_ns),
+            cycles.unwrap_or(0),
+        );
+    }
+    check_payload_cap(&transaction_state, &get_configuration())?;
+    transaction_state.valid_until_ns = valid_until_ns;
+    transaction_state.retries_left = auto_retry.unwrap_or(0);
+    if let Some(timeout) = prepare_timeout_ns {
+        transaction_state.abort_prepare_after_ns = timeout;
+    }
+    if let Some(rate_limit) = rate_limit_ns {
+        transaction_state.rate_limit_ns = rate_limit;
+    }
+    transaction_state.initiator = initiator;
+    add_transaction(tid, transaction_state, now);
+    if let Some(key) = idempotency_key {
+        record_idempotency_key(key, tid);
+    }
+
+    get_transaction_state(tid)
+}
+
+/// Builder for a portfolio rebalance: a single atomic transaction that
+/// debits and credits several tokens across several ledgers in arbitrary
+/// combinations. `build` validates the legs before any transaction state
+/// is constructed, so inconsistent rebalances never reach the
+/// participants.
+#[derive(Default)]
+pub struct RebalanceBuilder {
+    legs: Vec<(Principal, TokenName, i64)>,
+    value_neutral: bool,
+}
+
+impl RebalanceBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a leg: apply `amount` to `token` on the given participant.
+    pub fn leg(mut self, canister: Principal, token: TokenName, amount: i64) -> Self {
+        self.legs.push((canister, token, amount));
+        self
+    }
+
+    /// Require the rebalance to be value-neutral: the signed amounts of
+    /// all legs must sum to zero.
+    pub fn value_neutral(mut self) -> Self {
+        self.value_neutral = true;
+        self
+    }
+
+    /// Validate the rebalance and return its legs. Rejected are: fewer
+    /// than two legs, zero-amount legs, the same token appearing twice
+    /// on the same participant, and - if requested - a non-neutral value
+    /// balance.
+    pub fn build(self) -> Result<Vec<(Principal, TokenName, i64)>, TransactionError> {
+        if self.legs.len() < 2 {
+            return Err(TransactionError::InconsistentRebalance);
+        }
+        for (i, (canister, token, amount)) in self.legs.iter().enumerate() {
+            if *amount == 0 {
+                return Err(TransactionError::InconsistentRebalance);
+            }
+            // A token duplicated on the same participant would make the
+            // legs race for the same lock and the intent ambiguous.
+            if self.legs[..i]
+                .iter()
+                .any(|(other_canister, other_token, _)| {
+                    other_canister == canister && other_token == token
+                })
+            {
+                return Err(TransactionError::InconsistentRebalance);
+            }
+        }
+        if self.value_neutral {
+            let sum: i64 = self.legs.iter().map(|(_, _, amount)| *amount).sum();
+            if sum != 0 {
+                return Err(TransactionError::InconsistentRebalance);
+            }
+        }
+        Ok(self.legs)
+    }
+}
+
+/// Start a portfolio rebalance: atomically apply every leg's amount to
+/// its token, across all involved ledgers. All legs prepare, and only if
+/// all succeed does the whole rebalance commit; otherwise every
+/// participant releases its locks.
+///
+/// With `value_neutral` set, the rebalance is refused unless the signed
+/// leg amounts sum to zero. `valid_until_ns` and `auto_retry` behave as
+/// in `swap_tokens`.
+#[update]
+async fn rebalance_tokens(
+    legs: Vec<(Principal, TokenName, i64)>,
+    value_neutral: bool,
+    valid_until_ns: Option<u64>,
+    auto_retry: Option<u8>,
+) -> Result<TransactionResult, TransactionError> {
+    let mut builder = RebalanceBuilder::new();
+    for (canister, token, amount) in legs {
+        builder = builder.leg(canister, token, amount);
+    }
+    if value_neutral {
+        builder = builder.value_neutral();
+    }
+    let legs = builder.build()?;
+
+    let tid = get_next_transaction_number();
+    let trace_id = ic_cdk::api::time();
+    let mut transaction_state = transaction_for_legs(
+        tid,
+        trace_id,
+        &legs,
+        valid_until_ns,
+        get_configuration().prepare_call_mode,
+        0,
+    );
+    check_payload_cap(&transaction_state, &get_configuration())?;
+    transaction_state.valid_until_ns = valid_until_ns;
+    transaction_state.retries_left = auto_retry.unwrap_or(0);
+    transaction_state.initiator = ic_cdk::caller();
+    add_transaction(tid, transaction_state, ic_cdk::api::time());
+
+    get_transaction_state(tid)
+}
+
+/// Check that at least `required` ledgers are registered, so a swap
+/// submitted before `init` fails with a typed error instead of an index
+/// panic when the ledgers are addressed positionally.
+fn _require_ledgers(canisters: &[Principal], required: usize) -> Result<(), TransactionError> {
+    if canisters.len() < required {
+        return Err(TransactionError::NotInitialized);
+    }
+    Ok(())
+}
+
+/// Validate the legs of an N-participant transaction: at least one leg,
+/// and every leg addressed at a registered ledger.
+fn _validate_participants(
+    participants: &[(Principal, TokenName, i64)],
+    canisters: &[Principal],
+) -> Result<(), TransactionError> {
+    if participants.is_empty() {
+        return Err(TransactionError::InconsistentRebalance);
+    }
+    if participants
+        .iter()
+        .any(|(canister, _, _)| !canisters.contains(canister))
+    {
+        return Err(TransactionError::UnknownParticipant);
+    }
+    Ok(())
+}
+
+/// Start a transaction spanning any number of participants: atomically
+/// apply each leg's amount to its token. All legs prepare, and only if
+/// every participant votes "yes" does anything commit. Unlike the
+/// two-leg `swap_tokens`, the participant set is taken from the
+/// argument, so three or more ledgers can take part.
+#[update]
+async fn execute_transaction(
+    participants: Vec<(Principal, TokenName, i64)>,
+) -> Result<TransactionResult, TransactionError> {
+    _validate_participants(&participants, &utils::get_canister_ids())?;
+
+    let tid = get_next_transaction_number();
+    let trace_id = ic_cdk::api::time();
+    let mut transaction_state = transaction_for_legs(
+        tid,
+        trace_id,
+        &participants,
+        None,
+        get_configuration().prepare_call_mode,
+        0,
+    );
+    check_payload_cap(&transaction_state, &get_configuration())?;
+    transaction_state.initiator = ic_cdk::caller();
+    add_transaction(tid, transaction_state, ic_cdk::api::time());
+
+    get_transaction_state(tid)
+}
+
+/// The swap a signed intent authorizes; the candid encoding of this
+/// struct is the exact byte string the signature covers.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct SwapIntent {
+    pub token1: TokenName,
+    pub token2: TokenName,
+    pub amount1: i64,
+    pub amount2: i64,
+    pub valid_until_ns: Option<u64>,
+    pub auto_retry: Option<u8>,
+}
+
+/// A swap authorized off-chain by its initiator: anyone, e.g. a relayer,
+/// may submit it, the signature proves who authorized it.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct SignedSwapIntent {
+    /// Candid-encoded `SwapIntent`.
+    pub payload: Vec<u8>,
+    /// The initiator's ed25519 public key (32 bytes). The initiator
+    /// principal is the self-authenticating principal of this key.
+    pub public_key: Vec<u8>,
+    /// ed25519 signature over `payload`.
+    pub signature: Vec<u8>,
+}
+
+/// Verify a signed intent and return the swap it authorizes together
+/// with the initiator principal derived from the verified public key.
+fn verify_intent(intent: &SignedSwapIntent) -> Result<(SwapIntent, Principal), TransactionError> {
+    let public_key: &[u8; 32] = intent
+        .public_key
+        .as_slice()
+        .try_into()
+        .map_err(|_| TransactionError::InvalidSignature)?;
+    let public_key =
+        VerifyingKey::from_bytes(public_key).map_err(|_| TransactionError::InvalidSignature)?;
+    let signature = Signature::from_slice(&intent.signature)
+        .map_err(|_| TransactionError::InvalidSignature)?;
+    public_key
+        .verify_strict(&intent.payload, &signature)
+        .map_err(|_| TransactionError::InvalidSignature)?;
+    let swap =
+        Decode!(&intent.payload, SwapIntent).map_err(|_| TransactionError::MalformedIntent)?;
+    Ok((swap, Principal::self_authenticating(&intent.public_key)))
+}
+
+/// Start a swap authorized by a signed intent, without the initiator
+/// calling the coordinator themselves. The verified signer is recorded
+/// as the transaction's initiator, not the submitting caller.
+#[update]
+async fn submit_signed_swap(
+    intent: SignedSwapIntent,
+) -> Result<TransactionResult, TransactionError> {
+    let (swap, initiator) = verify_intent(&intent)?;
+    create_swap(
+        swap.token1,
+        swap.token2,
+        BalanceDelta::from_signed(swap.amount1),
+        BalanceDelta::from_signed(swap.amount2),
+        swap.valid_until_ns,
+        swap.auto_retry,
+        None,
+        None,
+        None,
+        None,
+        initiator,
+        get_next_transaction_number(),
+        ic_cdk::api::time(),
+    )
+}
+
+/// Diagnostic: ask every participant for its current time and report the
+/// signed difference from the coordinator's clock, in nanoseconds.
+///
+/// Timeout and lease handling compare timestamps taken on different
+/// canisters; a large skew here would explain premature or delayed lease
+/// expirations.
+#[update]
+async fn check_clock_skew() -> Vec<(Principal, i64)> {
+    let mut skews = vec![];
+    for canister in utils::get_canister_ids() {
+        if let Ok((participant_now,)) =
+            ic_cdk::api::call::call::<_, (u64,)>(canister, "now", ()).await
+        {
+            skews.push((canister, clock_skew_ns(ic_cdk::api::time(), participant_now)));
+        }
+    }
+    skews
+}
+
+/// Incident triage: query every participant's currently held locks and
+/// report the single longest-held one as (participant, token, lock age
+/// in nanoseconds). Participants that do not answer are skipped. `None`
+/// if no participant holds a lock.
+#[update]
+async fn longest_lock() -> Option<(Principal, TokenName, u64)> {
+    let mut locks = vec![];
+    for canister in utils::get_canister_ids() {
+        if let Ok((locked,)) = ic_cdk::api::call::call::<_, (Vec<(TokenName, u64)>,)>(
+            canister,
+            "locked_tokens",
+            (),
+        )
+        .await
+        {
+            for (token, age) in locked {
+                locks.push((canister, token, age));
+            }
+        }
+    }
+    _longest_lock(locks)
+}
+
+/// The longest-held lock among the given (participant, token, age)
+/// entries.
+fn _longest_lock(locks: Vec<(Principal, TokenName, u64)>) -> Option<(Principal, TokenName, u64)> {
+    locks.into_iter().max_by_key(|(_, _, age)| *age)
+}
+
+/// Every token tradable through this coordinator, aggregated across all
+/// participants for UI bootstrapping.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct TokenListing {
+    /// (participant, token) pairs, in participant order.
+    pub tokens: Vec<(Principal, TokenName)>,
+    /// Set if at least one participant was unreachable; its tokens are
+    /// missing from the listing.
+    pub partial: bool,
+}
+
+thread_local! {
+    /// Cached aggregated token listing, as (fetch time, listing).
+    static TOKEN_CACHE: std::cell::RefCell<Option<(u64, TokenListing)>> =
+        const { std::cell::RefCell::new(None) };
+}
+
+/// Drop the cached token listing, e.g. after the set of participants or
+/// tokens changed.
+pub fn invalidate_token_cache() {
+    TOKEN_CACHE.with(|cache| *cache.borrow_mut() = None);
+}
+
+/// List every token on every participant, so clients can enumerate what
+/// is tradable without knowing the topology. The listing is cached for
+/// `token_cache_ttl_ns`; partial results (an unreachable participant)
+/// are returned but never cached, so the next call retries.
+#[update]
+async fn list_all_tokens() -> TokenListing {
+    let now = ic_cdk::api::time();
+    let cached = TOKEN_CACHE.with(|cache| {
+        cache.borrow().as_ref().and_then(|(fetched_at, listing)| {
+            (now < fetched_at + get_configuration().token_cache_ttl_ns)
+                .then(|| listing.clone())
+        })
+    });
+    if let Some(listing) = cached {
+        return listing;
+    }
+    let mut answers = vec![];
+    for canister in utils::get_canister_ids() {
+        let answer =
+            ic_cdk::api::call::call::<_, (Vec<TokenName>,)>(canister, "list_tokens", ()).await;
+        answers.push((canister, answer.ok().map(|(tokens,)| tokens)));
+    }
+    let listing = _aggregate_tokens(answers);
+    if !listing.partial {
+        TOKEN_CACHE.with(|cache| *cache.borrow_mut() = Some((now, listing.clone())));
+    }
+    listing
+}
+
+/// Merge the participants' answers into one listing, flagging it as
+/// partial if any participant did not answer.
+fn _aggregate_tokens(answers: Vec<(Principal, Option<Vec<TokenName>>)>) -> TokenListing {
+    let mut listing = TokenListing {
+        tokens: vec![],
+        partial: false,
+    };
+    for (canister, tokens) in answers {
+        match tokens {
+            Some(tokens) => {
+                for token in tokens {
+                    listing.tokens.push((canister, token));
+                }
+            }
+            None => listing.partial = true,
+        }
+    }
+    listing
+}
+
+/// Upgrade management: collect the compile-time WASM version of every
+/// participant, so operators can confirm the fleet runs compatible
+/// versions before initiating swaps. Participants that do not answer are
+/// skipped, so a short report also reveals unreachable ledgers.
+#[update]
+async fn participant_versions() -> Vec<(Principal, String)> {
+    let mut versions = vec![];
+    for canister in utils::get_canister_ids() {
+        if let Ok((version,)) =
+            ic_cdk::api::call::call::<_, (String,)>(canister, "version", ()).await
+        {
+            versions.push((canister, version));
+        }
+    }
+    versions
+}
+
+/// Whether the reported versions describe a partially-upgraded fleet,
+/// i.e. not all participants run the same version.
+fn _mixed_versions(versions: &[(Principal, String)]) -> bool {
+    versions
+        .windows(2)
+        .any(|pair| pair[0].1 != pair[1].1)
+}
+
+/// Signed difference between a participant's clock and the coordinator's
+/// clock: positive if the participant's clock is ahead.
+fn clock_skew_ns(coordinator_now: u64, participant_now: u64) -> i64 {
+    participant_now as i64 - coordinator_now as i64
+}
+
+/// Group legs by target canister, so that several legs on the same
+/// participant are prepared atomically in one batched call.
+fn group_legs(legs: &[(Principal, TokenName, i64)]) -> Vec<(Principal, Vec<(TokenName, i64)>)> {
+    let mut groups: Vec<(Principal, Vec<(TokenName, i64)>)> = vec![];
+    for (canister, token, amount) in legs {
+        match groups.iter_mut().find(|(target, _)| target == canister) {
+            Some((_, changes)) => changes.push((token.clone(), *amount)),
+            None => groups.push((*canister, vec![(token.clone(), *amount)])),
+        }
+    }
+    groups
+}
+
+/// Build the transaction state for the given legs. Participants that
+/// carry a single leg get the plain prepare/abort/commit calls; as soon
+/// as one participant carries several legs, the batched methods are used
+/// so each participant's legs are evaluated atomically.
+///
+/// In `PrepareCallMode::Query`, single-leg prepares go out against the
+/// participants' read-only `prepare_query` and the commit revalidates
+/// via `commit_unprepared`; batched legs always use update prepares.
+fn transaction_for_legs(
+    tid: TransactionId,
+    trace_id: u64,
+    legs: &[(Principal, TokenName, i64)],
+    valid_until_ns: Option<u64>,
+    mode: PrepareCallMode,
+    cycles: u128,
+) -> TransactionState {
+    let groups = group_legs(legs);
+    let canisters: Vec<Principal> = groups.iter().map(|(canister, _)| *canister).collect();
+    if groups.len() == legs.len() {
+        let args: Vec<Vec<u8>> = legs
+            .iter()
+            .map(|(_, token, amount)| {
+                Encode!(token, &BalanceDelta::from_signed(*amount), &valid_until_ns).unwrap()
+            })
+            .collect();
+        let (method_prepare, method_commit) = match mode {
+            PrepareCallMode::Update => ("prepare_transaction", "commit_transaction"),
+            PrepareCallMode::Query => ("prepare_query", "commit_unprepared"),
+        };
+        TransactionState::uniform(
+            tid,
+            trace_id,
+            &canisters,
+            method_prepare,
+            "abort_transaction",
+            method_commit,
+            &args,
+            cycles,
+        )
+    } else {
+        let args: Vec<Vec<u8>> = groups
+            .iter()
+            .map(|(_, changes)| Encode!(changes, &valid_until_ns).unwrap())
+            .collect();
+        TransactionState::uniform(
+            tid,
+            trace_id,
+            &canisters,
+            "prepare_batch",
+            "abort_batch",
+            "commit_batch",
+            &args,
+            cycles,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tid(nonce: u64) -> TransactionId {
+        TransactionId::new(Principal::anonymous(), nonce)
+    }
+
+    #[test]
+    fn test_swap_batch_allocates_distinct_tids() {
+        utils::set_canister_ids(vec![
+            Principal::from_slice(&[1]),
+            Principal::from_slice(&[2]),
+        ]);
+        // Distinct token pairs: identical pairs would trip the
+        // coordinator-side resource reservation.
+        let swaps: Vec<SwapRequest> = (1..=3)
+            .map(|i| SwapRequest {
+                token1: format!("ICP{}", i),
+                token2: format!("EUR{}", i),
+                amount1: -i,
+                amount2: i,
+                valid_until_ns: None,
+                auto_retry: None,
+            })
+            .collect();
+        let tids: Vec<TransactionId> = swaps
+            .into_iter()
+            .enumerate()
+            .map(|(i, swap)| {
+                _create_swap(swap, Principal::anonymous(), tid(i as u64), 0)
+                    .unwrap()
+                    .transaction_number
+            })
+            .collect();
+        // Three registered transactions under three distinct IDs.
+        assert_eq!(tids.len(), 3);
+        assert!(tids.windows(2).all(|pair| pair[0] != pair[1]));
+        assert_eq!(atomic_transactions::count_transactions(), 3);
+    }
+
+    #[test]
+    fn test_overlapping_swap_is_rejected_immediately() {
+        utils::set_canister_ids(vec![
+            Principal::from_slice(&[1]),
+            Principal::from_slice(&[2]),
+        ]);
+        let swap = SwapRequest {
+            token1: "ICP".to_string(),
+            token2: "EUR".to_string(),
+            amount1: -100,
+            amount2: 100,
+            valid_until_ns: None,
+            auto_retry: None,
+        };
+        _create_swap(swap.clone(), Principal::anonymous(), tid(0), 0).unwrap();
+        // A second swap moving the same tokens is refused while the
+        // first is still in flight.
+        assert_eq!(
+            _create_swap(swap.clone(), Principal::anonymous(), tid(1), 0).map(|_| ()),
+            Err(TransactionError::ResourceBusy)
+        );
+        // Disjoint tokens are not affected by the reservation.
+        let other = SwapRequest {
+            token1: "BTC".to_string(),
+            token2: "USD".to_string(),
+            ..swap.clone()
+        };
+        _create_swap(other, Principal::anonymous(), tid(2), 0).unwrap();
+        // Once the first swap finalizes, its reservation is released.
+        with_transaction_list(|list| {
+            list.transactions.get_mut(&tid(0)).unwrap().transaction_status =
+                atomic_transactions::TransactionStatus::Aborted;
+            list.active.remove(&tid(0));
+        });
+        _create_swap(swap, Principal::anonymous(), tid(3), 0).unwrap();
+    }
+
+    #[test]
+    fn test_swap_with_fee_debits_coordinator_account_atomically() {
+        let ledger1 = Principal::from_slice(&[1]);
+        let ledger2 = Principal::from_slice(&[2]);
+        let coordinator = Principal::from_slice(&[9]);
+        utils::set_canister_ids(vec![ledger1, ledger2]);
+        local_participant::fund_account("FEE".to_string(), 1_000);
+        local_participant::set_swap_fee(Some(local_participant::SwapFee {
+            coordinator,
+            account: "FEE".to_string(),
+            amount: 25,
+        }));
+
+        // The swap carries a third, local leg targeting the coordinator
+        // itself through the fee endpoints.
+        let result = create_swap(
+            "ICP".to_string(),
+            "EUR".to_string(),
+            BalanceDelta::debit(100),
+            BalanceDelta::credit(100),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            Principal::anonymous(),
+            tid(0),
+            0,
+        )
+        .unwrap();
+        assert_eq!(result.total, 3);
+        with_transaction_list(|list| {
+            let state = &list.transactions[&tid(0)];
+            let fee_call = state
+                .pending_prepare_calls
+                .iter()
+                .find(|call| call.target == coordinator)
+                .expect("swap has no local fee leg");
+            assert_eq!(fee_call.method, "prepare_fee_transaction");
+        });
+
+        // All three participants vote yes; the local prepare locks the
+        // fee account but charges nothing yet.
+        assert_eq!(
+            local_participant::prepare_fee(tid(0), "FEE".to_string(), BalanceDelta::debit(25), None, 0),
+            PrepareVote::Yes
+        );
+        with_transaction_list(|list| {
+            let state = list.transactions.get_mut(&tid(0)).unwrap();
+            for call in &mut state.pending_prepare_calls {
+                call.num_tries = 1;
+            }
+            state.prepare_received(true, ledger1);
+            state.prepare_received(true, ledger2);
+            state.prepare_received(true, coordinator);
+            assert_eq!(
+                state.transaction_status,
+                atomic_transactions::TransactionStatus::Committing
+            );
+        });
+        assert_eq!(local_participant::fee_balance(&"FEE".to_string()), Some(1_000));
+        // Only the commit of the local leg charges the fee.
+        local_participant::commit_fee(tid(0), "FEE".to_string(), BalanceDelta::debit(25));
+        assert_eq!(local_participant::fee_balance(&"FEE".to_string()), Some(975));
+
+        local_participant::set_swap_fee(None);
+    }
+
+    #[test]
+    fn test_simulated_votes_decode_from_raw_answers() {
+        use ic_atomic_transactions::PrepareError;
+        assert!(_decode_vote(&Encode!(&PrepareVote::Yes).unwrap()));
+        assert!(!_decode_vote(
+            &Encode!(&PrepareVote::No(PrepareError::InsufficientBalance)).unwrap()
+        ));
+        assert!(!_decode_vote(&Encode!(&PrepareVote::Busy).unwrap()));
+        // Garbage from a misbehaving participant is a "no", not a trap.
+        assert!(!_decode_vote(b"not candid"));
+    }
+
+    #[test]
+    fn test_healthcheck_reports_dead_ledger_as_unhealthy() {
+        use ic_cdk::api::call::RejectionCode;
+        assert!(_ledger_alive(&Ok(vec![])));
+        // A deleted or uninstalled ledger rejects the call outright.
+        assert!(!_ledger_alive(&Err((
+            RejectionCode::DestinationInvalid,
+            "canister does not exist".to_string()
+        ))));
+        assert!(!_ledger_alive(&Err((
+            RejectionCode::CanisterError,
+            "canister is stopped".to_string()
+        ))));
+    }
+
+    #[test]
+    fn test_signed_intent_verifies_and_detects_tampering() {
+        use ed25519_dalek::{Signer, SigningKey};
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let swap = SwapIntent {
+            token1: "ICP".to_string(),
+            token2: "EUR".to_string(),
+            amount1: -1337,
+            amount2: 42,
+            valid_until_ns: None,
+            auto_retry: None,
+        };
+        let payload = Encode!(&swap).unwrap();
+        let intent = SignedSwapIntent {
+            payload: payload.clone(),
+            public_key: key.verifying_key().to_bytes().to_vec(),
+            signature: key.sign(&payload).to_bytes().to_vec(),
+        };
+        let (verified, initiator) = verify_intent(&intent).unwrap();
+        assert_eq!(verified.token1, "ICP");
+        assert_eq!(verified.amount2, 42);
+        // The initiator is derived from the verified key, not claimed.
+        assert_eq!(
+            initiator,
+            Principal::self_authenticating(&intent.public_key)
+        );
+
+        // A relayer inflating the amount invalidates the signature.
+        let mut tampered = intent.clone();
+        let mut inflated = swap.clone();
+        inflated.amount2 = 43;
+        tampered.payload = Encode!(&inflated).unwrap();
+        assert_eq!(
+            verify_intent(&tampered).unwrap_err(),
+            TransactionError::InvalidSignature
+        );
+        // So does a signature from a different key.
+        let mut forged = intent.clone();
+        forged.public_key = SigningKey::from_bytes(&[8; 32])
+            .verifying_key()
+            .to_bytes()
+            .to_vec();
+        assert_eq!(
+            verify_intent(&forged).unwrap_err(),
+            TransactionError::InvalidSignature
+        );
+    }
+
+    #[test]
+    fn test_longest_lock_picks_oldest_across_participants() {
+        let ledger1 = Principal::from_slice(&[1]);
+        let ledger2 = Principal::from_slice(&[2]);
+        assert_eq!(_longest_lock(vec![]), None);
+        assert_eq!(
+            _longest_lock(vec![
+                (ledger1, "ICP".to_string(), 500),
+                (ledger2, "EUR".to_string(), 1_500),
+                (ledger1, "USD".to_string(), 100),
+            ]),
+            Some((ledger2, "EUR".to_string(), 1_500))
+        );
+    }
+
+    #[test]
+    fn test_aggregate_tokens_flags_partial_results() {
+        let ledger1 = Principal::from_slice(&[1]);
+        let ledger2 = Principal::from_slice(&[2]);
+        let listing = _aggregate_tokens(vec![
+            (ledger1, Some(vec!["ICP".to_string(), "USD".to_string()])),
+            (ledger2, Some(vec!["EUR".to_string()])),
+        ]);
+        assert_eq!(
+            listing.tokens,
+            vec![
+                (ledger1, "ICP".to_string()),
+                (ledger1, "USD".to_string()),
+                (ledger2, "EUR".to_string()),
+            ]
+        );
+        assert!(!listing.partial);
+        // An unreachable participant is omitted but flagged.
+        let listing = _aggregate_tokens(vec![
+            (ledger1, Some(vec!["ICP".to_string()])),
+            (ledger2, None),
+        ]);
+        assert_eq!(listing.tokens, vec![(ledger1, "ICP".to_string())]);
+        assert!(listing.partial);
+    }
+
+    #[test]
+    fn test_rebalance_builder_validation() {
+        let ledger1 = Principal::from_slice(&[1]);
+        let ledger2 = Principal::from_slice(&[2]);
+        // A single leg is not a rebalance.
+        assert_eq!(
+            RebalanceBuilder::new()
+                .leg(ledger1, "ICP".to_string(), -10)
+                .build(),
+            Err(TransactionError::InconsistentRebalance)
+        );
+        // Zero-amount legs are meaningless.
+        assert_eq!(
+            RebalanceBuilder::new()
+                .leg(ledger1, "ICP".to_string(), -10)
+                .leg(ledger2, "EUR".to_string(), 0)
+                .build(),
+            Err(TransactionError::InconsistentRebalance)
+        );
+        // The same token twice on the same participant is ambiguous.
+        assert_eq!(
+            RebalanceBuilder::new()
+                .leg(ledger1, "ICP".to_string(), -10)
+                .leg(ledger1, "ICP".to_string(), 10)
+                .build(),
+            Err(TransactionError::InconsistentRebalance)
+        );
+        // Value neutrality is only checked when requested.
+        assert_eq!(
+            RebalanceBuilder::new()
+                .leg(ledger1, "ICP".to_string(), -10)
+                .leg(ledger2, "EUR".to_string(), 7)
+                .value_neutral()
+                .build(),
+            Err(TransactionError::InconsistentRebalance)
+        );
+        let legs = vec![
+            (ledger1, "ICP".to_string(), -10),
+            (ledger1, "USD".to_string(), 3),
+            (ledger2, "EUR".to_string(), 7),
+        ];
+        let mut builder = RebalanceBuilder::new().value_neutral();
+        for (canister, token, amount) in &legs {
+            builder = builder.leg(*canister, token.clone(), *amount);
+        }
+        assert_eq!(builder.build(), Ok(legs));
+    }
+
+    #[test]
+    fn test_execute_transaction_validates_participants() {
+        let ledger1 = Principal::from_slice(&[1]);
+        let ledger2 = Principal::from_slice(&[2]);
+        let stranger = Principal::from_slice(&[99]);
+        let known = vec![ledger1, ledger2];
+        assert_eq!(
+            _validate_participants(&[], &known),
+            Err(TransactionError::InconsistentRebalance)
+        );
+        assert_eq!(
+            _validate_participants(&[(stranger, "ICP".to_string(), -1)], &known),
+            Err(TransactionError::UnknownParticipant)
+        );
+        assert_eq!(
+            _validate_participants(&[(ledger1, "ICP".to_string(), -1)], &known),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_swap_before_init_is_cleanly_rejected() {
+        // Before `init` there are no registered ledgers: the guard must
+        // fire instead of `canisters[0]` panicking with an index error.
+        assert_eq!(
+            _require_ledgers(&[], 2),
+            Err(TransactionError::NotInitialized)
+        );
+        assert_eq!(
+            _require_ledgers(&[Principal::from_slice(&[1])], 2),
+            Err(TransactionError::NotInitialized)
+        );
+        let ledgers = vec![Principal::from_slice(&[1]), Principal::from_slice(&[2])];
+        assert_eq!(_require_ledgers(&ledgers, 2), Ok(()));
+    }
+
+    #[test]
+    fn test_commit_faults_are_retried_until_committed() {
+        use ic_atomic_transactions::TwoPhaseCommitState;
+        // Participant side: with `fail_commit_times = 2` the first two
+        // commit attempts are rejected, the third one goes through.
+        let mut participant: TwoPhaseCommitState<String> = TwoPhaseCommitState::default();
+        participant.configuration.fail_commit_times = 2;
+        assert!(participant.prepare_transaction(tid(1), &"ICP".to_string(), None, 0));
+        assert!(participant.take_commit_fault());
+        assert!(participant.take_commit_fault());
+        assert!(!participant.take_commit_fault());
+        participant.commit_transaction(tid(1), &"ICP".to_string());
+
+        // Coordinator side: the two rejected commits count as failures
+        // and are retried; the swap still ends up `Committed`.
+        let ledger1 = Principal::from_slice(&[1]);
+        let ledger2 = Principal::from_slice(&[2]);
+        let legs = vec![
+            (ledger1, "ICP".to_string(), -10),
+            (ledger2, "EUR".to_string(), 10),
+        ];
+        let mut state = transaction_for_legs(tid(1), 0, &legs, None, PrepareCallMode::Update, 0);
+        for call in &mut state.pending_prepare_calls {
+            call.num_tries = 1;
+        }
+        state.prepare_received(true, ledger1);
+        state.prepare_received(true, ledger2);
+        assert_eq!(
+            state.transaction_status,
+            atomic_transactions::TransactionStatus::Committing
+        );
+        state.commit_received(false, ledger1);
+        state.commit_received(false, ledger1);
+        state.commit_received(true, ledger1);
+        state.commit_received(true, ledger2);
+        assert_eq!(
+            state.transaction_status,
+            atomic_transactions::TransactionStatus::Committed
+        );
+    }
+
+    #[test]
+    fn test_three_way_transfer_is_all_or_nothing() {
+        let ledgers: Vec<Principal> = (1..=3u8).map(|i| Principal::from_slice(&[i])).collect();
+        let legs = vec![
+            (ledgers[0], "ICP".to_string(), -10),
+            (ledgers[1], "EUR".to_string(), 4),
+            (ledgers[2], "USD".to_string(), 6),
+        ];
+        // Three unanimous yes votes commit every leg.
+        let mut state = transaction_for_legs(tid(0), 0, &legs, None, PrepareCallMode::Update, 0);
+        for call in &mut state.pending_prepare_calls {
+            call.num_tries = 1;
+        }
+        for ledger in &ledgers {
+            state.prepare_received(true, *ledger);
+        }
+        assert_eq!(
+            state.transaction_status,
+            atomic_transactions::TransactionStatus::Committing
+        );
+        // One refusal aborts all three legs; no balance changes anywhere.
+        let mut state = transaction_for_legs(tid(1), 0, &legs, None, PrepareCallMode::Update, 0);
+        for call in &mut state.pending_prepare_calls {
+            call.num_tries = 1;
+        }
+        state.prepare_received(true, ledgers[0]);
+        state.prepare_received(true, ledgers[1]);
+        state.prepare_received(false, ledgers[2]);
+        assert_eq!(
+            state.transaction_status,
+            atomic_transactions::TransactionStatus::Aborting
+        );
+        assert!(state
+            .pending_commit_calls
+            .iter()
+            .all(|call| call.num_success == 0));
+    }
+
+    #[test]
+    fn test_quorum_commits_with_one_participant_pending() {
+        let ledgers: Vec<Principal> = (1..=3u8).map(|i| Principal::from_slice(&[i])).collect();
+        let legs = vec![
+            (ledgers[0], "ICP".to_string(), -10),
+            (ledgers[1], "EUR".to_string(), 4),
+            (ledgers[2], "USD".to_string(), 6),
+        ];
+        // Experimentation-only quorum mode: two of three successes
+        // advance each phase; atomicity for the third leg is forfeit.
+        let mut state = transaction_for_legs(tid(0), 0, &legs, None, PrepareCallMode::Update, 0);
+        state.required_successes = 2;
+        for call in &mut state.pending_prepare_calls {
+            call.num_tries = 1;
+        }
+        state.prepare_received(true, ledgers[0]);
+        state.prepare_received(true, ledgers[1]);
+        // The third prepare is still pending, yet the quorum commits.
+        assert_eq!(
+            state.transaction_status,
+            atomic_transactions::TransactionStatus::Committing
+        );
+        // The straggler's late "yes" is tolerated, not a trap.
+        state.prepare_received(true, ledgers[2]);
+        for call in &mut state.pending_commit_calls {
+            call.num_tries = 1;
+        }
+        state.commit_received(true, ledgers[0]);
+        state.commit_received(true, ledgers[1]);
+        assert_eq!(
+            state.transaction_status,
+            atomic_transactions::TransactionStatus::Committed
+        );
+    }
+
+    #[test]
+    fn test_rebalance_three_tokens_two_ledgers_is_all_or_nothing() {
+        let ledger1 = Principal::from_slice(&[1]);
+        let ledger2 = Principal::from_slice(&[2]);
+        let legs = RebalanceBuilder::new()
+            .leg(ledger1, "ICP".to_string(), -10)
+            .leg(ledger1, "USD".to_string(), 3)
+            .leg(ledger2, "EUR".to_string(), 7)
+            .value_neutral()
+            .build()
+            .unwrap();
+
+        // Ledger 1 carries two legs, so the transaction has one batched
+        // call per ledger. Only unanimous yes votes commit.
+        let mut state = transaction_for_legs(tid(0), 0, &legs, None, PrepareCallMode::Update, 0);
+        assert_eq!(state.pending_prepare_calls.len(), 2);
+        for call in &mut state.pending_prepare_calls {
+            call.num_tries = 1;
+        }
+        state.prepare_received(true, ledger1);
+        state.prepare_received(true, ledger2);
+        assert_eq!(
+            state.transaction_status,
+            atomic_transactions::TransactionStatus::Committing
+        );
+
+        // If any participant votes no, nothing commits: the whole
+        // rebalance aborts.
+        let mut state = transaction_for_legs(tid(1), 0, &legs, None, PrepareCallMode::Update, 0);
+        for call in &mut state.pending_prepare_calls {
+            call.num_tries = 1;
+        }
+        state.prepare_received(true, ledger1);
+        state.prepare_received(false, ledger2);
+        assert_eq!(
+            state.transaction_status,
+            atomic_transactions::TransactionStatus::Aborting
+        );
+    }
+
+    #[test]
+    fn test_mixed_versions_detects_partial_upgrade() {
+        let ledger1 = Principal::from_slice(&[1]);
+        let ledger2 = Principal::from_slice(&[2]);
+        // A uniform fleet reports the same version everywhere.
+        assert!(!_mixed_versions(&[
+            (ledger1, "0.1.0".to_string()),
+            (ledger2, "0.1.0".to_string()),
+        ]));
+        // After upgrading only ledger 2 to a bumped version, the fleet
+        // reads as partially upgraded.
+        assert!(_mixed_versions(&[
+            (ledger1, "0.1.0".to_string()),
+            (ledger2, "0.2.0".to_string()),
+        ]));
+    }
+
+    #[test]
+    fn test_clock_skew_sign() {
+        // A participant whose clock runs ahead reports positive skew.
+        assert_eq!(clock_skew_ns(1_000, 1_500), 500);
+        // A participant whose clock lags reports negative skew.
+        assert_eq!(clock_skew_ns(1_500, 1_000), -500);
+        assert_eq!(clock_skew_ns(1_000, 1_000), 0);
+    }
+
+    #[test]
+    fn test_group_legs_merges_same_target() {
+        let ledger1 = Principal::from_slice(&[1]);
+        let ledger2 = Principal::from_slice(&[2]);
+        let legs = vec![
+            (ledger1, "ICP".to_string(), -1337),
+            (ledger1, "USD".to_string(), -10),
+            (ledger2, "EUR".to_string(), 42),
+        ];
+        let groups = group_legs(&legs);
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].1.len(), 2);
+        assert_eq!(groups[1].1.len(), 1);
+    }
+
+    #[test]
+    fn test_transaction_for_legs_uses_batch_methods_for_shared_target() {
+        let ledger1 = Principal::from_slice(&[1]);
+        let legs = vec![
+            (ledger1, "ICP".to_string(), -1337),
+            (ledger1, "USD".to_string(), -10),
+        ];
+        let state = transaction_for_legs(tid(0), 0, &legs, None, PrepareCallMode::Update, 0);
+        assert_eq!(state.pending_prepare_calls.len(), 1);
+        assert_eq!(state.pending_prepare_calls[0].method, "prepare_batch");
+        assert_eq!(state.pending_commit_calls[0].method, "commit_batch");
+    }
+
+    #[test]
+    fn test_transaction_for_legs_query_mode_uses_query_prepare() {
+        let ledger1 = Principal::from_slice(&[1]);
+        let ledger2 = Principal::from_slice(&[2]);
+        let legs = vec![
+            (ledger1, "ICP".to_string(), -1337),
+            (ledger2, "EUR".to_string(), 42),
+        ];
+        let state = transaction_for_legs(tid(0), 0, &legs, None, PrepareCallMode::Query, 0);
+        assert_eq!(state.pending_prepare_calls[0].method, "prepare_query");
+        // The query prepare reserved nothing, so the commit revalidates.
+        assert_eq!(state.pending_commit_calls[0].method, "commit_unprepared");
+        assert_eq!(state.pending_abort_calls[0].method, "abort_transaction");
+    }
+
+    #[test]
+    fn test_cycles_are_attached_to_every_phase_call() {
+        let ledger1 = Principal::from_slice(&[1]);
+        let ledger2 = Principal::from_slice(&[2]);
+        let legs = vec![
+            (ledger1, "ICP".to_string(), -1337),
+            (ledger2, "EUR".to_string(), 42),
+        ];
+        // Every call of every phase carries the requested amount, so the
+        // participant sees it via `msg_cycles_available` no matter which
+        // phase the message belongs to. Actual delivery is the replica's
+        // job and not observable on the host.
+        let state = transaction_for_legs(tid(0), 0, &legs, None, PrepareCallMode::Update, 7_000);
+        for call in state
+            .pending_prepare_calls
+            .iter()
+            .chain(state.pending_abort_calls.iter())
+            .chain(state.pending_commit_calls.iter())
+        {
+            assert_eq!(call.cycles, 7_000);
+        }
+    }
+
+    #[test]
+    fn test_idempotency_key_returns_the_same_transaction() {
+        utils::set_canister_ids(vec![
+            Principal::from_slice(&[1]),
+            Principal::from_slice(&[2]),
+        ]);
+        let submit = |tid| {
+            create_swap(
+                "ICP".to_string(),
+                "EUR".to_string(),
+                BalanceDelta::debit(100),
+                BalanceDelta::credit(100),
+                None,
+                None,
+                None,
+                None,
+                None,
+                Some("swap-abc".to_string()),
+                Principal::anonymous(),
+                tid,
+                0,
+            )
+            .unwrap()
+        };
+        // The second submission under the same key does not create a
+        // second transaction - it answers with the first one's result.
+        let first = submit(tid(0));
+        let second = submit(tid(1));
+        assert_eq!(first.transaction_number, second.transaction_number);
+        assert_eq!(atomic_transactions::count_transactions(), 1);
+
+        // The key expires together with its garbage-collected
+        // transaction; afterwards it may be reused for a fresh swap.
+        with_transaction_list(|list| {
+            list.transactions.remove(&tid(0));
+            list.active.remove(&tid(0));
+            atomic_transactions::expire_idempotency_keys(list);
+        });
+        let fresh = submit(tid(2));
+        assert_ne!(first.transaction_number, fresh.transaction_number);
+    }
+}
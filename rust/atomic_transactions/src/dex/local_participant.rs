@@ -0,0 +1,286 @@
+//! Coordinator-local 2PC participant: a small fee-account ledger living
+//! inside the DEX itself. Its legs are driven through ordinary self-calls
+//! (`call_raw` to `ic_cdk::id()` works like any other inter-canister
+//! call), so a local balance change commits or aborts atomically with
+//! the external ledgers' legs of the same transaction.
+
+use crate::atomic_transactions::{ParticipantSpec, TransactionId};
+use candid::{Decode, Encode, Principal};
+use ic_atomic_transactions::{
+    BalanceDelta, Envelope, Phase, PrepareError, PrepareVote, TokenName, TwoPhaseCommitState,
+};
+use ic_cdk::update;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+
+thread_local! {
+    /// Balances of the coordinator's own accounts, e.g. the fee account.
+    static FEE_ACCOUNTS: RefCell<BTreeMap<TokenName, u64>> = const { RefCell::new(BTreeMap::new()) };
+    /// Participant-side lock state for the local accounts, the same
+    /// machinery the external ledgers use.
+    static PC_STATE: RefCell<TwoPhaseCommitState<TokenName>> =
+        RefCell::new(TwoPhaseCommitState::default());
+    /// Log of `(account, tid)` pairs whose commit was already applied,
+    /// making retried commits a no-op instead of a trap.
+    static COMMITTED: RefCell<BTreeSet<(TokenName, TransactionId)>> =
+        const { RefCell::new(BTreeSet::new()) };
+    /// The fee every swap pays, if one is configured.
+    static SWAP_FEE: RefCell<Option<SwapFee>> = const { RefCell::new(None) };
+}
+
+/// A per-swap fee debited from a coordinator-local account, included in
+/// every swap transaction as an additional local leg.
+#[derive(Clone, Debug)]
+pub struct SwapFee {
+    /// The coordinator's own principal, recorded at configuration time
+    /// so transaction construction needs no `ic_cdk::id()` call.
+    pub coordinator: Principal,
+    /// The local account the fee is debited from.
+    pub account: TokenName,
+    /// The fee in base units; debited once per swap.
+    pub amount: u64,
+}
+
+/// The currently configured swap fee, if any.
+pub fn swap_fee() -> Option<SwapFee> {
+    SWAP_FEE.with(|fee| fee.borrow().clone())
+}
+
+/// Configure or clear the swap fee.
+pub fn set_swap_fee(fee: Option<SwapFee>) {
+    SWAP_FEE.with(|current| *current.borrow_mut() = fee);
+}
+
+/// The participant spec of a swap's local fee leg: a self-call debiting
+/// the fee account through the local prepare/abort/commit endpoints.
+pub fn fee_participant(fee: &SwapFee, valid_until_ns: Option<u64>) -> ParticipantSpec {
+    ParticipantSpec {
+        canister: fee.coordinator,
+        prepare: "prepare_fee_transaction".to_string(),
+        abort: "abort_fee_transaction".to_string(),
+        commit: "commit_fee_transaction".to_string(),
+        payload: Encode!(&fee.account, &BalanceDelta::debit(fee.amount), &valid_until_ns).unwrap(),
+    }
+}
+
+/// Current balance of a local account.
+pub fn fee_balance(account: &TokenName) -> Option<u64> {
+    FEE_ACCOUNTS.with(|accounts| accounts.borrow().get(account).copied())
+}
+
+/// Credit a local account, creating it if it does not exist yet.
+pub fn fund_account(account: TokenName, amount: u64) {
+    FEE_ACCOUNTS.with(|accounts| {
+        *accounts.borrow_mut().entry(account).or_insert(0) += amount;
+    });
+}
+
+/// Whether the given change is applicable to the given local account,
+/// classifying a rejection the same way the external ledgers do.
+fn check_change(account: &TokenName, change: BalanceDelta) -> Option<PrepareError> {
+    let change = change.as_i64();
+    if change == 0 {
+        return Some(PrepareError::NoOp);
+    }
+    FEE_ACCOUNTS.with(|accounts| match accounts.borrow().get(account) {
+        Some(balance) if balance.checked_add_signed(change).is_some() => None,
+        Some(_) if change < 0 => Some(PrepareError::InsufficientBalance),
+        Some(_) => Some(PrepareError::Overflow),
+        None => Some(PrepareError::UnknownToken),
+    })
+}
+
+/// Local prepare: vote on and, on a "yes", lock the given account for
+/// the transaction. Mirrors the participant ledgers' `prepare_balance`.
+pub fn prepare_fee(
+    tid: TransactionId,
+    account: TokenName,
+    change: BalanceDelta,
+    valid_until_ns: Option<u64>,
+    now: u64,
+) -> PrepareVote {
+    if let Some(error) = check_change(&account, change) {
+        ic_cdk::println!(
+            "Change {} cannot be applied to local account {}: {:?}",
+            change,
+            account,
+            error
+        );
+        return PrepareVote::No(error);
+    }
+    let lock_taken =
+        PC_STATE.with(|state| state.borrow_mut().prepare_transaction(tid, &account, valid_until_ns, now));
+    if !lock_taken {
+        ic_cdk::println!("Local account {} already locked by another transaction", account);
+        return PrepareVote::Busy;
+    }
+    PrepareVote::Yes
+}
+
+/// Local abort: release the lock on the given account if this
+/// transaction holds it. Safe to call multiple times.
+pub fn abort_fee(tid: TransactionId, account: &TokenName) -> bool {
+    PC_STATE.with(|state| state.borrow_mut().abort_transaction(tid, account))
+}
+
+/// Local commit: apply the prepared change to the account. The balance
+/// only moves here - a prepared-but-aborted fee leg never charges
+/// anything. Retried commits are a no-op success, like on the ledgers.
+pub fn commit_fee(tid: TransactionId, account: TokenName, change: BalanceDelta) {
+    let already_committed =
+        COMMITTED.with(|committed| !committed.borrow_mut().insert((account.clone(), tid)));
+    if already_committed {
+        ic_cdk::println!(
+            "Transaction {} already committed for local account {} - ignoring retry",
+            tid,
+            account
+        );
+        return;
+    }
+    PC_STATE.with(|state| state.borrow_mut().commit_transaction(tid, &account));
+    FEE_ACCOUNTS.with(|accounts| {
+        let mut accounts = accounts.borrow_mut();
+        let balance = accounts
+            .get_mut(&account)
+            .expect("commit for unknown local account");
+        // Cannot fail, prepare already checked the change applies.
+        *balance = balance.checked_add_signed(change.as_i64()).unwrap();
+    });
+}
+
+/// Trap unless the call is a self-call: only the transaction loop of
+/// this very canister may drive the local participant, any other caller
+/// could forge a prepare and lock the fee account.
+fn require_self() {
+    if ic_cdk::caller() != ic_cdk::id() {
+        ic_cdk::trap("Only the coordinator itself may drive its local participant");
+    }
+}
+
+/// Prepare endpoint of the local participant, reached via self-call
+/// from the transaction loop like any external ledger's prepare.
+#[update]
+fn prepare_fee_transaction(envelope: Envelope) -> PrepareVote {
+    require_self();
+    if !envelope.matches_phase(Phase::Prepare) {
+        return PrepareVote::No(PrepareError::Rejected);
+    }
+    let (account, change, valid_until_ns) =
+        Decode!(&envelope.args, TokenName, BalanceDelta, Option<u64>).unwrap();
+    prepare_fee(envelope.tid, account, change, valid_until_ns, ic_cdk::api::time())
+}
+
+/// Abort endpoint of the local participant.
+#[update]
+fn abort_fee_transaction(envelope: Envelope) -> bool {
+    require_self();
+    if !envelope.matches_phase(Phase::Abort) {
+        return false;
+    }
+    let (account, _change, _valid_until_ns) =
+        Decode!(&envelope.args, TokenName, BalanceDelta, Option<u64>).unwrap();
+    abort_fee(envelope.tid, &account)
+}
+
+/// Commit endpoint of the local participant.
+#[update]
+fn commit_fee_transaction(envelope: Envelope) -> bool {
+    require_self();
+    if !envelope.matches_phase(Phase::Commit) {
+        return false;
+    }
+    let (account, change, _valid_until_ns) =
+        Decode!(&envelope.args, TokenName, BalanceDelta, Option<u64>).unwrap();
+    commit_fee(envelope.tid, account, change);
+    true
+}
+
+/// Configure the per-swap fee: every subsequent swap includes a local
+/// leg debiting `amount` from the given account. An amount of zero
+/// clears the fee. Only callable by a controller.
+#[update]
+fn set_swap_fee_config(account: TokenName, amount: u64) {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        ic_cdk::trap("set_swap_fee_config can only be called by a controller");
+    }
+    set_swap_fee((amount > 0).then(|| SwapFee {
+        coordinator: ic_cdk::id(),
+        account,
+        amount,
+    }));
+}
+
+/// Credit a coordinator-local account, e.g. to fund the fee account.
+/// Only callable by a controller.
+#[update]
+fn fund_fee_account(account: TokenName, amount: u64) {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        ic_cdk::trap("fund_fee_account can only be called by a controller");
+    }
+    fund_account(account, amount);
+}
+
+/// Query the balance of a coordinator-local account.
+#[ic_cdk::query]
+fn fee_account_balance(account: TokenName) -> Option<u64> {
+    fee_balance(&account)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tid(nonce: u64) -> TransactionId {
+        TransactionId::new(Principal::anonymous(), nonce)
+    }
+
+    #[test]
+    fn test_fee_applies_only_on_commit() {
+        fund_account("FEE".to_string(), 1_000);
+        // A prepared debit locks the account but moves no funds yet.
+        assert_eq!(
+            prepare_fee(tid(1), "FEE".to_string(), BalanceDelta::debit(10), None, 0),
+            PrepareVote::Yes
+        );
+        assert_eq!(fee_balance(&"FEE".to_string()), Some(1_000));
+        // Only the commit charges the fee.
+        commit_fee(tid(1), "FEE".to_string(), BalanceDelta::debit(10));
+        assert_eq!(fee_balance(&"FEE".to_string()), Some(990));
+        // A retried commit does not double-charge.
+        commit_fee(tid(1), "FEE".to_string(), BalanceDelta::debit(10));
+        assert_eq!(fee_balance(&"FEE".to_string()), Some(990));
+    }
+
+    #[test]
+    fn test_aborted_fee_leg_charges_nothing() {
+        fund_account("FEE".to_string(), 1_000);
+        assert_eq!(
+            prepare_fee(tid(1), "FEE".to_string(), BalanceDelta::debit(10), None, 0),
+            PrepareVote::Yes
+        );
+        // While prepared, the account is locked for other transactions.
+        assert_eq!(
+            prepare_fee(tid(2), "FEE".to_string(), BalanceDelta::debit(10), None, 0),
+            PrepareVote::Busy
+        );
+        assert!(abort_fee(tid(1), &"FEE".to_string()));
+        assert_eq!(fee_balance(&"FEE".to_string()), Some(1_000));
+    }
+
+    #[test]
+    fn test_local_prepare_rejections() {
+        fund_account("FEE".to_string(), 100);
+        assert_eq!(
+            prepare_fee(tid(1), "FEE".to_string(), BalanceDelta::debit(200), None, 0),
+            PrepareVote::No(PrepareError::InsufficientBalance)
+        );
+        assert_eq!(
+            prepare_fee(tid(2), "MISSING".to_string(), BalanceDelta::debit(1), None, 0),
+            PrepareVote::No(PrepareError::UnknownToken)
+        );
+        assert_eq!(
+            prepare_fee(tid(3), "FEE".to_string(), BalanceDelta::from_signed(0), None, 0),
+            PrepareVote::No(PrepareError::NoOp)
+        );
+    }
+}
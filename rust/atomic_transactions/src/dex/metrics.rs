@@ -0,0 +1,151 @@
+//! Prometheus-format metrics served over the HTTP gateway, so a
+//! monitoring stack can be pointed directly at the coordinator instead
+//! of scraping debug prints.
+
+use crate::atomic_transactions::{
+    get_active_transactions, with_transaction_list, TransactionList, TransactionStatus,
+};
+use candid::{CandidType, Deserialize};
+use ic_cdk::query;
+
+/// The request the HTTP gateway hands to `http_request`.
+#[derive(CandidType, Deserialize)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+/// The response returned to the HTTP gateway.
+#[derive(CandidType, Deserialize)]
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+/// Serve the metrics in Prometheus exposition format. Any path is
+/// answered, there is nothing else to serve on this canister.
+#[query]
+pub fn http_request(_req: HttpRequest) -> HttpResponse {
+    let active = get_active_transactions().len();
+    let body = with_transaction_list(|list| render_metrics(list, active));
+    HttpResponse {
+        status_code: 200,
+        headers: vec![(
+            "Content-Type".to_string(),
+            "text/plain; version=0.0.4".to_string(),
+        )],
+        body: body.into_bytes(),
+    }
+}
+
+fn status_label(status: TransactionStatus) -> &'static str {
+    match status {
+        TransactionStatus::Preparing => "preparing",
+        TransactionStatus::Aborting => "aborting",
+        TransactionStatus::Aborted => "aborted",
+        TransactionStatus::Committing => "committing",
+        TransactionStatus::Committed => "committed",
+        TransactionStatus::NeedsReview => "needs_review",
+    }
+}
+
+/// Render the exposition text from the transaction table and the number
+/// of transactions the timer loop still drives.
+fn render_metrics(list: &TransactionList, active: usize) -> String {
+    let statuses = [
+        TransactionStatus::Preparing,
+        TransactionStatus::Aborting,
+        TransactionStatus::Aborted,
+        TransactionStatus::Committing,
+        TransactionStatus::Committed,
+        TransactionStatus::NeedsReview,
+    ];
+    let mut out = String::new();
+    out.push_str("# HELP dex_transactions Number of transactions by status.\n");
+    out.push_str("# TYPE dex_transactions gauge\n");
+    for status in statuses {
+        let count = list
+            .transactions
+            .values()
+            .filter(|state| state.transaction_status == status)
+            .count();
+        out.push_str(&format!(
+            "dex_transactions{{status=\"{}\"}} {}\n",
+            status_label(status),
+            count
+        ));
+    }
+    out.push_str("# HELP dex_calls_total Total 2PC calls issued per phase.\n");
+    out.push_str("# TYPE dex_calls_total counter\n");
+    for phase in ["prepare", "abort", "commit"] {
+        let total: u64 = list
+            .transactions
+            .values()
+            .flat_map(|state| match phase {
+                "prepare" => state.pending_prepare_calls.iter(),
+                "abort" => state.pending_abort_calls.iter(),
+                _ => state.pending_commit_calls.iter(),
+            })
+            .map(|call| call.num_tries)
+            .sum();
+        out.push_str(&format!("dex_calls_total{{phase=\"{}\"}} {}\n", phase, total));
+    }
+    out.push_str("# HELP dex_active_transactions Transactions still driven by the timer loop.\n");
+    out.push_str("# TYPE dex_active_transactions gauge\n");
+    out.push_str(&format!("dex_active_transactions {}\n", active));
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::atomic_transactions::{TransactionId, TransactionState};
+    use candid::{Encode, Principal};
+
+    fn metric(exposition: &str, name: &str) -> u64 {
+        exposition
+            .lines()
+            .find(|line| line.starts_with(name))
+            .and_then(|line| line.rsplit(' ').next())
+            .and_then(|value| value.parse().ok())
+            .unwrap_or_else(|| panic!("metric {} not found", name))
+    }
+
+    #[test]
+    fn test_committed_counter_increments_after_swap() {
+        let ledger1 = Principal::from_slice(&[1]);
+        let ledger2 = Principal::from_slice(&[2]);
+        let tid = TransactionId::new(Principal::anonymous(), 0);
+        let mut state = TransactionState::uniform(
+            tid,
+            0,
+            &[ledger1, ledger2],
+            "prepare_transaction",
+            "abort_transaction",
+            "commit_transaction",
+            &[
+                Encode!(&"ICP".to_string(), &-1337_i64).unwrap(),
+                Encode!(&"EUR".to_string(), &1337_i64).unwrap(),
+            ],
+            0,
+        );
+
+        let before = with_transaction_list(|list| render_metrics(list, 0));
+        assert_eq!(metric(&before, "dex_transactions{status=\"committed\"}"), 0);
+
+        // Drive the swap through a clean prepare and commit round.
+        state.prepare_received(true, ledger1);
+        state.prepare_received(true, ledger2);
+        state.commit_received(true, ledger1);
+        state.commit_received(true, ledger2);
+        assert_eq!(state.transaction_status, TransactionStatus::Committed);
+        with_transaction_list(|list| list.transactions.insert(tid, state));
+
+        let after = with_transaction_list(|list| render_metrics(list, 0));
+        assert_eq!(metric(&after, "dex_transactions{status=\"committed\"}"), 1);
+        assert_eq!(metric(&after, "dex_calls_total{phase=\"prepare\"}"), 0);
+    }
+}
@@ -0,0 +1,281 @@
+use candid::{CandidType, Deserialize, Encode, Principal};
+use ic_atomic_transactions::TokenName;
+use ic_cdk::api::management_canister::main::{
+    create_canister, delete_canister, install_code, stop_canister, CanisterIdRecord,
+    CanisterInstallMode, CanisterSettings, CreateCanisterArgument, InstallCodeArgument,
+};
+use std::sync::{Arc, RwLock};
+
+/// Initial balance of every token in the default layout.
+pub const INITIAL_BALANCE: u64 = 1_000_000;
+
+/// Tokens and initial balances of one ledger canister to provision.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct LedgerSpec {
+    pub token_names: Vec<TokenName>,
+    pub token_balances: Vec<u64>,
+}
+
+/// The demo's default layout: a first ledger holding ICP and USD and a
+/// second one holding EUR.
+pub fn default_ledger_specs() -> Vec<LedgerSpec> {
+    vec![
+        LedgerSpec {
+            token_names: vec!["ICP".to_string(), "USD".to_string()],
+            token_balances: vec![INITIAL_BALANCE, INITIAL_BALANCE],
+        },
+        LedgerSpec {
+            token_names: vec!["EUR".to_string()],
+            token_balances: vec![INITIAL_BALANCE],
+        },
+    ]
+}
+
+/// The init argument a ledger provisioned from `spec` is installed with;
+/// matches the ledger's `(vec text, vec nat64)` service signature.
+fn install_arg(spec: &LedgerSpec) -> Vec<u8> {
+    Encode!(&spec.token_names, &spec.token_balances).unwrap()
+}
+
+/// The install operations a redeploy performs: one per existing ledger,
+/// against its current principal - a redeploy never creates canisters.
+fn redeploy_plan(ids: &[Principal], mode: CanisterInstallMode) -> Vec<(Principal, CanisterInstallMode)> {
+    ids.iter().map(|id| (*id, mode)).collect()
+}
+
+/// Install the bundled ledger WASM into an already-created canister.
+async fn install_ledger(
+    canister_id: Principal,
+    mode: CanisterInstallMode,
+    arg: Vec<u8>,
+) -> Result<(), String> {
+    let install_args = InstallCodeArgument {
+        mode,
+        canister_id,
+        wasm_module: WASM.to_vec(),
+        arg,
+    };
+    install_code(install_args).await.map_err(|(code, message)| {
+        format!(
+            "Installing ledger {} failed: {:?}: {}",
+            canister_id, code, message
+        )
+    })
+}
+
+/// Redeploy the bundled ledger WASM into the existing ledgers, keeping
+/// their principals. `Upgrade` keeps each ledger's state, provided the
+/// ledger saves it to stable memory across upgrades; `Reinstall` wipes
+/// it. Either way the ledger's init arguments are not re-sent: an
+/// upgraded ledger restores its tokens, a reinstalled one starts empty.
+pub async fn redeploy_ledgers(mode: CanisterInstallMode) {
+    for (canister_id, mode) in redeploy_plan(&get_canister_ids(), mode) {
+        install_ledger(canister_id, mode, Encode!().unwrap())
+            .await
+            .unwrap_or_else(|err| ic_cdk::trap(&err));
+        ic_cdk::println!("Redeployed ledger canister {}", canister_id);
+    }
+}
+
+// Inline wasm binary of the ledger canister.
+#[cfg(target_arch = "wasm32")]
+pub const WASM: &[u8] =
+    include_bytes!("../../target/wasm32-unknown-unknown/release/ledger.wasm.gz");
+#[cfg(not(target_arch = "wasm32"))]
+pub const WASM: &[u8] = &[];
+
+thread_local! {
+    // A list of canister IDs of the ledgers.
+    static CANISTER_IDS: Arc<RwLock<Vec<Principal>>> = Arc::new(RwLock::new(vec![]));
+}
+
+pub fn get_canister_ids() -> Vec<Principal> {
+    CANISTER_IDS.with(|canister_ids| canister_ids.read().unwrap().clone())
+}
+
+/// Restore the ledger canister IDs saved before an upgrade.
+pub fn set_canister_ids(ids: Vec<Principal>) {
+    CANISTER_IDS.with(|canister_ids| *canister_ids.write().unwrap() = ids);
+}
+
+/// One ledger's provisioning result: its principal on success, or - on
+/// failure - the canister that may already exist (created but never
+/// installed) together with the error that stopped the run.
+type ProvisionStep = Result<Principal, (Option<Principal>, String)>;
+
+/// Fold per-ledger provisioning steps into an overall outcome: every
+/// principal on success, or the canisters to roll back plus the error
+/// of the step that failed. A canister created by the failing step
+/// itself belongs to the rollback set, it never received its code.
+fn _provision_outcome(
+    steps: Vec<ProvisionStep>,
+) -> Result<Vec<Principal>, (Vec<Principal>, String)> {
+    let mut created = vec![];
+    for step in steps {
+        match step {
+            Ok(canister_id) => created.push(canister_id),
+            Err((orphan, error)) => {
+                created.extend(orphan);
+                return Err((created, error));
+            }
+        }
+    }
+    Ok(created)
+}
+
+/// Best-effort rollback of a failed provisioning run: stop and delete
+/// the given canisters so no orphans remain. A canister that refuses to
+/// die is logged and skipped - it is no worse off than before the
+/// rollback.
+async fn rollback_ledgers(canisters: &[Principal]) {
+    for canister_id in canisters {
+        let record = CanisterIdRecord {
+            canister_id: *canister_id,
+        };
+        if let Err((code, message)) = stop_canister(record).await {
+            ic_cdk::println!(
+                "Rollback: failed to stop {}: {:?}: {}",
+                canister_id,
+                code,
+                message
+            );
+            continue;
+        }
+        match delete_canister(record).await {
+            Ok(()) => ic_cdk::println!("Rolled back ledger canister {}", canister_id),
+            Err((code, message)) => ic_cdk::println!(
+                "Rollback: failed to delete {}: {:?}: {}",
+                canister_id,
+                code,
+                message
+            ),
+        }
+    }
+}
+
+/// Create one ledger canister per spec, all from the same WASM, each
+/// initialized with its own tokens. All-or-nothing: on any management
+/// canister failure the canisters created so far are deleted again and
+/// the error is returned; `CANISTER_IDS` only ever grows by a complete
+/// set, so the DEX never believes in a half-provisioned ledger layout.
+pub async fn create_ledgers_from_wasm(specs: &[LedgerSpec]) -> Result<Vec<Principal>, String> {
+    let mut steps: Vec<ProvisionStep> = vec![];
+    for spec in specs {
+        let create_args = CreateCanisterArgument {
+            settings: Some(CanisterSettings {
+                controllers: Some(vec![ic_cdk::id()]),
+                compute_allocation: Some(0u64.into()),
+                memory_allocation: Some(0u64.into()),
+                freezing_threshold: Some(0u64.into()),
+                reserved_cycles_limit: None,
+                log_visibility: None,
+                wasm_memory_limit: None,
+            }),
+        };
+
+        let step = match create_canister(create_args, 1_000_000_000_000).await {
+            Ok((record,)) => {
+                let canister_id = record.canister_id;
+                ic_cdk::println!("Created ledger canister {}", canister_id);
+                match install_ledger(canister_id, CanisterInstallMode::Install, install_arg(spec))
+                    .await
+                {
+                    Ok(()) => Ok(canister_id),
+                    Err(error) => Err((Some(canister_id), error)),
+                }
+            }
+            Err((code, message)) => Err((
+                None,
+                format!("Creating a ledger canister failed: {:?}: {}", code, message),
+            )),
+        };
+        let failed = step.is_err();
+        steps.push(step);
+        if failed {
+            break;
+        }
+    }
+    match _provision_outcome(steps) {
+        Ok(created) => {
+            CANISTER_IDS.with(|canister_ids| {
+                canister_ids.write().unwrap().extend(created.iter().copied());
+            });
+            Ok(created)
+        }
+        Err((orphans, error)) => {
+            rollback_ledgers(&orphans).await;
+            Err(error)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::Decode;
+
+    #[test]
+    fn test_custom_ledger_specs_produce_one_install_arg_each() {
+        let specs: Vec<LedgerSpec> = (0..3)
+            .map(|i| LedgerSpec {
+                token_names: vec![format!("TOK{}", i)],
+                token_balances: vec![100 * (i + 1)],
+            })
+            .collect();
+        assert_eq!(specs.len(), 3);
+        // Each ledger's install argument carries exactly its own tokens,
+        // in the shape the ledger's init expects.
+        for (i, spec) in specs.iter().enumerate() {
+            let arg = install_arg(spec);
+            let (names, balances) = Decode!(&arg, Vec<TokenName>, Vec<u64>).unwrap();
+            assert_eq!(names, vec![format!("TOK{}", i)]);
+            assert_eq!(balances, vec![100 * (i as u64 + 1)]);
+        }
+    }
+
+    #[test]
+    fn test_redeploy_targets_existing_principals_only() {
+        let ledgers = vec![Principal::from_slice(&[1]), Principal::from_slice(&[2])];
+        let plan = redeploy_plan(&ledgers, CanisterInstallMode::Upgrade(None));
+        // An upgrade touches exactly the known ledgers and keeps their
+        // principals; nothing is created.
+        assert_eq!(plan.len(), ledgers.len());
+        for ((canister, mode), expected) in plan.iter().zip(&ledgers) {
+            assert_eq!(canister, expected);
+            assert!(matches!(mode, CanisterInstallMode::Upgrade(_)));
+        }
+    }
+
+    #[test]
+    fn test_failed_install_rolls_back_created_canisters() {
+        let ledger1 = Principal::from_slice(&[1]);
+        let ledger2 = Principal::from_slice(&[2]);
+        // Second ledger was created but its install failed: the rollback
+        // set covers everything created so far, including that canister.
+        let steps: Vec<ProvisionStep> = vec![
+            Ok(ledger1),
+            Err((Some(ledger2), "install failed".to_string())),
+        ];
+        let (orphans, error) = _provision_outcome(steps).unwrap_err();
+        assert_eq!(orphans, vec![ledger1, ledger2]);
+        assert_eq!(error, "install failed");
+        // A failed create has no canister of its own to roll back.
+        let steps: Vec<ProvisionStep> =
+            vec![Ok(ledger1), Err((None, "create failed".to_string()))];
+        let (orphans, _) = _provision_outcome(steps).unwrap_err();
+        assert_eq!(orphans, vec![ledger1]);
+        // Only a run where every step succeeded registers its ledgers.
+        assert_eq!(
+            _provision_outcome(vec![Ok(ledger1), Ok(ledger2)]),
+            Ok(vec![ledger1, ledger2])
+        );
+    }
+
+    #[test]
+    fn test_default_specs_keep_the_demo_layout() {
+        let specs = default_ledger_specs();
+        assert_eq!(specs.len(), 2);
+        assert_eq!(specs[0].token_names, vec!["ICP", "USD"]);
+        assert_eq!(specs[1].token_names, vec!["EUR"]);
+    }
+}
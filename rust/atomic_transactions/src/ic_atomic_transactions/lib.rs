@@ -0,0 +1,643 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// The name of a token, the wire type shared by coordinator and
+/// participants so both sides always encode and decode the same thing.
+pub type TokenName = String;
+
+/// Globally unique transaction identifier: the coordinator's principal
+/// plus a nonce that is monotonic within that coordinator. Including the
+/// coordinator makes IDs collision-free even when participants are
+/// shared across several coordinators.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TransactionId {
+    pub coordinator: Principal,
+    pub nonce: u64,
+}
+
+impl TransactionId {
+    pub fn new(coordinator: Principal, nonce: u64) -> Self {
+        TransactionId { coordinator, nonce }
+    }
+}
+
+impl std::fmt::Display for TransactionId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}#{}", self.coordinator, self.nonce)
+    }
+}
+
+/// A signed balance change whose sign is fixed by its constructor: a
+/// `debit` is always negative, a `credit` always positive, so mixing the
+/// two up no longer compiles. On the wire it is a plain `int64` - the
+/// exact encoding of the bare `i64` the handlers historically exchanged
+/// - so coordinator and participants can adopt the type independently.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct BalanceDelta(i64);
+
+impl BalanceDelta {
+    /// A change removing `amount` base units from a balance.
+    pub fn debit(amount: u64) -> Self {
+        BalanceDelta(-i64::try_from(amount).expect("debit amount exceeds i64::MAX"))
+    }
+
+    /// A change adding `amount` base units to a balance.
+    pub fn credit(amount: u64) -> Self {
+        BalanceDelta(i64::try_from(amount).expect("credit amount exceeds i64::MAX"))
+    }
+
+    /// Wrap a signed wire amount, e.g. one received from a client still
+    /// speaking the bare-`i64` convention (negative = debit). The escape
+    /// hatch for the candid boundary; internal code wants the
+    /// constructors above.
+    pub fn from_signed(amount: i64) -> Self {
+        BalanceDelta(amount)
+    }
+
+    /// The signed wire representation: negative for a debit.
+    pub fn as_i64(&self) -> i64 {
+        self.0
+    }
+}
+
+impl CandidType for BalanceDelta {
+    fn _ty() -> candid::types::Type {
+        i64::_ty()
+    }
+
+    fn idl_serialize<S>(&self, serializer: S) -> Result<(), S::Error>
+    where
+        S: candid::types::Serializer,
+    {
+        self.0.idl_serialize(serializer)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for BalanceDelta {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        i64::deserialize(deserializer).map(BalanceDelta)
+    }
+}
+
+impl std::fmt::Display for BalanceDelta {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Generous default for `max_transaction_payload_bytes`: current swap
+/// payloads are a few dozen bytes each.
+pub const DEFAULT_MAX_TRANSACTION_PAYLOAD_BYTES: u64 = 1 << 20;
+
+/// Default for `first_retry_grace_ns`, matching the coordinator's
+/// regular rate limit.
+pub const DEFAULT_FIRST_RETRY_GRACE_NS: u64 = 5_000_000_000;
+
+/// Default for `idle_stop_after_ticks`: stop the timer after ten
+/// consecutive seconds without an active transaction.
+pub const DEFAULT_IDLE_STOP_AFTER_TICKS: u64 = 10;
+
+/// Default for `token_cache_ttl_ns`: the token set changes rarely, so
+/// one minute of staleness is acceptable for front-ends.
+pub const DEFAULT_TOKEN_CACHE_TTL_NS: u64 = 60_000_000_000;
+
+/// Default for `max_inflight_per_participant`: generous enough for the
+/// current sequential call pattern, yet a real bound once calls are
+/// issued concurrently.
+pub const DEFAULT_MAX_INFLIGHT_PER_PARTICIPANT: u64 = 4;
+
+/// Default for `lease_ns`: five minutes is far longer than any healthy
+/// coordinator takes to commit or abort, so only truly stale prepares
+/// get released.
+pub const DEFAULT_LEASE_NS: u64 = 300_000_000_000;
+
+/// Default for `max_active_transactions`: generous for a demo, but
+/// still a hard bound on coordinator memory and per-tick timer work.
+pub const DEFAULT_MAX_ACTIVE_TRANSACTIONS: u64 = 1_000;
+
+/// How the coordinator issues prepare calls to the participants.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PrepareCallMode {
+    /// Prepare is an update call that locks the resource: classic
+    /// two-phase commit.
+    #[default]
+    Update,
+    /// Prepare is a read-mostly query that only checks whether the
+    /// change is applicable and reserves nothing. Cheaper, but the "yes"
+    /// vote guarantees nothing: it must be paired with an update at
+    /// commit that revalidates and applies the change in one message.
+    Query,
+}
+
+/// Configuration of a canister: resource bounds, plus switches used to
+/// simulate malicious or misbehaving participants in tests.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Configuration {
+    /// Never return from the prepare call (the participant holds on to
+    /// the message forever by repeatedly calling itself).
+    pub infinite_prepare: bool,
+    /// Vote "no" on every prepare request.
+    pub stop_on_prepare: bool,
+    /// Wait this long before answering a prepare, simulating a
+    /// slow-but-honest participant. `0` answers immediately.
+    pub prepare_delay_ns: u64,
+    /// Also emit the colored, human-oriented log lines next to the
+    /// structured JSON records. On by default for the interactive demo;
+    /// log pipelines want it off and parse only the JSON.
+    pub colored_logs: bool,
+    /// Trap on the next N commit requests before honoring them, to
+    /// exercise the coordinator's commit retry loop. Decremented on
+    /// every injected failure; `0` disables the fault.
+    pub fail_commit_times: u32,
+    /// Maximum total payload bytes a single transaction may carry; the
+    /// coordinator refuses to create larger transactions.
+    pub max_transaction_payload_bytes: u64,
+    /// How prepare calls are issued for newly created transactions.
+    pub prepare_call_mode: PrepareCallMode,
+    /// How long the coordinator waits before the first retry of a
+    /// prepare. Tunable independently of the regular rate limit: fast
+    /// local swaps want a shorter wait, cross-subnet ones a longer one.
+    pub first_retry_grace_ns: u64,
+    /// Stop re-arming the coordinator's timer after this many consecutive
+    /// ticks without an active transaction; it is re-armed when a new
+    /// transaction arrives. `0` keeps the timer running forever.
+    pub idle_stop_after_ticks: u64,
+    /// Use optimistic locking on the participant: prepare records the
+    /// intended change and the resource's version instead of locking,
+    /// and commit applies it only if the version is still unchanged.
+    /// Higher concurrency for low-contention resources, at the cost of
+    /// possible commit-time conflicts.
+    pub optimistic_locking: bool,
+    /// How long the coordinator's aggregated token listing stays fresh
+    /// before `list_all_tokens` queries the participants again.
+    pub token_cache_ttl_ns: u64,
+    /// Maximum outstanding (issued but not yet replied) calls the
+    /// coordinator keeps toward a single participant; further calls are
+    /// deferred to the next tick. Backpressure toward slow participants.
+    pub max_inflight_per_participant: u64,
+    /// How long a participant holds a prepared lock before treating it
+    /// as stale and releasable. Protects resources against a coordinator
+    /// that dies between prepare and commit. `0` disables the lease.
+    pub lease_ns: u64,
+    /// Maximum unfinished transactions the coordinator accepts at once;
+    /// further swap submissions are rejected until some finalize.
+    pub max_active_transactions: u64,
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Configuration {
+            infinite_prepare: false,
+            stop_on_prepare: false,
+            prepare_delay_ns: 0,
+            colored_logs: true,
+            fail_commit_times: 0,
+            max_transaction_payload_bytes: DEFAULT_MAX_TRANSACTION_PAYLOAD_BYTES,
+            prepare_call_mode: PrepareCallMode::default(),
+            first_retry_grace_ns: DEFAULT_FIRST_RETRY_GRACE_NS,
+            idle_stop_after_ticks: DEFAULT_IDLE_STOP_AFTER_TICKS,
+            optimistic_locking: false,
+            token_cache_ttl_ns: DEFAULT_TOKEN_CACHE_TTL_NS,
+            max_inflight_per_participant: DEFAULT_MAX_INFLIGHT_PER_PARTICIPANT,
+            lease_ns: DEFAULT_LEASE_NS,
+            max_active_transactions: DEFAULT_MAX_ACTIVE_TRANSACTIONS,
+        }
+    }
+}
+
+/// Why a prepare request was rejected outright, so a client can tell
+/// "not enough balance" from "balance too large" from "no such token".
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PrepareError {
+    /// A debit larger than the resource's current value.
+    InsufficientBalance,
+    /// A credit that would push the resource past its maximum value.
+    Overflow,
+    /// The resource does not exist on this ledger.
+    UnknownToken,
+    /// A zero change: committing it would do nothing, so locking the
+    /// resource for it only causes needless contention.
+    NoOp,
+    /// Any other permanent rejection, e.g. an envelope of the wrong
+    /// phase or a participant configured to vote "no".
+    Rejected,
+}
+
+/// Outcome of a prepare request.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PrepareVote {
+    Yes,
+    /// Permanent rejection, with the reason. Retrying the transaction
+    /// will not help.
+    No(PrepareError),
+    /// Transient rejection: another transaction holds a still-valid lock
+    /// on the resource. A later attempt may succeed.
+    Busy,
+    /// The resource is administratively frozen. Permanent until an
+    /// operator unfreezes it, so retrying does not help.
+    TokenFrozen,
+}
+
+impl From<bool> for PrepareVote {
+    fn from(vote: bool) -> Self {
+        if vote {
+            PrepareVote::Yes
+        } else {
+            PrepareVote::No(PrepareError::Rejected)
+        }
+    }
+}
+
+/// Phase of the two-phase commit protocol a call belongs to.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    Prepare,
+    Abort,
+    Commit,
+}
+
+/// Self-describing wrapper around the application arguments of one
+/// coordinator call.
+///
+/// Carrying the transaction ID and phase alongside the candid-encoded
+/// arguments lets a participant verify that a payload really belongs to
+/// the method it arrived at, so a commit payload reaching the prepare
+/// handler is rejected instead of acted on. The trace ID correlates the
+/// log entries of one transaction across canisters.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Envelope {
+    pub tid: TransactionId,
+    pub phase: Phase,
+    pub trace_id: u64,
+    /// Candid-encoded application arguments.
+    pub args: Vec<u8>,
+}
+
+impl Envelope {
+    pub fn new(tid: TransactionId, phase: Phase, trace_id: u64, args: Vec<u8>) -> Self {
+        Envelope {
+            tid,
+            phase,
+            trace_id,
+            args,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        Encode!(self).unwrap()
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<Envelope, candid::Error> {
+        Decode!(payload, Envelope)
+    }
+
+    /// True if this envelope belongs to the given phase. Handlers must
+    /// refuse to act on an envelope of the wrong phase.
+    pub fn matches_phase(&self, phase: Phase) -> bool {
+        self.phase == phase
+    }
+}
+
+/// State of a single resource as seen by the participant.
+///
+/// A resource that voted "yes" on a prepare request is locked for the
+/// given transaction until the coordinator either commits or aborts it.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum TransactionStatus {
+    Prepared(TransactionId),
+    Aborted,
+    Comitted,
+}
+
+/// Generic participant-side state of the two-phase commit protocol.
+///
+/// Tracks for each resource whether it is currently locked by a
+/// transaction. The resource type is generic, the ledger canister uses
+/// its token names as resources.
+#[derive(Default)]
+pub struct TwoPhaseCommitState<ResourceId: Ord> {
+    pub state: BTreeMap<ResourceId, TransactionStatus>,
+    /// Client-supplied deadline per locked resource. A lock whose
+    /// deadline has passed is treated as released, mirroring the
+    /// coordinator aborting the transaction at the same timestamp.
+    pub valid_until: BTreeMap<ResourceId, u64>,
+    /// When each currently held lock was taken, for lock-age
+    /// observability.
+    pub locked_since: BTreeMap<ResourceId, u64>,
+    /// Log of honored aborts, so a retried abort is answered as an
+    /// idempotent success and an aborted transaction can never re-lock
+    /// the resource it was aborted on.
+    pub aborted: BTreeSet<(ResourceId, TransactionId)>,
+    pub configuration: Configuration,
+}
+
+impl<ResourceId: Ord + Clone> TwoPhaseCommitState<ResourceId> {
+    /// Try to lock the given resource for the given transaction, with an
+    /// optional deadline after which the lock auto-releases.
+    ///
+    /// Returns `true` if the lock was acquired, i.e. the resource was
+    /// not already locked by a different transaction whose lock is still
+    /// valid.
+    pub fn prepare_transaction(
+        &mut self,
+        tid: TransactionId,
+        resource: &ResourceId,
+        valid_until_ns: Option<u64>,
+        now: u64,
+    ) -> bool {
+        // A transaction that was already aborted on this resource must
+        // never re-lock it, even if the abort and a late prepare retry
+        // arrive out of order.
+        if self.aborted.contains(&(resource.clone(), tid)) {
+            return false;
+        }
+        match self.state.get(resource) {
+            Some(TransactionStatus::Prepared(other_tid)) => {
+                // Re-preparing for the same transaction is okay, another
+                // transaction holding a still-valid lock is not.
+                if *other_tid == tid {
+                    true
+                } else if self.lock_expired(resource, now) {
+                    self.take_lock(tid, resource, valid_until_ns, now);
+                    true
+                } else {
+                    false
+                }
+            }
+            _ => {
+                self.take_lock(tid, resource, valid_until_ns, now);
+                true
+            }
+        }
+    }
+
+    fn take_lock(
+        &mut self,
+        tid: TransactionId,
+        resource: &ResourceId,
+        valid_until_ns: Option<u64>,
+        now: u64,
+    ) {
+        self.state
+            .insert(resource.clone(), TransactionStatus::Prepared(tid));
+        self.locked_since.insert(resource.clone(), now);
+        match valid_until_ns {
+            Some(deadline) => self.valid_until.insert(resource.clone(), deadline),
+            None => self.valid_until.remove(resource),
+        };
+    }
+
+    /// How long the lock on the given resource has been held, or `None`
+    /// if the resource is not locked or the lock has expired.
+    pub fn lock_age(&self, resource: &ResourceId, now: u64) -> Option<u64> {
+        match self.state.get(resource) {
+            Some(TransactionStatus::Prepared(_)) if !self.lock_expired(resource, now) => self
+                .locked_since
+                .get(resource)
+                .map(|since| now.saturating_sub(*since)),
+            _ => None,
+        }
+    }
+
+    /// True if the lock on the given resource has a deadline that lies in
+    /// the past, or has been held longer than the configured lease.
+    pub fn lock_expired(&self, resource: &ResourceId, now: u64) -> bool {
+        let deadline_passed = self
+            .valid_until
+            .get(resource)
+            .is_some_and(|deadline| now > *deadline);
+        let lease_ns = self.configuration.lease_ns;
+        let lease_passed = lease_ns != 0
+            && self
+                .locked_since
+                .get(resource)
+                .is_some_and(|since| now > since.saturating_add(lease_ns));
+        deadline_passed || lease_passed
+    }
+
+    /// Consume one injected commit fault, if any are configured.
+    ///
+    /// Returns `true` if the caller should fail this commit attempt;
+    /// after `fail_commit_times` failures the fault is used up and
+    /// commits go through again.
+    pub fn take_commit_fault(&mut self) -> bool {
+        if self.configuration.fail_commit_times > 0 {
+            self.configuration.fail_commit_times -= 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Release the lock on the given resource if it is held by the given
+    /// transaction.
+    ///
+    /// Returns `true` if the lock was released now or the abort was
+    /// already honored earlier, so a coordinator retrying an abort gets
+    /// an idempotent success.
+    pub fn abort_transaction(&mut self, tid: TransactionId, resource: &ResourceId) -> bool {
+        if let Some(TransactionStatus::Prepared(prepared_tid)) = self.state.get(resource) {
+            if *prepared_tid == tid {
+                self.state
+                    .insert(resource.clone(), TransactionStatus::Aborted);
+                self.valid_until.remove(resource);
+                self.locked_since.remove(resource);
+                self.aborted.insert((resource.clone(), tid));
+                return true;
+            }
+        }
+        self.aborted.contains(&(resource.clone(), tid))
+    }
+
+    /// Mark the given resource as committed for the given transaction.
+    ///
+    /// Panics if the resource is not prepared for this transaction:
+    /// a coordinator must only commit after a successful prepare.
+    // XXX - This is not idempotent: a retried commit for an already
+    // committed transaction will trap.
+    pub fn commit_transaction(&mut self, tid: TransactionId, resource: &ResourceId) {
+        assert_eq!(
+            self.state.get(resource),
+            Some(&TransactionStatus::Prepared(tid))
+        );
+        self.state
+            .insert(resource.clone(), TransactionStatus::Comitted);
+        self.valid_until.remove(resource);
+        self.locked_since.remove(resource);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tid(nonce: u64) -> TransactionId {
+        TransactionId::new(Principal::anonymous(), nonce)
+    }
+
+    #[test]
+    fn test_wire_types_round_trip_between_canisters() {
+        // The coordinator encodes a swap leg with the shared types...
+        let bytes = Encode!(&TokenName::from("ICP"), &-1337_i64).unwrap();
+        // ...and the participant decodes it with the very same ones, so
+        // the Candid encodings line up by construction.
+        let (token, amount) = Decode!(&bytes, TokenName, i64).unwrap();
+        assert_eq!(token, "ICP");
+        assert_eq!(amount, -1337);
+
+        let bytes = Encode!(&TransactionStatus::Prepared(tid(1))).unwrap();
+        assert_eq!(
+            Decode!(&bytes, TransactionStatus).unwrap(),
+            TransactionStatus::Prepared(tid(1))
+        );
+        let bytes = Encode!(&tid(9)).unwrap();
+        assert_eq!(Decode!(&bytes, TransactionId).unwrap(), tid(9));
+    }
+
+    #[test]
+    fn test_balance_delta_signs_and_wire_compatibility() {
+        assert_eq!(BalanceDelta::debit(1337).as_i64(), -1337);
+        assert_eq!(BalanceDelta::credit(42).as_i64(), 42);
+        // On the wire a delta is a bare `int64`: a participant still
+        // decoding `i64` sees the historical encoding...
+        let bytes = Encode!(&TokenName::from("ICP"), &BalanceDelta::debit(1337)).unwrap();
+        let (token, amount) = Decode!(&bytes, TokenName, i64).unwrap();
+        assert_eq!(token, "ICP");
+        assert_eq!(amount, -1337);
+        // ...and a typed participant decodes what an untyped coordinator
+        // encoded.
+        let bytes = Encode!(&-1337_i64).unwrap();
+        assert_eq!(
+            Decode!(&bytes, BalanceDelta).unwrap(),
+            BalanceDelta::debit(1337)
+        );
+    }
+
+    #[test]
+    fn test_envelope_roundtrip() {
+        let args = Encode!(&"ICP".to_string(), &-1337_i64).unwrap();
+        let envelope = Envelope::new(tid(3), Phase::Prepare, 42, args.clone());
+        let decoded = Envelope::decode(&envelope.encode()).unwrap();
+        assert_eq!(decoded.tid, tid(3));
+        assert_eq!(decoded.trace_id, 42);
+        assert_eq!(decoded.args, args);
+        assert!(decoded.matches_phase(Phase::Prepare));
+    }
+
+    #[test]
+    fn test_envelope_phase_mismatch_is_detected() {
+        let envelope = Envelope::new(tid(3), Phase::Commit, 42, vec![]);
+        // A commit payload must not be accepted by the prepare handler.
+        assert!(!envelope.matches_phase(Phase::Prepare));
+        assert!(!envelope.matches_phase(Phase::Abort));
+    }
+
+    #[test]
+    fn test_prepare_locks_resource() {
+        let mut state = TwoPhaseCommitState::default();
+        assert!(state.prepare_transaction(tid(1), &"ICP".to_string(), None, 0));
+        // A different transaction cannot lock the same resource.
+        assert!(!state.prepare_transaction(tid(2), &"ICP".to_string(), None, 0));
+        // The same transaction can re-prepare.
+        assert!(state.prepare_transaction(tid(1), &"ICP".to_string(), None, 0));
+    }
+
+    #[test]
+    fn test_abort_releases_lock() {
+        let mut state = TwoPhaseCommitState::default();
+        assert!(state.prepare_transaction(tid(1), &"ICP".to_string(), None, 0));
+        state.abort_transaction(tid(1), &"ICP".to_string());
+        assert!(state.prepare_transaction(tid(2), &"ICP".to_string(), None, 0));
+    }
+
+    #[test]
+    fn test_lock_age_tracks_held_locks() {
+        let mut state = TwoPhaseCommitState::default();
+        assert!(state.prepare_transaction(tid(1), &"ICP".to_string(), Some(500), 100));
+        assert_eq!(state.lock_age(&"ICP".to_string(), 250), Some(150));
+        // An expired lock no longer counts as held.
+        assert_eq!(state.lock_age(&"ICP".to_string(), 600), None);
+        state.abort_transaction(tid(1), &"ICP".to_string());
+        assert_eq!(state.lock_age(&"ICP".to_string(), 250), None);
+    }
+
+    #[test]
+    fn test_expired_lock_auto_releases() {
+        let mut state = TwoPhaseCommitState::default();
+        assert!(state.prepare_transaction(tid(1), &"ICP".to_string(), Some(100), 0));
+        // Before the deadline, the lock holds.
+        assert!(!state.prepare_transaction(tid(2), &"ICP".to_string(), None, 100));
+        // After the deadline, the lock is treated as released.
+        assert!(state.prepare_transaction(tid(2), &"ICP".to_string(), None, 101));
+        assert_eq!(
+            state.state.get("ICP"),
+            Some(&TransactionStatus::Prepared(tid(2)))
+        );
+    }
+
+    #[test]
+    fn test_stale_prepare_auto_releases_after_lease() {
+        let mut state = TwoPhaseCommitState::default();
+        // No client-supplied deadline: only the lease bounds the lock.
+        assert!(state.prepare_transaction(tid(1), &"ICP".to_string(), None, 0));
+        // Within the lease, the lock holds.
+        assert!(!state.prepare_transaction(tid(2), &"ICP".to_string(), None, DEFAULT_LEASE_NS));
+        // Past the lease, the stale prepare is treated as released.
+        assert!(state.prepare_transaction(tid(2), &"ICP".to_string(), None, DEFAULT_LEASE_NS + 1));
+        assert_eq!(
+            state.state.get("ICP"),
+            Some(&TransactionStatus::Prepared(tid(2)))
+        );
+    }
+
+    #[test]
+    fn test_zero_lease_disables_auto_release() {
+        let mut state = TwoPhaseCommitState::default();
+        state.configuration.lease_ns = 0;
+        assert!(state.prepare_transaction(tid(1), &"ICP".to_string(), None, 0));
+        assert!(!state.prepare_transaction(tid(2), &"ICP".to_string(), None, u64::MAX));
+    }
+
+    #[test]
+    fn test_ids_of_two_coordinators_never_collide() {
+        let coordinator_a = Principal::from_slice(&[1]);
+        let coordinator_b = Principal::from_slice(&[2]);
+        // Both coordinators hand out the same nonce, but the resulting
+        // ids are distinct because the coordinator is part of the id.
+        let tid_a = TransactionId::new(coordinator_a, 1);
+        let tid_b = TransactionId::new(coordinator_b, 1);
+        assert_ne!(tid_a, tid_b);
+
+        let mut state = TwoPhaseCommitState::default();
+        assert!(state.prepare_transaction(tid_a, &"ICP".to_string(), None, 0));
+        // B's transaction must not be mistaken for A's, so its prepare
+        // for the same resource is refused rather than treated as a
+        // duplicate of the existing lock.
+        assert!(!state.prepare_transaction(tid_b, &"ICP".to_string(), None, 0));
+        // Only the full id, including the coordinator, may commit.
+        state.commit_transaction(tid_a, &"ICP".to_string());
+    }
+
+    #[test]
+    fn test_abort_is_idempotent_and_sticky() {
+        let mut state = TwoPhaseCommitState::default();
+        assert!(state.prepare_transaction(tid(1), &"ICP".to_string(), None, 0));
+        assert!(state.abort_transaction(tid(1), &"ICP".to_string()));
+        // A retried abort is confirmed from the abort log.
+        assert!(state.abort_transaction(tid(1), &"ICP".to_string()));
+        // A late prepare retry for the aborted transaction must not
+        // re-lock the resource.
+        assert!(!state.prepare_transaction(tid(1), &"ICP".to_string(), None, 0));
+        assert_eq!(
+            state.state.get("ICP"),
+            Some(&TransactionStatus::Aborted)
+        );
+        // An abort for a transaction that never prepared is no success.
+        assert!(!state.abort_transaction(tid(3), &"ICP".to_string()));
+        // A different transaction may still take the released resource.
+        assert!(state.prepare_transaction(tid(2), &"ICP".to_string(), None, 0));
+    }
+}
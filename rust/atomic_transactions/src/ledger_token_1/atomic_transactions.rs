@@ -0,0 +1,727 @@
+use crate::{with_resources, with_resources_mut, with_state_mut, TokenName};
+use candid::Principal;
+use ic_atomic_transactions::{BalanceDelta, PrepareError, PrepareVote, TransactionId};
+use ic_cdk::api::call::call;
+use std::cell::RefCell;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Default maximum recursion depth of `call_forever`.
+const MAX_CALL_FOREVER_DEPTH: u64 = 50;
+/// Default number of instructions to burn at each level of
+/// `call_forever`.
+const INSTRUCTIONS_PER_LEVEL: u64 = 1_000_000_000;
+
+/// Bookkeeping for a transaction this ledger has voted "yes" on.
+#[allow(dead_code)]
+pub struct PreparedTransaction {
+    pub tid: TransactionId,
+    /// The principal that sent the prepare request; its account receives
+    /// the committed change.
+    pub owner: Principal,
+}
+
+thread_local! {
+    static PREPARED_TRANSACTIONS: RefCell<BTreeMap<TokenName, PreparedTransaction>> =
+        const { RefCell::new(BTreeMap::new()) };
+    /// Tokens an operator has frozen: new prepares are rejected, but
+    /// in-flight commits and aborts still complete.
+    static FROZEN_TOKENS: RefCell<BTreeSet<TokenName>> = const { RefCell::new(BTreeSet::new()) };
+    /// Set when an operator asked a running `call_forever` simulation to
+    /// stop; checked and cleared at the top of every level.
+    static STOP_CALL_FOREVER: RefCell<bool> = const { RefCell::new(false) };
+    /// Per-resource version counter for optimistic locking, bumped on
+    /// every committed change.
+    static VERSIONS: RefCell<BTreeMap<TokenName, u64>> = const { RefCell::new(BTreeMap::new()) };
+    /// Intents recorded by optimistic prepares: the resource version
+    /// each transaction saw when it validated its change.
+    static OPTIMISTIC_INTENTS: RefCell<BTreeMap<(TokenName, TransactionId), u64>> =
+        const { RefCell::new(BTreeMap::new()) };
+    /// Log of `(resource, tid)` pairs whose commit was already applied,
+    /// making retried commits a no-op instead of a trap.
+    static COMMITTED_TRANSACTIONS: RefCell<BTreeSet<(TokenName, TransactionId)>> =
+        const { RefCell::new(BTreeSet::new()) };
+    /// Per-token display decimals. Balances and 2PC deltas stay in base
+    /// units throughout; the decimals only tell clients where to place
+    /// the point when rendering a balance.
+    static DECIMALS: RefCell<BTreeMap<TokenName, u8>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+/// Decimals reported for tokens that were never configured: this
+/// ledger's balances historically are whole units.
+pub const DEFAULT_DECIMALS: u8 = 0;
+
+/// Check whether the given change is applicable to the given resource,
+/// classifying a rejection: an unknown resource, a debit exceeding the
+/// current value, or a credit past the resource's maximum.
+fn check_change(resource: &TokenName, balance_change: i64) -> Option<PrepareError> {
+    // A zero change would lock the resource for a commit that does
+    // nothing; reject it before it can cause contention.
+    if balance_change == 0 {
+        return Some(PrepareError::NoOp);
+    }
+    with_resources(|resources| match resources.get(resource) {
+        Some(res) if res.prepare(balance_change) => None,
+        Some(_) if balance_change < 0 => Some(PrepareError::InsufficientBalance),
+        Some(_) => Some(PrepareError::Overflow),
+        None => Some(PrepareError::UnknownToken),
+    })
+}
+
+/// Optimistic prepare: validate the change and remember the resource's
+/// current version, without locking anything. Several transactions may
+/// hold intents on the same resource concurrently; conflicts only
+/// surface at commit time.
+pub fn prepare_optimistic(tid: TransactionId, resource: TokenName, balance_change: i64) -> PrepareVote {
+    if token_frozen(&resource) {
+        ic_cdk::println!("Token {} is frozen, rejecting prepare", resource);
+        return PrepareVote::TokenFrozen;
+    }
+    if let Some(error) = check_change(&resource, balance_change) {
+        return PrepareVote::No(error);
+    }
+    let version = VERSIONS.with(|versions| *versions.borrow().get(&resource).unwrap_or(&0));
+    OPTIMISTIC_INTENTS.with(|intents| intents.borrow_mut().insert((resource, tid), version));
+    PrepareVote::Yes
+}
+
+/// Optimistic commit: apply the intended change only if no other commit
+/// changed the resource since the prepare (compare-and-swap on the
+/// version counter). Returns `false` on a conflict, upon which the
+/// coordinator aborts the transaction.
+pub fn commit_optimistic(tid: TransactionId, resource: TokenName, balance_change: i64) -> bool {
+    let prepared_version = OPTIMISTIC_INTENTS
+        .with(|intents| intents.borrow_mut().remove(&(resource.clone(), tid)));
+    let Some(prepared_version) = prepared_version else {
+        ic_cdk::println!("Optimistic commit without an intent for token {}", resource);
+        return false;
+    };
+    let conflict = VERSIONS.with(|versions| {
+        let mut versions = versions.borrow_mut();
+        let current = versions.entry(resource.clone()).or_insert(0);
+        if *current != prepared_version {
+            return true;
+        }
+        *current += 1;
+        false
+    });
+    if conflict {
+        ic_cdk::println!(
+            "Optimistic commit conflict for transaction {} on token {}",
+            tid,
+            resource
+        );
+        return false;
+    }
+    // The version is unchanged, so the applicability check from the
+    // prepare still holds.
+    with_resources_mut(|resources| {
+        resources
+            .get_mut(&resource)
+            .expect("commit for unknown resource")
+            .commit(balance_change);
+    });
+    true
+}
+
+/// Drop the optimistic intent of an aborted transaction. Safe to call
+/// multiple times.
+pub fn abort_optimistic(tid: TransactionId, resource: TokenName) {
+    OPTIMISTIC_INTENTS.with(|intents| intents.borrow_mut().remove(&(resource, tid)));
+}
+
+/// A future completed by a one-shot timer, see `sleep`.
+struct Sleep {
+    shared: Rc<RefCell<SleepState>>,
+}
+
+#[derive(Default)]
+struct SleepState {
+    fired: bool,
+    waker: Option<Waker>,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.shared.borrow_mut();
+        if state.fired {
+            Poll::Ready(())
+        } else {
+            state.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+/// Await a real, timer-based delay: the future completes when the
+/// one-shot timer fires and wakes it. Used to simulate a
+/// slow-but-honest participant, see `Configuration::prepare_delay_ns`.
+pub async fn sleep(duration: Duration) {
+    let shared = Rc::new(RefCell::new(SleepState::default()));
+    let timer_shared = Rc::clone(&shared);
+    ic_cdk_timers::set_timer(duration, move || {
+        let mut state = timer_shared.borrow_mut();
+        state.fired = true;
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    });
+    Sleep { shared }.await
+}
+
+/// Ask a running `call_forever` simulation to stop at its next level,
+/// instead of waiting out the full recursion depth.
+pub fn stop_call_forever() {
+    STOP_CALL_FOREVER.with(|stop| *stop.borrow_mut() = true);
+}
+
+/// Freeze or unfreeze the given token. A frozen token rejects every new
+/// prepare with `TokenFrozen` until it is unfrozen again.
+pub fn set_token_frozen(token: TokenName, frozen: bool) {
+    FROZEN_TOKENS.with(|tokens| {
+        if frozen {
+            tokens.borrow_mut().insert(token);
+        } else {
+            tokens.borrow_mut().remove(&token);
+        }
+    });
+}
+
+/// True if an operator has frozen the given token.
+pub fn token_frozen(token: &TokenName) -> bool {
+    FROZEN_TOKENS.with(|tokens| tokens.borrow().contains(token))
+}
+
+/// Configure how many decimals the given token's base units carry.
+/// Purely presentational: no stored balance or in-flight transaction
+/// is rescaled, clients divide by `10^decimals` for display.
+pub fn set_token_decimals(token: TokenName, decimals: u8) {
+    DECIMALS.with(|all| {
+        all.borrow_mut().insert(token, decimals);
+    });
+}
+
+/// The display decimals of the given token, `DEFAULT_DECIMALS` if never
+/// configured.
+pub fn token_decimals(token: &TokenName) -> u8 {
+    DECIMALS.with(|all| all.borrow().get(token).copied().unwrap_or(DEFAULT_DECIMALS))
+}
+
+/// Check whether the given change can be applied to the given resource
+/// and, if so, lock the resource for the given transaction, honoring an
+/// optional deadline after which the lock auto-releases.
+///
+/// Votes `No` for permanent problems (unknown resource, a change the
+/// resource refuses), `TokenFrozen` for frozen resources and `Busy` if
+/// another transaction holds a still-valid lock.
+pub fn prepare_balance(
+    tid: TransactionId,
+    resource: TokenName,
+    balance_change: BalanceDelta,
+    valid_until_ns: Option<u64>,
+    now: u64,
+    owner: Principal,
+) -> PrepareVote {
+    prepare_balances(
+        tid,
+        &[(resource, balance_change.as_i64())],
+        valid_until_ns,
+        now,
+        owner,
+    )
+}
+
+/// Atomically evaluate several balance changes for one transaction on
+/// this ledger: vote "yes" only if every change is applicable and every
+/// token could be locked. Locks taken for a failing vote are released
+/// again, so a rejected batch leaves no token locked. This is the core
+/// prepare path; the single-resource `prepare_balance` is a thin
+/// wrapper around it.
+pub fn prepare_balances(
+    tid: TransactionId,
+    changes: &[(TokenName, i64)],
+    valid_until_ns: Option<u64>,
+    now: u64,
+    owner: Principal,
+) -> PrepareVote {
+    let mut locked: Vec<TokenName> = vec![];
+    let release = |locked: &[TokenName]| {
+        for resource in locked {
+            with_state_mut(|state| state.abort_transaction(tid, resource));
+            PREPARED_TRANSACTIONS.with(|prepared| prepared.borrow_mut().remove(resource));
+        }
+        ic_cdk::println!("Batched prepare for transaction {} rejected", tid);
+    };
+    for (resource, balance_change) in changes {
+        if token_frozen(resource) {
+            ic_cdk::println!("Token {} is frozen, rejecting prepare", resource);
+            release(&locked);
+            return PrepareVote::TokenFrozen;
+        }
+        if let Some(error) = check_change(resource, *balance_change) {
+            ic_cdk::println!(
+                "Change {} cannot be applied to resource {}: {:?}",
+                balance_change,
+                resource,
+                error
+            );
+            release(&locked);
+            return PrepareVote::No(error);
+        }
+        let lock_taken =
+            with_state_mut(|state| state.prepare_transaction(tid, resource, valid_until_ns, now));
+        if !lock_taken {
+            ic_cdk::println!("Token {} already locked by another transaction", resource);
+            release(&locked);
+            return PrepareVote::Busy;
+        }
+        PREPARED_TRANSACTIONS.with(|prepared| {
+            prepared
+                .borrow_mut()
+                .insert(resource.clone(), PreparedTransaction { tid, owner })
+        });
+        ic_cdk::println!("Prepared transaction {} for token {}", tid, resource);
+        locked.push(resource.clone());
+    }
+    PrepareVote::Yes
+}
+
+/// Read-only prepare check for query-mode transactions: report whether
+/// the balance change could be applied right now, without reserving
+/// anything. A `Yes` vote guarantees nothing; the coordinator must pair
+/// it with `commit_unprepared`, which revalidates before applying.
+pub fn prepare_balance_query(
+    tid: TransactionId,
+    resource: &TokenName,
+    balance_change: i64,
+    now: u64,
+) -> PrepareVote {
+    if token_frozen(resource) {
+        return PrepareVote::TokenFrozen;
+    }
+    if let Some(error) = check_change(resource, balance_change) {
+        return PrepareVote::No(error);
+    }
+    let locked_by_other = crate::with_state(|state| {
+        matches!(
+            state.state.get(resource),
+            Some(ic_atomic_transactions::TransactionStatus::Prepared(other_tid))
+                if *other_tid != tid && !state.lock_expired(resource, now)
+        )
+    });
+    if locked_by_other {
+        PrepareVote::Busy
+    } else {
+        PrepareVote::Yes
+    }
+}
+
+/// Lock and apply a balance change in one message, for transactions
+/// whose prepare went out as a query and therefore reserved nothing.
+/// Returns `false` without changing anything if the change is no longer
+/// applicable.
+pub fn commit_unprepared(
+    tid: TransactionId,
+    resource: TokenName,
+    balance_change: BalanceDelta,
+    now: u64,
+    owner: Principal,
+) -> bool {
+    if prepare_balance(tid, resource.clone(), balance_change, None, now, owner)
+        != PrepareVote::Yes
+    {
+        return false;
+    }
+    commit_balance(tid, resource, balance_change);
+    true
+}
+
+/// Apply the change of a committed transaction to its resource. The
+/// coordinator retries commits until they are acknowledged, so a commit
+/// that was already applied for this `(resource, tid)` pair is a no-op
+/// success rather than a trap.
+///
+/// Panics if the transaction was not prepared for this resource.
+pub fn commit_balance(tid: TransactionId, resource: TokenName, balance_change: BalanceDelta) {
+    let already_committed = COMMITTED_TRANSACTIONS
+        .with(|committed| !committed.borrow_mut().insert((resource.clone(), tid)));
+    if already_committed {
+        ic_cdk::println!(
+            "Transaction {} already committed for token {} - ignoring retry",
+            tid,
+            resource
+        );
+        return;
+    }
+    with_state_mut(|state| state.commit_transaction(tid, &resource));
+    with_resources_mut(|resources| {
+        resources
+            .get_mut(&resource)
+            .expect("commit for unknown resource")
+            // Cannot fail, prepare already checked the change applies.
+            .commit(balance_change.as_i64());
+    });
+    // Mirror the change onto the account of the principal that prepared
+    // this leg, so 2PC transactions and ICRC-1 transfers move the same
+    // funds.
+    let owner = PREPARED_TRANSACTIONS
+        .with(|prepared| prepared.borrow_mut().remove(&resource))
+        .map(|prepared| prepared.owner)
+        .unwrap_or_else(Principal::anonymous);
+    crate::icrc1::apply_change(&resource, owner, balance_change.as_i64());
+}
+
+/// Recursively call ourselves, burning instructions at each level, to
+/// simulate a participant that never answers a prepare request.
+///
+/// `instructions_per_level` and `max_depth` default to values that make
+/// the simulation noticeable on a real subnet; tests pass small budgets
+/// to keep it predictable. Returns how many recursions actually ran
+/// below this level, so a caller can assert the loop engaged.
+pub async fn call_forever(
+    level: u64,
+    instructions_per_level: Option<u64>,
+    max_depth: Option<u64>,
+) -> u64 {
+    if STOP_CALL_FOREVER.with(|stop| std::mem::take(&mut *stop.borrow_mut())) {
+        ic_cdk::println!("call_forever stopped at level {}", level);
+        return 0;
+    }
+    if level >= max_depth.unwrap_or(MAX_CALL_FOREVER_DEPTH) {
+        return 0;
+    }
+    // Burn instructions so every level does a significant amount of "work".
+    let budget = instructions_per_level.unwrap_or(INSTRUCTIONS_PER_LEVEL);
+    if budget > 0 {
+        let start = ic_cdk::api::performance_counter(0);
+        while ic_cdk::api::performance_counter(0) - start < budget {}
+    }
+    // The recursion goes through a real self-call, not plain async
+    // recursion, so it exercises the canister's message scheduling.
+    let deeper: Result<(u64,), _> = call(
+        ic_cdk::id(),
+        "call_forever",
+        (level + 1, instructions_per_level, max_depth),
+    )
+    .await;
+    1 + deeper.map(|(count,)| count).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tid(nonce: u64) -> TransactionId {
+        TransactionId::new(Principal::anonymous(), nonce)
+    }
+    use crate::{Balance, Counter};
+
+    fn init_balances() {
+        with_resources_mut(|resources| {
+            resources.insert("ICP".to_string(), Box::new(Balance(1_000_000)));
+            resources.insert("USD".to_string(), Box::new(Balance(1_000_000)));
+        });
+    }
+
+    #[test]
+    fn test_rejections_distinguish_underflow_overflow_and_unknown() {
+        init_balances();
+        let owner = Principal::anonymous();
+        // A debit exceeding the balance is "not enough funds"...
+        assert_eq!(
+            prepare_balance(tid(1), "ICP".to_string(), BalanceDelta::debit(2_000_000), None, 0, owner),
+            PrepareVote::No(PrepareError::InsufficientBalance)
+        );
+        // ...a token this ledger does not hold is unknown...
+        assert_eq!(
+            prepare_balance(tid(2), "BTC".to_string(), BalanceDelta::credit(1), None, 0, owner),
+            PrepareVote::No(PrepareError::UnknownToken)
+        );
+        // ...and a credit pushing the balance past `u64::MAX` is an
+        // overflow. Two maximal credits are needed to get there from
+        // the initial balance.
+        assert_eq!(
+            prepare_balance(tid(3), "ICP".to_string(), BalanceDelta::credit(i64::MAX as u64), None, 0, owner),
+            PrepareVote::Yes
+        );
+        commit_balance(tid(3), "ICP".to_string(), BalanceDelta::credit(i64::MAX as u64));
+        assert_eq!(
+            prepare_balance(tid(4), "ICP".to_string(), BalanceDelta::credit(i64::MAX as u64), None, 0, owner),
+            PrepareVote::No(PrepareError::Overflow)
+        );
+    }
+
+    #[test]
+    fn test_zero_change_is_rejected_without_locking() {
+        init_balances();
+        assert_eq!(
+            prepare_balance(
+                tid(1),
+                "ICP".to_string(),
+                BalanceDelta::from_signed(0),
+                None,
+                0,
+                Principal::anonymous()
+            ),
+            PrepareVote::No(PrepareError::NoOp)
+        );
+        // The rejected prepare left no lock behind: another transaction
+        // can take the token right away.
+        assert_eq!(
+            prepare_balance(
+                tid(2),
+                "ICP".to_string(),
+                BalanceDelta::debit(1),
+                None,
+                0,
+                Principal::anonymous()
+            ),
+            PrepareVote::Yes
+        );
+    }
+
+    #[test]
+    fn test_stop_call_forever_ends_simulation_early() {
+        use std::future::Future;
+        stop_call_forever();
+        // With the stop flag set, the simulation completes at the first
+        // level instead of burning instructions and recursing.
+        let mut simulation = std::pin::pin!(call_forever(0, None, None));
+        let waker = std::task::Waker::noop();
+        let mut context = std::task::Context::from_waker(waker);
+        assert_eq!(simulation.as_mut().poll(&mut context), Poll::Ready(0));
+    }
+
+    #[test]
+    fn test_call_forever_respects_its_depth_budget() {
+        use std::future::Future;
+        // A zero instruction budget and zero depth terminate the
+        // simulation immediately: no instructions burnt, no recursion.
+        let mut simulation = std::pin::pin!(call_forever(0, Some(0), Some(0)));
+        let waker = std::task::Waker::noop();
+        let mut context = std::task::Context::from_waker(waker);
+        assert_eq!(simulation.as_mut().poll(&mut context), Poll::Ready(0));
+
+        // A level already past the budget likewise ends the recursion.
+        let mut simulation = std::pin::pin!(call_forever(7, Some(0), Some(5)));
+        assert_eq!(simulation.as_mut().poll(&mut context), Poll::Ready(0));
+    }
+
+    #[test]
+    fn test_mixed_resource_types_in_one_transaction() {
+        init_balances();
+        with_resources_mut(|resources| {
+            resources.insert("reservations".to_string(), Box::new(Counter(0)));
+        });
+        let owner = Principal::anonymous();
+        // A balance and a counter are prepared and committed together.
+        assert_eq!(
+            prepare_balances(
+                tid(1),
+                &[("ICP".to_string(), -10), ("reservations".to_string(), 1)],
+                None,
+                0,
+                owner,
+            ),
+            PrepareVote::Yes
+        );
+        commit_balance(tid(1), "ICP".to_string(), BalanceDelta::debit(10));
+        commit_balance(tid(1), "reservations".to_string(), BalanceDelta::credit(1));
+        with_resources(|resources| {
+            assert_eq!(resources.get("ICP").map(|res| res.value()), Some(999_990));
+            assert_eq!(
+                resources.get("reservations").map(|res| res.value()),
+                Some(1)
+            );
+        });
+        // The counter refuses a change that would make it negative.
+        assert_eq!(
+            prepare_balance(tid(2), "reservations".to_string(), BalanceDelta::debit(5), None, 0, owner),
+            PrepareVote::No(PrepareError::InsufficientBalance)
+        );
+    }
+
+    #[test]
+    fn test_query_prepare_reserves_nothing() {
+        init_balances();
+        let owner = Principal::anonymous();
+        assert_eq!(
+            prepare_balance_query(tid(1), &"ICP".to_string(), -10, 0),
+            PrepareVote::Yes
+        );
+        // The query vote reserved nothing: another transaction can still
+        // take the lock.
+        assert_eq!(
+            prepare_balance(tid(2), "ICP".to_string(), BalanceDelta::debit(10), None, 0, owner),
+            PrepareVote::Yes
+        );
+        assert_eq!(
+            prepare_balance_query(tid(1), &"ICP".to_string(), -10, 0),
+            PrepareVote::Busy
+        );
+        // The paired commit revalidates and refuses the locked token...
+        assert!(!commit_unprepared(tid(1), "ICP".to_string(), BalanceDelta::debit(10), 0, owner));
+        // ...but locks and applies in one step where possible.
+        assert!(commit_unprepared(tid(1), "USD".to_string(), BalanceDelta::debit(10), 0, owner));
+        assert_eq!(
+            with_resources(|resources| resources.get("USD").map(|res| res.value())),
+            Some(999_990)
+        );
+    }
+
+    #[test]
+    fn test_frozen_token_rejects_new_prepares_but_commits_proceed() {
+        init_balances();
+        let owner = Principal::anonymous();
+        // Transaction 1 prepares before the freeze.
+        assert_eq!(
+            prepare_balance(tid(1), "ICP".to_string(), BalanceDelta::debit(10), None, 0, owner),
+            PrepareVote::Yes
+        );
+        set_token_frozen("ICP".to_string(), true);
+        // New prepares are rejected while the token is frozen...
+        assert_eq!(
+            prepare_balance(tid(2), "USD".to_string(), BalanceDelta::debit(10), None, 0, owner),
+            PrepareVote::Yes
+        );
+        set_token_frozen("USD".to_string(), true);
+        assert_eq!(
+            prepare_balance(tid(3), "USD".to_string(), BalanceDelta::debit(10), None, 0, owner),
+            PrepareVote::TokenFrozen
+        );
+        assert_eq!(
+            prepare_balance_query(tid(3), &"USD".to_string(), -10, 0),
+            PrepareVote::TokenFrozen
+        );
+        // ...but the in-flight commit of transaction 1 still completes.
+        commit_balance(tid(1), "ICP".to_string(), BalanceDelta::debit(10));
+        assert_eq!(
+            with_resources(|resources| resources.get("ICP").map(|res| res.value())),
+            Some(999_990)
+        );
+        // Unfreezing re-admits prepares.
+        set_token_frozen("USD".to_string(), false);
+        assert_eq!(
+            prepare_balance(tid(3), "USD".to_string(), BalanceDelta::debit(10), None, 0, owner),
+            PrepareVote::Busy
+        );
+    }
+
+    #[test]
+    fn test_prepare_balances_all_or_nothing() {
+        init_balances();
+        let owner = Principal::anonymous();
+        // The second change underflows the USD balance, so the whole
+        // batch must be rejected and the first token must not stay
+        // locked.
+        assert_eq!(
+            prepare_balances(
+                tid(1),
+                &[("ICP".to_string(), -10), ("USD".to_string(), -2_000_000)],
+                None,
+                0,
+                owner,
+            ),
+            PrepareVote::No(PrepareError::InsufficientBalance)
+        );
+        // A different transaction can still lock ICP.
+        assert_eq!(
+            prepare_balance(tid(2), "ICP".to_string(), BalanceDelta::debit(10), None, 0, owner),
+            PrepareVote::Yes
+        );
+    }
+
+    #[test]
+    fn test_prepare_balances_locks_all_tokens() {
+        init_balances();
+        let owner = Principal::anonymous();
+        assert_eq!(
+            prepare_balances(
+                tid(1),
+                &[("ICP".to_string(), -10), ("USD".to_string(), 10)],
+                None,
+                0,
+                owner,
+            ),
+            PrepareVote::Yes
+        );
+        // Both tokens are locked for transaction 1 now.
+        assert_eq!(
+            prepare_balance(tid(2), "ICP".to_string(), BalanceDelta::debit(10), None, 0, owner),
+            PrepareVote::Busy
+        );
+        assert_eq!(
+            prepare_balance(tid(2), "USD".to_string(), BalanceDelta::debit(10), None, 0, owner),
+            PrepareVote::Busy
+        );
+    }
+
+    #[test]
+    fn test_retried_commit_applies_only_once() {
+        init_balances();
+        let owner = Principal::anonymous();
+        assert_eq!(
+            prepare_balance(tid(1), "ICP".to_string(), BalanceDelta::debit(10), None, 0, owner),
+            PrepareVote::Yes
+        );
+        commit_balance(tid(1), "ICP".to_string(), BalanceDelta::debit(10));
+        assert_eq!(
+            with_resources(|resources| resources.get("ICP").map(|res| res.value())),
+            Some(999_990)
+        );
+        // The coordinator's retry of the same commit neither traps nor
+        // double-applies the change.
+        commit_balance(tid(1), "ICP".to_string(), BalanceDelta::debit(10));
+        assert_eq!(
+            with_resources(|resources| resources.get("ICP").map(|res| res.value())),
+            Some(999_990)
+        );
+    }
+
+    #[test]
+    fn test_optimistic_prepares_share_a_resource() {
+        init_balances();
+        // Unlike the pessimistic path, two concurrent prepares on the
+        // same token both succeed.
+        assert_eq!(prepare_optimistic(tid(1), "ICP".to_string(), -10), PrepareVote::Yes);
+        assert_eq!(prepare_optimistic(tid(2), "ICP".to_string(), -20), PrepareVote::Yes);
+        // The first commit wins and is applied.
+        assert!(commit_optimistic(tid(1), "ICP".to_string(), -10));
+        assert_eq!(
+            with_resources(|resources| resources.get("ICP").map(|res| res.value())),
+            Some(999_990)
+        );
+        // The second commit sees the bumped version and is refused.
+        assert!(!commit_optimistic(tid(2), "ICP".to_string(), -20));
+        assert_eq!(
+            with_resources(|resources| resources.get("ICP").map(|res| res.value())),
+            Some(999_990)
+        );
+        // A fresh prepare against the new version commits fine.
+        assert_eq!(prepare_optimistic(tid(3), "ICP".to_string(), -20), PrepareVote::Yes);
+        assert!(commit_optimistic(tid(3), "ICP".to_string(), -20));
+        assert_eq!(
+            with_resources(|resources| resources.get("ICP").map(|res| res.value())),
+            Some(999_970)
+        );
+    }
+
+    #[test]
+    fn test_optimistic_abort_drops_the_intent() {
+        init_balances();
+        assert_eq!(prepare_optimistic(tid(1), "ICP".to_string(), -10), PrepareVote::Yes);
+        abort_optimistic(tid(1), "ICP".to_string());
+        // Without an intent, a stray commit applies nothing.
+        assert!(!commit_optimistic(tid(1), "ICP".to_string(), -10));
+        assert_eq!(
+            with_resources(|resources| resources.get("ICP").map(|res| res.value())),
+            Some(1_000_000)
+        );
+    }
+}
@@ -0,0 +1,189 @@
+//! Minimal ICRC-1 compatible transfer surface on top of per-account
+//! balances.
+//!
+//! The ledger itself is multi-token while ICRC-1 ledgers are
+//! single-token, so the ICRC-1 endpoints are scoped to the first token
+//! from `init`, the "primary" token. Accounts exist for every token,
+//! though: the two-phase commit machinery mirrors committed changes
+//! onto the account of the principal that drove the prepare, so 2PC
+//! swaps and ICRC-1 transfers move the same funds.
+
+use crate::{TokenBalance, TokenName};
+use candid::{CandidType, Deserialize, Principal};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+/// An ICRC-1 account. Subaccounts are accepted for wire compatibility
+/// but not distinguished: every subaccount of an owner maps to the same
+/// balance.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<Vec<u8>>,
+}
+
+/// Arguments of `icrc1_transfer`. The fee, memo and deduplication
+/// fields of the full standard are accepted but ignored: this ledger
+/// charges no fees and keeps no block log beyond an index counter.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TransferArg {
+    pub to: Account,
+    pub amount: TokenBalance,
+    pub fee: Option<TokenBalance>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+/// Why an `icrc1_transfer` was refused.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum TransferError {
+    InsufficientFunds { balance: TokenBalance },
+}
+
+thread_local! {
+    /// Per-account balances, keyed by token and owner. For each token,
+    /// the sum over all owners matches the token's global resource
+    /// value.
+    static ACCOUNTS: RefCell<BTreeMap<(TokenName, Principal), TokenBalance>> =
+        const { RefCell::new(BTreeMap::new()) };
+    /// The token served by the ICRC-1 endpoints.
+    static PRIMARY_TOKEN: RefCell<TokenName> = const { RefCell::new(String::new()) };
+    /// Index handed out for the next transfer, standing in for the
+    /// block index a full ICRC-1 ledger would return.
+    static NEXT_BLOCK_INDEX: RefCell<u64> = const { RefCell::new(0) };
+}
+
+/// Designate the token the ICRC-1 endpoints serve; called from `init`
+/// with the ledger's first token.
+pub fn set_primary_token(token: TokenName) {
+    PRIMARY_TOKEN.with(|primary| *primary.borrow_mut() = token);
+}
+
+pub fn primary_token() -> TokenName {
+    PRIMARY_TOKEN.with(|primary| primary.borrow().clone())
+}
+
+/// Drop all account balances, e.g. when the ledger's `reset_all`
+/// reinitializes its tokens. The block index keeps counting so indices
+/// stay unique across a reset.
+pub fn reset_accounts() {
+    ACCOUNTS.with(|accounts| accounts.borrow_mut().clear());
+}
+
+/// Credit the given account, e.g. with its share of a token's initial
+/// balance.
+pub fn credit(token: TokenName, owner: Principal, amount: TokenBalance) {
+    ACCOUNTS.with(|accounts| {
+        let mut accounts = accounts.borrow_mut();
+        let balance = accounts.entry((token, owner)).or_insert(0);
+        *balance = balance.checked_add(amount).unwrap();
+    });
+}
+
+pub fn balance_of(token: &TokenName, owner: Principal) -> TokenBalance {
+    ACCOUNTS.with(|accounts| {
+        accounts
+            .borrow()
+            .get(&(token.clone(), owner))
+            .copied()
+            .unwrap_or(0)
+    })
+}
+
+/// Mirror a committed two-phase commit change onto the given account.
+/// Clamped at zero: if ICRC-1 transfers drained the account between
+/// prepare and commit, the account cannot go negative. The global
+/// resource value remains authoritative for prepare checks.
+pub fn apply_change(token: &TokenName, owner: Principal, change: i64) {
+    ACCOUNTS.with(|accounts| {
+        let mut accounts = accounts.borrow_mut();
+        let balance = accounts.entry((token.clone(), owner)).or_insert(0);
+        *balance = balance.saturating_add_signed(change);
+    });
+}
+
+/// Move funds of the primary token between two accounts. Returns the
+/// transfer's block index.
+pub fn transfer(
+    from: Principal,
+    to: Principal,
+    amount: TokenBalance,
+) -> Result<u64, TransferError> {
+    let token = primary_token();
+    ACCOUNTS.with(|accounts| {
+        let mut accounts = accounts.borrow_mut();
+        let from_balance = accounts
+            .get(&(token.clone(), from))
+            .copied()
+            .unwrap_or(0);
+        if from_balance < amount {
+            return Err(TransferError::InsufficientFunds {
+                balance: from_balance,
+            });
+        }
+        accounts.insert((token.clone(), from), from_balance - amount);
+        let to_balance = accounts.entry((token, to)).or_insert(0);
+        *to_balance = to_balance.checked_add(amount).unwrap();
+        Ok(NEXT_BLOCK_INDEX.with(|index| {
+            let mut index = index.borrow_mut();
+            *index += 1;
+            *index - 1
+        }))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::atomic_transactions::{commit_balance, prepare_balance};
+    use crate::{with_resources, with_resources_mut, Balance};
+    use ic_atomic_transactions::{BalanceDelta, PrepareVote, TransactionId};
+
+    fn tid(nonce: u64) -> TransactionId {
+        TransactionId::new(Principal::anonymous(), nonce)
+    }
+
+    #[test]
+    fn test_icrc1_transfer_moves_funds() {
+        set_primary_token("ICP".to_string());
+        let alice = Principal::from_slice(&[1]);
+        let bob = Principal::from_slice(&[2]);
+        credit("ICP".to_string(), alice, 1_000);
+        assert_eq!(transfer(alice, bob, 400), Ok(0));
+        assert_eq!(balance_of(&"ICP".to_string(), alice), 600);
+        assert_eq!(balance_of(&"ICP".to_string(), bob), 400);
+        // Overdrafts are refused, reporting the available balance.
+        assert_eq!(
+            transfer(bob, alice, 401),
+            Err(TransferError::InsufficientFunds { balance: 400 })
+        );
+    }
+
+    #[test]
+    fn test_icrc1_and_2pc_share_the_same_accounts() {
+        set_primary_token("EUR".to_string());
+        let coordinator = Principal::from_slice(&[9]);
+        let wallet = Principal::from_slice(&[10]);
+        with_resources_mut(|resources| {
+            resources.insert("EUR".to_string(), Box::new(Balance(1_000)));
+        });
+        credit("EUR".to_string(), coordinator, 1_000);
+
+        // A 2PC leg debits the account of the principal that prepared.
+        assert_eq!(
+            prepare_balance(tid(1), "EUR".to_string(), BalanceDelta::debit(300), None, 0, coordinator),
+            PrepareVote::Yes
+        );
+        commit_balance(tid(1), "EUR".to_string(), BalanceDelta::debit(300));
+        assert_eq!(balance_of(&"EUR".to_string(), coordinator), 700);
+        assert_eq!(
+            with_resources(|resources| resources.get("EUR").unwrap().value()),
+            700
+        );
+
+        // The committed funds remain usable over the ICRC-1 surface.
+        assert!(transfer(coordinator, wallet, 700).is_ok());
+        assert_eq!(balance_of(&"EUR".to_string(), wallet), 700);
+        assert_eq!(balance_of(&"EUR".to_string(), coordinator), 0);
+    }
+}
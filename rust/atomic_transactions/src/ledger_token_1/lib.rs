@@ -0,0 +1,1011 @@
+use candid::{CandidType, Decode, Principal};
+use ic_atomic_transactions::{
+    BalanceDelta, Configuration, Envelope, Phase, PrepareError, PrepareVote, TransactionId,
+    TransactionStatus, TwoPhaseCommitState,
+};
+use ic_cdk::api::call::ManualReply;
+use ic_cdk::{init, query, update};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+mod atomic_transactions;
+mod icrc1;
+
+pub use ic_atomic_transactions::TokenName;
+pub type TokenBalance = u64;
+
+/// A named resource this ledger manages under two-phase commit.
+///
+/// The 2PC machinery itself is agnostic of what is being locked; this
+/// trait is the only thing a new resource type has to implement to be
+/// coordinated alongside token balances.
+pub trait Resource {
+    /// True if the given change could be applied right now.
+    fn prepare(&self, change: i64) -> bool;
+    /// Apply a previously prepared change. Must not fail: `prepare`
+    /// vouched for the change while the resource was locked.
+    fn commit(&mut self, change: i64);
+    /// The current value of the resource, for queries and snapshots.
+    fn value(&self) -> i64;
+}
+
+/// A token balance: the classic resource of this ledger.
+pub struct Balance(pub TokenBalance);
+
+impl Resource for Balance {
+    fn prepare(&self, change: i64) -> bool {
+        self.0.checked_add_signed(change).is_some()
+    }
+
+    fn commit(&mut self, change: i64) {
+        self.0 = self.0.checked_add_signed(change).unwrap();
+    }
+
+    fn value(&self) -> i64 {
+        self.0 as i64
+    }
+}
+
+/// A non-negative counter, e.g. tracking reservations.
+pub struct Counter(pub i64);
+
+impl Resource for Counter {
+    fn prepare(&self, change: i64) -> bool {
+        self.0.checked_add(change).is_some_and(|count| count >= 0)
+    }
+
+    fn commit(&mut self, change: i64) {
+        self.0 = self.0.checked_add(change).unwrap();
+    }
+
+    fn value(&self) -> i64 {
+        self.0
+    }
+}
+
+thread_local! {
+    /// All resources managed by this ledger, keyed by name. Token
+    /// balances are `Balance` resources; other resource types take part
+    /// in the same transactions.
+    static RESOURCES: RefCell<BTreeMap<TokenName, Box<dyn Resource>>> =
+        const { RefCell::new(BTreeMap::new()) };
+    /// Two-phase commit state, tracking which tokens are locked by which
+    /// transaction.
+    static PC_STATE: RefCell<TwoPhaseCommitState<TokenName>> =
+        RefCell::new(TwoPhaseCommitState::default());
+    /// The coordinator that installed this ledger, target of
+    /// participant-initiated abort requests.
+    static COORDINATOR: RefCell<Principal> = const { RefCell::new(Principal::anonymous()) };
+}
+
+pub fn with_resources<R>(f: impl FnOnce(&BTreeMap<TokenName, Box<dyn Resource>>) -> R) -> R {
+    RESOURCES.with(|resources| f(&resources.borrow()))
+}
+
+pub fn with_resources_mut<R>(
+    f: impl FnOnce(&mut BTreeMap<TokenName, Box<dyn Resource>>) -> R,
+) -> R {
+    RESOURCES.with(|resources| f(&mut resources.borrow_mut()))
+}
+
+pub fn with_state<R>(f: impl FnOnce(&TwoPhaseCommitState<TokenName>) -> R) -> R {
+    PC_STATE.with(|state| f(&state.borrow()))
+}
+
+pub fn with_state_mut<R>(f: impl FnOnce(&mut TwoPhaseCommitState<TokenName>) -> R) -> R {
+    PC_STATE.with(|state| f(&mut state.borrow_mut()))
+}
+
+/// Initialize the ledger with the given tokens and balances.
+#[init]
+fn init(token_names: Vec<TokenName>, token_balances: Vec<TokenBalance>) {
+    assert_eq!(token_names.len(), token_balances.len());
+    COORDINATOR.with(|coordinator| *coordinator.borrow_mut() = ic_cdk::caller());
+    with_resources_mut(|resources| {
+        for (name, balance) in token_names.iter().zip(token_balances.iter()) {
+            resources.insert(name.clone(), Box::new(Balance(*balance)));
+        }
+    });
+    // The installing coordinator owns the initial liquidity; the ICRC-1
+    // endpoints serve the first token.
+    if let Some(primary) = token_names.first() {
+        icrc1::set_primary_token(primary.clone());
+    }
+    for (name, balance) in token_names.iter().zip(token_balances.iter()) {
+        icrc1::credit(name.clone(), ic_cdk::caller(), *balance);
+    }
+    ic_cdk::println!("Ledger initialized with tokens: {:?}", token_names);
+}
+
+/// True if the given caller is the registered coordinator. An unset
+/// coordinator (still the anonymous principal) authorizes nobody, so a
+/// ledger is locked down until its coordinator registered itself.
+fn _is_coordinator(caller: Principal, coordinator: Principal) -> bool {
+    coordinator != Principal::anonymous() && caller == coordinator
+}
+
+/// Trap unless the caller is the registered coordinator. Guards the
+/// prepare/abort/commit entry points: any other principal could
+/// otherwise forge a prepare and lock this ledger's tokens.
+fn require_coordinator() {
+    let coordinator = COORDINATOR.with(|coordinator| *coordinator.borrow());
+    if !_is_coordinator(ic_cdk::caller(), coordinator) {
+        ic_cdk::trap("Only the registered coordinator may drive two-phase commit");
+    }
+}
+
+/// Re-register the coordinator, e.g. after the dex was reinstalled under
+/// a new canister ID. Only callable by a controller.
+#[update]
+fn set_coordinator(coordinator: Principal) {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        ic_cdk::trap("Only a controller may set the coordinator");
+    }
+    COORDINATOR.with(|current| *current.borrow_mut() = coordinator);
+}
+
+/// Validate that an envelope belongs to the given phase and decode it,
+/// logging and returning `None` on a mismatch. Guards every handler
+/// against a payload reaching the wrong method or transaction.
+fn open_envelope(envelope: &Envelope, phase: Phase) -> Option<TransactionId> {
+    if !envelope.matches_phase(phase) {
+        ic_cdk::println!(
+            "Rejecting envelope for transaction {}: expected phase {:?}, got {:?}",
+            envelope.tid,
+            phase,
+            envelope.phase
+        );
+        return None;
+    }
+    Some(envelope.tid)
+}
+
+/// Prepare phase of the two-phase commit protocol.
+///
+/// Vote `Yes` if the given balance change can be applied to the given
+/// token and no other transaction holds a lock on it. A `Yes` vote locks
+/// the token for this transaction until commit or abort, or until the
+/// optional client-supplied deadline `valid_until_ns` passes. A lock
+/// held by another transaction yields `Busy`, a change that can never be
+/// applied yields `No`, as does an envelope of the wrong phase.
+#[update]
+async fn prepare_transaction(envelope: Envelope) -> PrepareVote {
+    require_coordinator();
+    let Some(tid) = open_envelope(&envelope, Phase::Prepare) else {
+        return PrepareVote::No(PrepareError::Rejected);
+    };
+    let (resource, balance_change, valid_until_ns) =
+        Decode!(&envelope.args, TokenName, BalanceDelta, Option<u64>).unwrap();
+    let configuration = get_configuration();
+    if configuration.infinite_prepare {
+        // Simulate a participant that never answers.
+        atomic_transactions::call_forever(0, None, None).await;
+    }
+    if configuration.prepare_delay_ns > 0 {
+        // Simulate a slow-but-honest participant: answer, but late.
+        atomic_transactions::sleep(Duration::from_nanos(configuration.prepare_delay_ns)).await;
+    }
+    if configuration.stop_on_prepare {
+        ic_cdk::println!("Configured to vote \"no\" on prepare");
+        return PrepareVote::No(PrepareError::Rejected);
+    }
+    if configuration.optimistic_locking {
+        return atomic_transactions::prepare_optimistic(tid, resource, balance_change.as_i64());
+    }
+    atomic_transactions::prepare_balance(
+        tid,
+        resource,
+        balance_change,
+        valid_until_ns,
+        ic_cdk::api::time(),
+        ic_cdk::caller(),
+    )
+}
+
+/// Query-based prepare: a read-mostly applicability check that reserves
+/// nothing. See `commit_unprepared` for the update it must be paired
+/// with.
+#[query]
+fn prepare_query(envelope: Envelope) -> PrepareVote {
+    let Some(tid) = open_envelope(&envelope, Phase::Prepare) else {
+        return PrepareVote::No(PrepareError::Rejected);
+    };
+    let (resource, balance_change) = Decode!(&envelope.args, TokenName, BalanceDelta).unwrap();
+    atomic_transactions::prepare_balance_query(
+        tid,
+        &resource,
+        balance_change.as_i64(),
+        ic_cdk::api::time(),
+    )
+}
+
+/// Commit for query-mode transactions: revalidate, lock and apply the
+/// balance change in one message, since the query prepare reserved
+/// nothing.
+#[update]
+fn commit_unprepared(envelope: Envelope) -> bool {
+    require_coordinator();
+    let Some(tid) = open_envelope(&envelope, Phase::Commit) else {
+        return false;
+    };
+    let (resource, balance_change) = Decode!(&envelope.args, TokenName, BalanceDelta).unwrap();
+    atomic_transactions::commit_unprepared(
+        tid,
+        resource,
+        balance_change,
+        ic_cdk::api::time(),
+        ic_cdk::caller(),
+    )
+}
+
+/// Batched prepare: atomically evaluate several balance changes on this
+/// ledger and vote "yes" only if all of them are acceptable, locking the
+/// tokens together.
+#[update]
+async fn prepare_batch(envelope: Envelope) -> PrepareVote {
+    require_coordinator();
+    let Some(tid) = open_envelope(&envelope, Phase::Prepare) else {
+        return PrepareVote::No(PrepareError::Rejected);
+    };
+    let (changes, valid_until_ns) =
+        Decode!(&envelope.args, Vec<(TokenName, i64)>, Option<u64>).unwrap();
+    let configuration = get_configuration();
+    if configuration.infinite_prepare {
+        // Simulate a participant that never answers.
+        atomic_transactions::call_forever(0, None, None).await;
+    }
+    if configuration.prepare_delay_ns > 0 {
+        // Simulate a slow-but-honest participant: answer, but late.
+        atomic_transactions::sleep(Duration::from_nanos(configuration.prepare_delay_ns)).await;
+    }
+    if configuration.stop_on_prepare {
+        ic_cdk::println!("Configured to vote \"no\" on prepare");
+        return PrepareVote::No(PrepareError::Rejected);
+    }
+    atomic_transactions::prepare_balances(
+        tid,
+        &changes,
+        valid_until_ns,
+        ic_cdk::api::time(),
+        ic_cdk::caller(),
+    )
+}
+
+/// Batched abort: release the locks of the given transaction on all the
+/// given tokens. Safe to call multiple times.
+#[update]
+fn abort_batch(envelope: Envelope) -> bool {
+    require_coordinator();
+    let Some(tid) = open_envelope(&envelope, Phase::Abort) else {
+        return false;
+    };
+    let changes = Decode!(&envelope.args, Vec<(TokenName, i64)>).unwrap();
+    ic_cdk::println!("Aborting batched transaction {}", tid);
+    with_state_mut(|state| {
+        for (resource, _) in &changes {
+            state.abort_transaction(tid, resource);
+        }
+    });
+    true
+}
+
+/// Batched commit: apply all balance changes voted on in a batched
+/// prepare.
+#[update]
+fn commit_batch(envelope: Envelope) -> bool {
+    require_coordinator();
+    let Some(tid) = open_envelope(&envelope, Phase::Commit) else {
+        return false;
+    };
+    let changes = Decode!(&envelope.args, Vec<(TokenName, i64)>).unwrap();
+    ic_cdk::println!("Committing batched transaction {}", tid);
+    for (resource, balance_change) in changes {
+        atomic_transactions::commit_balance(tid, resource, BalanceDelta::from_signed(balance_change));
+    }
+    true
+}
+
+/// Abort phase of the two-phase commit protocol.
+///
+/// Release the lock on the given token if this transaction holds it.
+/// Safe to call multiple times.
+#[update]
+fn abort_transaction(envelope: Envelope) -> bool {
+    require_coordinator();
+    let Some(tid) = open_envelope(&envelope, Phase::Abort) else {
+        return false;
+    };
+    let resource = Decode!(&envelope.args, TokenName).unwrap();
+    ic_cdk::println!("Aborting transaction {} for token {}", tid, resource);
+    if get_configuration().optimistic_locking {
+        atomic_transactions::abort_optimistic(tid, resource);
+        return true;
+    }
+    with_state_mut(|state| state.abort_transaction(tid, &resource))
+}
+
+/// Commit phase of the two-phase commit protocol.
+///
+/// Apply the balance change that was voted on in the prepare phase.
+/// Must only be called after a successful prepare.
+#[update(manual_reply = true)]
+fn commit_transaction(envelope: Envelope) -> ManualReply<bool> {
+    require_coordinator();
+    let Some(tid) = open_envelope(&envelope, Phase::Commit) else {
+        return ManualReply::one(false);
+    };
+    let (resource, balance_change) = Decode!(&envelope.args, TokenName, BalanceDelta).unwrap();
+    ic_cdk::println!("Committing transaction {} for token {}", tid, resource);
+    if with_state_mut(|state| state.take_commit_fault()) {
+        // Simulate a participant that fails during commit; the
+        // coordinator retries rejected commits until they stick. The
+        // failure is an explicit reject rather than a trap: a trap
+        // would roll this message back, including the counter
+        // decrement, and the fault would never be used up.
+        ic_cdk::println!("Injected commit failure for transaction {}", tid);
+        return ManualReply::reject("Injected commit failure (fail_commit_times)");
+    }
+    if get_configuration().optimistic_locking {
+        return ManualReply::one(atomic_transactions::commit_optimistic(
+            tid,
+            resource,
+            balance_change.as_i64(),
+        ));
+    }
+    atomic_transactions::commit_balance(tid, resource, balance_change);
+    ManualReply::one(true)
+}
+
+/// Query the current balance of the given token.
+#[query]
+fn get_balance(token: TokenName) -> Option<TokenBalance> {
+    with_resources(|resources| {
+        resources
+            .get(&token)
+            .and_then(|resource| u64::try_from(resource.value()).ok())
+    })
+}
+
+/// Alias of `get_balance` under the name most clients expect.
+#[query]
+fn balance_of(token: TokenName) -> Option<TokenBalance> {
+    get_balance(token)
+}
+
+/// Every token of this ledger with its current balance. Resources whose
+/// value is negative (e.g. a drained counter) are omitted, matching
+/// `get_balance`.
+#[query]
+fn all_balances() -> Vec<(TokenName, TokenBalance)> {
+    with_resources(|resources| {
+        resources
+            .iter()
+            .filter_map(|(token, resource)| {
+                u64::try_from(resource.value())
+                    .ok()
+                    .map(|balance| (token.clone(), balance))
+            })
+            .collect()
+    })
+}
+
+/// Name of the token served by the ICRC-1 endpoints. The ledger is
+/// multi-token while ICRC-1 ledgers are single-token, so the ICRC-1
+/// surface is scoped to the first token from `init`.
+#[query]
+fn icrc1_name() -> String {
+    icrc1::primary_token()
+}
+
+/// Symbol of the token served by the ICRC-1 endpoints; the token names
+/// of this ledger already are symbols.
+#[query]
+fn icrc1_symbol() -> String {
+    icrc1::primary_token()
+}
+
+/// Decimals of the token served by the ICRC-1 endpoints. The balances
+/// those endpoints report are base units; clients divide by
+/// `10^decimals` for display.
+#[query]
+fn icrc1_decimals() -> u8 {
+    atomic_transactions::token_decimals(&icrc1::primary_token())
+}
+
+/// ICRC-1 balance of the given account, in the primary token.
+#[query]
+fn icrc1_balance_of(account: icrc1::Account) -> TokenBalance {
+    icrc1::balance_of(&icrc1::primary_token(), account.owner)
+}
+
+/// Transfer primary-token funds from the caller's account to the given
+/// account. Returns the transfer's block index.
+#[update]
+fn icrc1_transfer(arg: icrc1::TransferArg) -> Result<u64, icrc1::TransferError> {
+    icrc1::transfer(ic_cdk::caller(), arg.to.owner, arg.amount)
+}
+
+/// The participant-side status of the given token, with an expired lock
+/// reported as `Aborted`, matching how prepare treats it.
+fn _token_status(
+    state: &TwoPhaseCommitState<TokenName>,
+    token: &TokenName,
+    now: u64,
+) -> Option<TransactionStatus> {
+    state.state.get(token).map(|status| match status {
+        TransactionStatus::Prepared(_) if state.lock_expired(token, now) => {
+            TransactionStatus::Aborted
+        }
+        other => other.clone(),
+    })
+}
+
+/// Query the two-phase-commit status of the given token, used by the
+/// coordinator's `reconcile` safety net to cross-check its recorded
+/// votes against this ledger's lock state.
+#[query]
+fn token_status(token: TokenName) -> Option<TransactionStatus> {
+    with_state(|state| _token_status(state, &token, ic_cdk::api::time()))
+}
+
+/// The tokens currently locked by a prepared transaction, with the
+/// transaction holding each lock. Expired locks are omitted, matching
+/// `locked_tokens`.
+fn _lock_holders(
+    state: &TwoPhaseCommitState<TokenName>,
+    now: u64,
+) -> Vec<(TokenName, TransactionId)> {
+    state
+        .state
+        .iter()
+        .filter_map(|(token, status)| match status {
+            TransactionStatus::Prepared(tid) if !state.lock_expired(token, now) => {
+                Some((token.clone(), *tid))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Which transaction holds the lock on which token, the counterpart of
+/// `locked_tokens` for callers that need the holder instead of the
+/// lock's age.
+#[query]
+fn lock_holders() -> Vec<(TokenName, TransactionId)> {
+    with_state(|state| _lock_holders(state, ic_cdk::api::time()))
+}
+
+/// The tokens currently locked by a prepared transaction, with the age
+/// of each lock in nanoseconds. Expired locks are omitted.
+fn _locked_tokens(state: &TwoPhaseCommitState<TokenName>, now: u64) -> Vec<(TokenName, u64)> {
+    state
+        .state
+        .keys()
+        .filter_map(|token| {
+            state
+                .lock_age(token, now)
+                .map(|age| (token.clone(), age))
+        })
+        .collect()
+}
+
+/// Query the currently held locks and their ages, used by the
+/// coordinator's `longest_lock` triage tool.
+#[query]
+fn locked_tokens() -> Vec<(TokenName, u64)> {
+    with_state(|state| _locked_tokens(state, ic_cdk::api::time()))
+}
+
+/// Metadata of a token held by this ledger.
+#[derive(CandidType, Clone, Debug)]
+pub struct TokenMetadata {
+    pub balance: TokenBalance,
+    /// Whether an operator has frozen the token; a frozen token rejects
+    /// new prepares until it is unfrozen.
+    pub frozen: bool,
+    /// Display decimals: the balance is in base units, clients divide by
+    /// `10^decimals` to render it.
+    pub decimals: u8,
+}
+
+/// The names of all tokens this ledger holds, used by the coordinator's
+/// aggregated token listing.
+#[query]
+fn list_tokens() -> Vec<TokenName> {
+    with_resources(|resources| resources.keys().cloned().collect())
+}
+
+/// Query the metadata of the given token, or `None` for unknown tokens.
+#[query]
+fn token_metadata(token: TokenName) -> Option<TokenMetadata> {
+    with_resources(|resources| {
+        resources.get(&token).map(|resource| TokenMetadata {
+            balance: u64::try_from(resource.value()).unwrap_or(0),
+            frozen: atomic_transactions::token_frozen(&token),
+            decimals: atomic_transactions::token_decimals(&token),
+        })
+    })
+}
+
+/// Freeze or unfreeze the given token, e.g. during an incident. New
+/// prepares on a frozen token are rejected with `TokenFrozen`; commits
+/// and aborts of transactions that already hold a lock still complete.
+/// Only callable by a controller.
+#[update]
+fn freeze_token(token: TokenName, frozen: bool) {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        ic_cdk::trap("Only a controller may freeze or unfreeze tokens");
+    }
+    atomic_transactions::set_token_frozen(token, frozen);
+}
+
+/// Configure the display decimals of the given token. Purely
+/// presentational: balances and 2PC deltas stay in base units, no
+/// stored value is rescaled. Only callable by a controller.
+#[update]
+fn set_token_decimals(token: TokenName, decimals: u8) {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        ic_cdk::trap("Only a controller may set token decimals");
+    }
+    atomic_transactions::set_token_decimals(token, decimals);
+}
+
+/// The display decimals of the given token; tokens never configured
+/// report zero, i.e. whole units.
+#[query]
+fn token_decimals(token: TokenName) -> u8 {
+    atomic_transactions::token_decimals(&token)
+}
+
+/// Why a supply change was refused.
+#[derive(CandidType, Clone, Debug, PartialEq, Eq)]
+pub enum TxError {
+    UnknownToken,
+    /// The token is locked by an in-flight prepared transaction; a
+    /// supply change now would race the 2PC.
+    TokenLocked,
+    Overflow,
+    InsufficientSupply,
+}
+
+/// True if the given token is currently locked by a prepared
+/// transaction whose lock has not expired.
+fn _token_locked(state: &TwoPhaseCommitState<TokenName>, token: &TokenName, now: u64) -> bool {
+    matches!(
+        _token_status(state, token, now),
+        Some(TransactionStatus::Prepared(_))
+    )
+}
+
+/// Apply a supply change outside of any transaction, refusing changes
+/// the resource cannot absorb.
+fn _change_supply(
+    resources: &mut BTreeMap<TokenName, Box<dyn Resource>>,
+    token: &TokenName,
+    change: i64,
+) -> Result<(), TxError> {
+    let resource = resources.get_mut(token).ok_or(TxError::UnknownToken)?;
+    if !resource.prepare(change) {
+        return Err(if change < 0 {
+            TxError::InsufficientSupply
+        } else {
+            TxError::Overflow
+        });
+    }
+    resource.commit(change);
+    Ok(())
+}
+
+/// Mint new units of the given token, e.g. for a faucet-style demo.
+/// Refused while the token is locked by an in-flight transaction. Only
+/// callable by a controller.
+#[update]
+fn mint(token: TokenName, amount: u64) -> Result<(), TxError> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        ic_cdk::trap("Only a controller may mint");
+    }
+    let change = i64::try_from(amount).map_err(|_| TxError::Overflow)?;
+    if with_state(|state| _token_locked(state, &token, ic_cdk::api::time())) {
+        return Err(TxError::TokenLocked);
+    }
+    with_resources_mut(|resources| _change_supply(resources, &token, change))
+}
+
+/// Burn units of the given token, the inverse of `mint`. Refused if it
+/// would underflow the balance or while the token is locked by an
+/// in-flight transaction. Only callable by a controller.
+#[update]
+fn burn(token: TokenName, amount: u64) -> Result<(), TxError> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        ic_cdk::trap("Only a controller may burn");
+    }
+    let change = i64::try_from(amount).map_err(|_| TxError::Overflow)?;
+    if with_state(|state| _token_locked(state, &token, ic_cdk::api::time())) {
+        return Err(TxError::TokenLocked);
+    }
+    with_resources_mut(|resources| _change_supply(resources, &token, -change))
+}
+
+/// Clear the 2PC bookkeeping unless an unexpired prepare is in flight:
+/// resetting under a live lock would let a second transaction prepare
+/// the same token and break the first one's atomicity. The configuration
+/// survives the reset, only lock states, deadlines and the abort log go.
+fn _reset_pc_state(
+    state: &mut TwoPhaseCommitState<TokenName>,
+    now: u64,
+) -> Result<(), TxError> {
+    if state
+        .state
+        .keys()
+        .any(|token| _token_locked(state, token, now))
+    {
+        return Err(TxError::TokenLocked);
+    }
+    let configuration = state.configuration.clone();
+    *state = TwoPhaseCommitState::default();
+    state.configuration = configuration;
+    Ok(())
+}
+
+/// Reset the two-phase commit state, dropping accumulated `Committed`
+/// and `Aborted` entries and expired locks, e.g. to isolate test runs
+/// against a long-lived ledger. Refused with `TokenLocked` while any
+/// transaction holds an unexpired `Prepared` lock. Only callable by a
+/// controller.
+#[update]
+fn reset_pc_state() -> Result<(), TxError> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        ic_cdk::trap("Only a controller may reset the 2PC state");
+    }
+    with_state_mut(|state| _reset_pc_state(state, ic_cdk::api::time()))
+}
+
+/// Reset the 2PC state like `reset_pc_state` and additionally
+/// reinitialize the ledger's tokens and balances as `init` would,
+/// replacing whatever resources existed before. Subject to the same
+/// `TokenLocked` guard. Only callable by a controller.
+#[update]
+fn reset_all(token_names: Vec<TokenName>, token_balances: Vec<TokenBalance>) -> Result<(), TxError> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        ic_cdk::trap("Only a controller may reset the ledger");
+    }
+    assert_eq!(token_names.len(), token_balances.len());
+    with_state_mut(|state| _reset_pc_state(state, ic_cdk::api::time()))?;
+    with_resources_mut(|resources| {
+        resources.clear();
+        for (name, balance) in token_names.iter().zip(token_balances.iter()) {
+            resources.insert(name.clone(), Box::new(Balance(*balance)));
+        }
+    });
+    // Rebuild the ICRC-1 account mirror the same way `init` does: the
+    // caller owns the fresh liquidity, the first token is primary.
+    icrc1::reset_accounts();
+    if let Some(primary) = token_names.first() {
+        icrc1::set_primary_token(primary.clone());
+    }
+    for (name, balance) in token_names.iter().zip(token_balances.iter()) {
+        icrc1::credit(name.clone(), ic_cdk::caller(), *balance);
+    }
+    Ok(())
+}
+
+/// Ask the coordinator to abort the given transaction, e.g. after an
+/// operator detected a problem with a token this ledger already voted
+/// "yes" on. Returns whether the coordinator accepted the request.
+#[update]
+async fn request_abort(tid: TransactionId) -> bool {
+    let coordinator = COORDINATOR.with(|coordinator| *coordinator.borrow());
+    match ic_cdk::api::call::call::<_, (bool,)>(coordinator, "request_abort", (tid,)).await {
+        Ok((accepted,)) => accepted,
+        Err(err) => {
+            ic_cdk::println!("Abort request to coordinator failed: {:?}", err);
+            false
+        }
+    }
+}
+
+/// Report this ledger's notion of the current time, used by the
+/// coordinator to diagnose clock skew between the canisters.
+#[query]
+fn now() -> u64 {
+    ic_cdk::api::time()
+}
+
+/// The compile-time version of this ledger's WASM, used by operators to
+/// confirm all participants run a compatible version before initiating
+/// swaps.
+#[query]
+fn version() -> String {
+    env!("CARGO_PKG_VERSION").to_string()
+}
+
+/// Simulate a participant that holds on to a message forever by
+/// repeatedly calling itself. Used to test the coordinator's timeout
+/// handling. The instruction budget per level and the maximum recursion
+/// depth default to values suited to a real subnet; the returned count
+/// of performed recursions lets a test assert the loop engaged.
+#[update]
+async fn call_forever(
+    level: u64,
+    instructions_per_level: Option<u64>,
+    max_depth: Option<u64>,
+) -> u64 {
+    atomic_transactions::call_forever(level, instructions_per_level, max_depth).await
+}
+
+/// End a running `call_forever` simulation at its next level, so a test
+/// harness can stop the grief scenario deterministically.
+#[update]
+fn stop_call_forever() {
+    atomic_transactions::stop_call_forever();
+}
+
+/// Set the test configuration of this ledger. Only callable by a
+/// controller.
+#[update]
+fn set_configuration(configuration: Configuration) {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        ic_cdk::trap("Only a controller may change the configuration");
+    }
+    with_state_mut(|state| state.configuration = configuration);
+}
+
+/// Read back the configuration, so a test setup can confirm a mode
+/// took effect before proceeding.
+#[query]
+fn get_configuration() -> Configuration {
+    with_state(|state| state.configuration.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tid(nonce: u64) -> TransactionId {
+        TransactionId::new(Principal::anonymous(), nonce)
+    }
+
+    #[test]
+    fn test_open_envelope_rejects_wrong_phase() {
+        // A commit payload reaching the prepare handler is refused.
+        let envelope = Envelope::new(tid(1), Phase::Commit, 0, vec![]);
+        assert_eq!(open_envelope(&envelope, Phase::Prepare), None);
+        assert_eq!(open_envelope(&envelope, Phase::Commit), Some(tid(1)));
+    }
+
+    #[test]
+    fn test_only_the_coordinator_may_drive_2pc() {
+        let coordinator = Principal::from_slice(&[1]);
+        let foreign = Principal::from_slice(&[2]);
+        // A foreign principal's prepare is rejected, the coordinator's
+        // is accepted.
+        assert!(!_is_coordinator(foreign, coordinator));
+        assert!(_is_coordinator(coordinator, coordinator));
+        // Until a coordinator registered itself, nobody is authorized,
+        // not even the anonymous principal matching the initial value.
+        assert!(!_is_coordinator(
+            Principal::anonymous(),
+            Principal::anonymous()
+        ));
+    }
+
+    #[test]
+    fn test_simulated_swap_leaves_balances_unchanged() {
+        with_resources_mut(|resources| {
+            resources.insert("ICP".to_string(), Box::new(Balance(1_000)));
+        });
+        // The coordinator's `simulate_swap` issues a prepare; a leg
+        // beyond the balance is refused without taking a lock.
+        assert_eq!(
+            atomic_transactions::prepare_balance(
+                tid(1),
+                "ICP".to_string(),
+                BalanceDelta::debit(2_000),
+                None,
+                0,
+                Principal::anonymous()
+            ),
+            PrepareVote::No(PrepareError::InsufficientBalance)
+        );
+        // The immediate abort that follows is a no-op for the refused
+        // leg and leaves the balance untouched and unlocked.
+        with_state_mut(|state| state.abort_transaction(tid(1), &"ICP".to_string()));
+        assert_eq!(balance_of("ICP".to_string()), Some(1_000));
+        with_state(|state| assert!(!_token_locked(state, &"ICP".to_string(), 0)));
+    }
+
+    #[test]
+    fn test_configuration_reads_back_what_was_set() {
+        let configuration = Configuration {
+            stop_on_prepare: true,
+            ..Configuration::default()
+        };
+        with_state_mut(|state| state.configuration = configuration);
+        assert!(get_configuration().stop_on_prepare);
+    }
+
+    #[test]
+    fn test_mint_then_burn() {
+        with_resources_mut(|resources| {
+            resources.insert("ICP".to_string(), Box::new(Balance(1_000)));
+        });
+        let token = "ICP".to_string();
+        with_resources_mut(|resources| {
+            assert_eq!(_change_supply(resources, &token, 500), Ok(()));
+        });
+        assert_eq!(balance_of(token.clone()), Some(1_500));
+        with_resources_mut(|resources| {
+            assert_eq!(_change_supply(resources, &token, -400), Ok(()));
+        });
+        assert_eq!(balance_of(token.clone()), Some(1_100));
+        // A burn past the balance is refused and changes nothing.
+        with_resources_mut(|resources| {
+            assert_eq!(
+                _change_supply(resources, &token, -2_000),
+                Err(TxError::InsufficientSupply)
+            );
+            assert_eq!(
+                _change_supply(resources, &"BTC".to_string(), 1),
+                Err(TxError::UnknownToken)
+            );
+        });
+        assert_eq!(balance_of(token), Some(1_100));
+    }
+
+    #[test]
+    fn test_minting_is_blocked_while_token_is_prepared() {
+        with_resources_mut(|resources| {
+            resources.insert("ICP".to_string(), Box::new(Balance(1_000)));
+        });
+        let token = "ICP".to_string();
+        assert_eq!(
+            atomic_transactions::prepare_balance(
+                tid(1),
+                token.clone(),
+                BalanceDelta::debit(300),
+                None,
+                0,
+                Principal::anonymous()
+            ),
+            PrepareVote::Yes
+        );
+        // The prepared lock blocks supply changes...
+        with_state(|state| assert!(_token_locked(state, &token, 0)));
+        // ...until the transaction is settled.
+        atomic_transactions::commit_balance(tid(1), token.clone(), BalanceDelta::debit(300));
+        with_state(|state| assert!(!_token_locked(state, &token, 0)));
+        with_resources_mut(|resources| {
+            assert_eq!(_change_supply(resources, &token, 300), Ok(()));
+        });
+        assert_eq!(balance_of(token), Some(1_000));
+    }
+
+    #[test]
+    fn test_balance_queries_reflect_committed_swap() {
+        with_resources_mut(|resources| {
+            resources.insert("ICP".to_string(), Box::new(Balance(1_000)));
+            resources.insert("EUR".to_string(), Box::new(Balance(500)));
+        });
+        // One swap leg: debit 300 ICP.
+        assert_eq!(
+            atomic_transactions::prepare_balance(
+                tid(1),
+                "ICP".to_string(),
+                BalanceDelta::debit(300),
+                None,
+                0,
+                Principal::anonymous()
+            ),
+            PrepareVote::Yes
+        );
+        // While prepared, the lock holder is visible.
+        with_state(|state| {
+            assert_eq!(_lock_holders(state, 0), vec![("ICP".to_string(), tid(1))]);
+        });
+        atomic_transactions::commit_balance(tid(1), "ICP".to_string(), BalanceDelta::debit(300));
+        assert_eq!(balance_of("ICP".to_string()), Some(700));
+        assert_eq!(
+            all_balances(),
+            vec![("EUR".to_string(), 500), ("ICP".to_string(), 700)]
+        );
+        with_state(|state| assert!(_lock_holders(state, 0).is_empty()));
+    }
+
+    #[test]
+    fn test_locked_tokens_omits_expired_locks() {
+        with_state_mut(|state| {
+            assert!(state.prepare_transaction(tid(1), &"ICP".to_string(), Some(100), 0));
+            assert!(state.prepare_transaction(tid(2), &"EUR".to_string(), None, 50));
+        });
+        with_state(|state| {
+            // The ICP lease expired at 100, only the EUR lock remains.
+            assert_eq!(
+                _locked_tokens(state, 150),
+                vec![("EUR".to_string(), 100)]
+            );
+        });
+    }
+
+    #[test]
+    fn test_token_status_reports_expired_lock_as_aborted() {
+        with_state_mut(|state| {
+            assert!(state.prepare_transaction(tid(1), &"ICP".to_string(), Some(100), 0));
+        });
+        with_state(|state| {
+            // While the lease is valid, the lock is visible.
+            assert_eq!(
+                _token_status(state, &"ICP".to_string(), 50),
+                Some(TransactionStatus::Prepared(tid(1)))
+            );
+            // Past the lease, the lock reads as released.
+            assert_eq!(
+                _token_status(state, &"ICP".to_string(), 200),
+                Some(TransactionStatus::Aborted)
+            );
+        });
+    }
+
+    #[test]
+    fn test_token_decimals_are_reported_but_never_rescale() {
+        with_resources_mut(|resources| {
+            resources.insert("BTC".to_string(), Box::new(Balance(100_000_000)));
+        });
+        let token = "BTC".to_string();
+        atomic_transactions::set_token_decimals(token.clone(), 8);
+        // The configured decimals show up in the queries and metadata.
+        assert_eq!(atomic_transactions::token_decimals(&token), 8);
+        let metadata = token_metadata(token.clone()).unwrap();
+        assert_eq!(metadata.decimals, 8);
+        // The balance itself stays in base units: one BTC at 8 decimals,
+        // not rescaled by the configuration.
+        assert_eq!(metadata.balance, 100_000_000);
+        // Unconfigured tokens keep reporting whole units.
+        assert_eq!(
+            atomic_transactions::token_decimals(&"ICP".to_string()),
+            atomic_transactions::DEFAULT_DECIMALS
+        );
+    }
+
+    #[test]
+    fn test_reset_pc_state_clears_stale_entries() {
+        with_resources_mut(|resources| {
+            resources.insert("ICP".to_string(), Box::new(Balance(1_000)));
+        });
+        let token = "ICP".to_string();
+        // A prepare with a lease locks the token.
+        assert_eq!(
+            atomic_transactions::prepare_balance(
+                tid(1),
+                token.clone(),
+                BalanceDelta::debit(10),
+                Some(100),
+                0,
+                Principal::anonymous()
+            ),
+            PrepareVote::Yes
+        );
+        // While the lock is live, the reset is refused: wiping the state
+        // under an in-flight prepare would break that transaction.
+        with_state_mut(|state| {
+            assert_eq!(_reset_pc_state(state, 50), Err(TxError::TokenLocked));
+        });
+        // Once the lease expired the stale entry may be cleared, and the
+        // token accepts a fresh prepare again.
+        with_state_mut(|state| {
+            assert_eq!(_reset_pc_state(state, 200), Ok(()));
+            assert!(state.state.is_empty());
+        });
+        assert_eq!(
+            atomic_transactions::prepare_balance(
+                tid(2),
+                token,
+                BalanceDelta::debit(10),
+                None,
+                200,
+                Principal::anonymous()
+            ),
+            PrepareVote::Yes
+        );
+    }
+}